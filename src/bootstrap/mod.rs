@@ -1,5 +1,356 @@
 #![allow(dead_code, unused_imports)]
 
-use crate::concurrency::RwSynchronized;
+use std::sync::Arc;
+use std::time::Duration;
 
-pub struct DbContext {}
+use crate::concurrency::{rw_acquire_shared, RwSynchronized};
+use crate::shared::{Lsn, INVALID_PAGE_ID};
+use crate::storage::bgwriter::BgWriterPolicy;
+use crate::storage::blink_tree::BLinkTree;
+use crate::storage::bufmgr::{BufferPool, BufferPoolInternal};
+use crate::storage::catalog::{Catalog, CatalogResult, IndexEntry, IndexOptions, ValueType};
+use crate::storage::checkpoint::{self, CheckpointPolicy, Checkpointer};
+use crate::storage::diskmgr::{DiskMgr, DiskMgrInternal, DiskResult, OpenMode};
+use crate::storage::key_codec::KeyCodec;
+use crate::storage::objptr::ObjectPtr;
+use crate::storage::recovery::{self, RecoveryStats};
+use crate::storage::wal::{Wal, WalInternal};
+
+/// How `DbContext::open` opens a database
+#[derive(Copy, Clone, Debug)]
+pub struct Options {
+    /// Whether the database file may or must already exist. The write-ahead log follows the database file
+    pub mode: OpenMode,
+    /// Frames in the buffer pool
+    pub pool_size: usize,
+    /// K of the LRU-K replacer of the buffer pool
+    pub replacer_k: usize,
+    /// When checkpoints are taken in the background
+    pub checkpoint: CheckpointPolicy,
+    /// Write dirty pages back in the background ahead of their eviction. None leaves it to the eviction itself
+    pub bgwriter: Option<BgWriterPolicy>,
+    /// Index lookups copy nodes out of the buffer pool without latching them, validating each copy against the page
+    /// version instead. Writers still latch exclusively
+    pub optimistic_reads: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            mode: OpenMode::OpenOrCreate,
+            pool_size: 64,
+            replacer_k: 2,
+            checkpoint: CheckpointPolicy {
+                interval: Some(Duration::from_secs(60)),
+                log_bytes: Some(64 << 20),
+            },
+            bgwriter: None,
+            optimistic_reads: false,
+        }
+    }
+}
+
+/// An open database: the database file, its write-ahead log, the buffer pool in front of them and the catalog of indexes.
+///
+/// Dropping a `DbContext` without calling `close` leaves the database as a crash would, to be recovered on the next open
+pub struct DbContext {
+    diskmgr: DiskMgr,
+    wal: Wal,
+    bufmgr: BufferPool,
+    catalog: Catalog,
+    recovery: RecoveryStats,
+    checkpointer: Checkpointer,
+}
+
+impl DbContext {
+    /// Open the database at `path`. The write-ahead log lives next to it, at `<path>.wal`. If the database was not
+    /// closed cleanly, it is recovered from the log before this returns. A new database starts with a new log
+    pub fn open(path: &str, options: Options) -> DiskResult<Self> {
+        let diskmgr = DiskMgrInternal::open(path, options.mode)?;
+        let wal_path = Self::wal_path(path);
+        if diskmgr.was_created() {
+            // a log left behind by an earlier database at this path would be replayed into the new one
+            match std::fs::remove_file(&wal_path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        // a database created without its log is still a database: an existing log is never required
+        let wal_mode = match options.mode {
+            OpenMode::OpenExisting => OpenMode::OpenOrCreate,
+            mode => mode,
+        };
+        let wal = WalInternal::open(&wal_path, wal_mode)?;
+        Self::assemble(diskmgr, wal, options)
+    }
+
+    /// The path of the write-ahead log of the database at `path`
+    pub fn wal_path(path: &str) -> String {
+        format!("{}.wal", path)
+    }
+
+    fn assemble(diskmgr: DiskMgrInternal, wal: WalInternal, options: Options) -> DiskResult<Self> {
+        let diskmgr = Arc::new(parking_lot::RwLock::new(diskmgr));
+        let wal = Arc::new(parking_lot::RwLock::new(wal));
+        let bufmgr = Arc::new(parking_lot::RwLock::new(
+            BufferPoolInternal::with_wal(
+                options.pool_size,
+                options.replacer_k,
+                diskmgr.clone(),
+                wal.clone(),
+            )
+            .with_optimistic_reads(options.optimistic_reads),
+        ));
+        let recovery = recovery::recover(&rw_acquire_shared(&bufmgr))?;
+        let catalog_page_id = rw_acquire_shared(&diskmgr).get_catalog_page_id();
+        let catalog = if catalog_page_id == INVALID_PAGE_ID {
+            Catalog::create(bufmgr.clone()).map_err(std::io::Error::from)?
+        } else {
+            Catalog::open(bufmgr.clone(), catalog_page_id)
+        };
+        {
+            let pool = rw_acquire_shared(&bufmgr);
+            // write the recovered pages out so the next recovery does not have to repeat the work
+            pool.flush_all().map_err(std::io::Error::from)?;
+            if catalog_page_id == INVALID_PAGE_ID {
+                rw_acquire_shared(&diskmgr).set_catalog_page_id(catalog.get_first_page_id())?;
+            }
+            checkpoint::checkpoint(&pool)?;
+        }
+        let checkpointer = Checkpointer::start(bufmgr.clone(), options.checkpoint);
+        if let Some(policy) = options.bgwriter {
+            BufferPoolInternal::start_bgwriter(&bufmgr, policy);
+        }
+        Ok(Self {
+            diskmgr,
+            wal,
+            bufmgr,
+            catalog,
+            recovery,
+            checkpointer,
+        })
+    }
+
+    /// Shut the database down cleanly: write every page back, take a final checkpoint so the next open has nothing to
+    /// recover, and sync the database file and the log
+    pub fn close(self) -> std::io::Result<()> {
+        self.checkpointer.stop();
+        let pool = rw_acquire_shared(&self.bufmgr);
+        pool.stop_bgwriter();
+        pool.flush_all().map_err(std::io::Error::from)?;
+        checkpoint::checkpoint(&pool)?;
+        rw_acquire_shared(&self.wal).close()?;
+        rw_acquire_shared(&self.diskmgr).close()
+    }
+
+    /// Create an empty index with keys of type `K`, under a name no other index has
+    pub fn create_index<K: KeyCodec>(
+        &self,
+        name: &str,
+        value_type: ValueType,
+        options: IndexOptions,
+    ) -> CatalogResult<BLinkTree<K>> {
+        self.catalog.create_index(name, value_type, options)
+    }
+
+    /// Create an index with keys of type `K` from entries sorted by key, with its nodes packed to `options.fill_factor`
+    pub fn bulk_load_index<K: KeyCodec>(
+        &self,
+        name: &str,
+        value_type: ValueType,
+        options: IndexOptions,
+        entries: impl IntoIterator<Item = (K, ObjectPtr)>,
+    ) -> CatalogResult<BLinkTree<K>> {
+        self.catalog
+            .bulk_load_index(name, value_type, options, entries)
+    }
+
+    /// Open an index by name. `K` has to be the key type the index was created with
+    pub fn open_index<K: KeyCodec>(&self, name: &str) -> CatalogResult<BLinkTree<K>> {
+        self.catalog.open_index(name)
+    }
+
+    /// Drop an index and free its pages. Every handle to it has to be dropped first
+    pub fn drop_index(&self, name: &str) -> CatalogResult<()> {
+        self.catalog.drop_index(name)
+    }
+
+    pub fn list_indexes(&self) -> CatalogResult<Vec<IndexEntry>> {
+        self.catalog.list_indexes()
+    }
+
+    /// Take a checkpoint now, bounding the log the next recovery has to read. Returns the checkpoint's LSN
+    pub fn checkpoint(&self) -> std::io::Result<Lsn> {
+        checkpoint::checkpoint(&rw_acquire_shared(&self.bufmgr))
+    }
+
+    #[inline]
+    pub fn get_diskmgr(&self) -> &DiskMgr {
+        &self.diskmgr
+    }
+
+    #[inline]
+    pub fn get_bufmgr(&self) -> &BufferPool {
+        &self.bufmgr
+    }
+
+    #[inline]
+    pub fn get_wal(&self) -> &Wal {
+        &self.wal
+    }
+
+    /// What recovery did when the database was opened
+    #[inline]
+    pub fn get_recovery_stats(&self) -> &RecoveryStats {
+        &self.recovery
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{DbContext, Options};
+    use crate::concurrency::rw_acquire_shared;
+    use crate::shared::{PageId, PAGE_SIZE};
+    use crate::storage::bgwriter::BgWriterPolicy;
+    use crate::storage::blink_tree::BLinkTree;
+    use crate::storage::catalog::{IndexOptions, ValueType};
+    use crate::storage::diskmgr::{DiskError, OpenMode};
+    use crate::storage::objptr::ObjectPtr;
+
+    fn db_path(name: &str) -> String {
+        let path = crate::shared::cwd() + "/data/test/__bootstrap__/" + name;
+        for file in [path.clone(), DbContext::wal_path(&path)] {
+            let _ = std::fs::remove_file(file);
+        }
+        path
+    }
+
+    #[test]
+    fn close_and_reopen() {
+        let path = db_path("close.bin");
+        let options = Options {
+            mode: OpenMode::CreateNew,
+            pool_size: 4,
+            replacer_k: 3,
+            ..Options::default()
+        };
+        let page_ids: Vec<PageId> = {
+            let ctx = DbContext::open(&path, options).unwrap();
+            let pool = rw_acquire_shared(ctx.get_bufmgr());
+            // more pages than frames, so some are evicted along the way
+            let page_ids: Vec<PageId> = (0..10)
+                .map(|i| {
+                    let txn = pool.begin();
+                    let mut page = txn.new_page_write().unwrap();
+                    page[PAGE_SIZE - 1] = i;
+                    let page_id = page.get_page_id();
+                    drop(page);
+                    txn.commit().unwrap();
+                    page_id
+                })
+                .collect();
+            drop(pool);
+            ctx.close().unwrap();
+            page_ids
+        };
+        assert!(matches!(
+            DbContext::open(&path, options),
+            Err(DiskError::AlreadyExists(_))
+        ));
+
+        let options = Options {
+            mode: OpenMode::OpenExisting,
+            ..options
+        };
+        let ctx = DbContext::open(&path, options).unwrap();
+        let stats = ctx.get_recovery_stats();
+        // a clean shutdown leaves nothing to redo or undo
+        assert_eq!((stats.redone, stats.losers), (0, 0));
+        let pool = rw_acquire_shared(ctx.get_bufmgr());
+        for (i, &page_id) in page_ids.iter().enumerate() {
+            assert_eq!(
+                pool.fetch_page_read(page_id).unwrap()[PAGE_SIZE - 1],
+                i as u8
+            );
+        }
+    }
+
+    #[test]
+    fn stale_log_is_not_replayed() {
+        let path = db_path("stale_log.bin");
+        let ptr = |i: i64| ObjectPtr::new(i as PageId, 0);
+        {
+            let ctx = DbContext::open(&path, Options::default()).unwrap();
+            let plays: BLinkTree<i64> = ctx
+                .create_index("plays", ValueType::ObjectPtr, IndexOptions::default())
+                .unwrap();
+            for i in 0..200 {
+                plays.insert(&i, ptr(i)).unwrap();
+            }
+            // the process dies, and only the database file is removed afterwards
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert!(std::path::Path::new(&DbContext::wal_path(&path)).exists());
+
+        for mode in [OpenMode::OpenOrCreate, OpenMode::CreateNew] {
+            let _ = std::fs::remove_file(&path);
+            let ctx = DbContext::open(
+                &path,
+                Options {
+                    mode,
+                    ..Options::default()
+                },
+            )
+            .unwrap();
+            let stats = ctx.get_recovery_stats();
+            assert_eq!((stats.redone, stats.losers), (0, 0));
+            assert!(ctx.list_indexes().unwrap().is_empty());
+            ctx.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn background_writer() {
+        let path = db_path("bgwriter.bin");
+        let options = Options {
+            pool_size: 16,
+            bgwriter: Some(BgWriterPolicy {
+                delay: Duration::from_millis(5),
+                lookahead: 16,
+                max_pages: 8,
+            }),
+            ..Options::default()
+        };
+        let ptr = |i: i64| ObjectPtr::new(i as PageId, 0);
+        {
+            let ctx = DbContext::open(&path, options).unwrap();
+            let plays: BLinkTree<i64> = ctx
+                .create_index("plays", ValueType::ObjectPtr, IndexOptions::default())
+                .unwrap();
+            for i in 0..1500 {
+                plays.insert(&i, ptr(i)).unwrap();
+            }
+            let stats = rw_acquire_shared(ctx.get_bufmgr()).stats();
+            assert!(stats.background_write_backs > 0, "{:?}", stats);
+            // the process dies with the writer running. It only ever wrote pages whose log records were durable
+        }
+
+        let ctx = DbContext::open(&path, options).unwrap();
+        let plays = ctx.open_index::<i64>("plays").unwrap();
+        for i in 0..1500 {
+            assert_eq!(plays.get(&i).unwrap(), Some(ptr(i)));
+        }
+        for i in 1500..2000 {
+            plays.insert(&i, ptr(i)).unwrap();
+        }
+        drop(plays);
+        ctx.close().unwrap();
+
+        let ctx = DbContext::open(&path, Options::default()).unwrap();
+        assert_eq!(ctx.get_recovery_stats().redone, 0);
+        let plays = ctx.open_index::<i64>("plays").unwrap();
+        assert_eq!(plays.range(..).count(), 2000);
+    }
+}
@@ -0,0 +1,209 @@
+//! Safe latching on top of `Synchronized<T>` and `RwSynchronized<T>`. Every latch is a guard that is released when it is
+//! dropped, and every mode transition consumes the old guard and returns the new one, so a latch can neither leak nor be
+//! released twice.
+//!
+//! Each acquire function comes in two flavours. The plain one returns a guard that borrows the latch. The `_owned` one
+//! clones the `Arc` and returns a guard with no lifetime, for latches that must outlive the borrow they were taken
+//! through (e.g. page latches held by a guard that is passed up and down a B-link tree)
+use parking_lot::lock_api::{
+    ArcMutexGuard, ArcRwLockReadGuard, ArcRwLockUpgradableReadGuard, ArcRwLockWriteGuard,
+};
+use parking_lot::{
+    MutexGuard, RawMutex, RawRwLock, RwLockReadGuard, RwLockUpgradableReadGuard, RwLockWriteGuard,
+};
+
+use super::{RwSynchronized, Synchronized};
+
+pub type MutexLatch<'a, T> = MutexGuard<'a, T>;
+pub type SharedLatch<'a, T> = RwLockReadGuard<'a, T>;
+pub type UpgradableLatch<'a, T> = RwLockUpgradableReadGuard<'a, T>;
+pub type ExclusiveLatch<'a, T> = RwLockWriteGuard<'a, T>;
+
+pub type OwnedMutexLatch<T> = ArcMutexGuard<RawMutex, T>;
+pub type OwnedSharedLatch<T> = ArcRwLockReadGuard<RawRwLock, T>;
+pub type OwnedUpgradableLatch<T> = ArcRwLockUpgradableReadGuard<RawRwLock, T>;
+pub type OwnedExclusiveLatch<T> = ArcRwLockWriteGuard<RawRwLock, T>;
+
+#[inline]
+pub fn acquire<T>(item: &Synchronized<T>) -> MutexLatch<'_, T> {
+    item.lock()
+}
+
+#[inline]
+pub fn acquire_owned<T>(item: &Synchronized<T>) -> OwnedMutexLatch<T> {
+    item.lock_arc()
+}
+
+#[inline]
+pub fn try_acquire<T>(item: &Synchronized<T>) -> Option<MutexLatch<'_, T>> {
+    item.try_lock()
+}
+
+#[inline]
+pub fn rw_acquire_shared<T>(item: &RwSynchronized<T>) -> SharedLatch<'_, T> {
+    item.read()
+}
+
+#[inline]
+pub fn rw_acquire_shared_owned<T>(item: &RwSynchronized<T>) -> OwnedSharedLatch<T> {
+    item.read_arc()
+}
+
+#[inline]
+pub fn rw_try_acquire_shared<T>(item: &RwSynchronized<T>) -> Option<SharedLatch<'_, T>> {
+    item.try_read()
+}
+
+#[inline]
+pub fn rw_try_acquire_shared_owned<T>(item: &RwSynchronized<T>) -> Option<OwnedSharedLatch<T>> {
+    item.try_read_arc()
+}
+
+/// An upgradable latch coexists with shared latches but excludes other upgradable and exclusive latches, so it can
+/// become exclusive without another writer getting in first
+#[inline]
+pub fn rw_acquire_upgradable<T>(item: &RwSynchronized<T>) -> UpgradableLatch<'_, T> {
+    item.upgradable_read()
+}
+
+#[inline]
+pub fn rw_acquire_upgradable_owned<T>(item: &RwSynchronized<T>) -> OwnedUpgradableLatch<T> {
+    item.upgradable_read_arc()
+}
+
+#[inline]
+pub fn rw_acquire_excl<T>(item: &RwSynchronized<T>) -> ExclusiveLatch<'_, T> {
+    item.write()
+}
+
+#[inline]
+pub fn rw_acquire_excl_owned<T>(item: &RwSynchronized<T>) -> OwnedExclusiveLatch<T> {
+    item.write_arc()
+}
+
+#[inline]
+pub fn rw_try_acquire_excl<T>(item: &RwSynchronized<T>) -> Option<ExclusiveLatch<'_, T>> {
+    item.try_write()
+}
+
+#[inline]
+pub fn rw_try_acquire_excl_owned<T>(item: &RwSynchronized<T>) -> Option<OwnedExclusiveLatch<T>> {
+    item.try_write_arc()
+}
+
+/// Wait for shared holders to leave, then turn an upgradable latch into an exclusive one
+#[inline]
+pub fn rw_upgrade<T>(latch: UpgradableLatch<'_, T>) -> ExclusiveLatch<'_, T> {
+    RwLockUpgradableReadGuard::upgrade(latch)
+}
+
+#[inline]
+pub fn rw_upgrade_owned<T>(latch: OwnedUpgradableLatch<T>) -> OwnedExclusiveLatch<T> {
+    ArcRwLockUpgradableReadGuard::upgrade(latch)
+}
+
+/// Upgrade only if no shared holders are present. On failure the upgradable latch is handed back unchanged
+#[inline]
+pub fn rw_try_upgrade<T>(
+    latch: UpgradableLatch<'_, T>,
+) -> Result<ExclusiveLatch<'_, T>, UpgradableLatch<'_, T>> {
+    RwLockUpgradableReadGuard::try_upgrade(latch)
+}
+
+#[inline]
+pub fn rw_downgrade_excl_to_shared<T>(latch: ExclusiveLatch<'_, T>) -> SharedLatch<'_, T> {
+    RwLockWriteGuard::downgrade(latch)
+}
+
+#[inline]
+pub fn rw_downgrade_excl_to_shared_owned<T>(latch: OwnedExclusiveLatch<T>) -> OwnedSharedLatch<T> {
+    ArcRwLockWriteGuard::downgrade(latch)
+}
+
+#[inline]
+pub fn rw_downgrade_excl_to_upgradable<T>(latch: ExclusiveLatch<'_, T>) -> UpgradableLatch<'_, T> {
+    RwLockWriteGuard::downgrade_to_upgradable(latch)
+}
+
+#[inline]
+pub fn rw_downgrade_excl_to_upgradable_owned<T>(
+    latch: OwnedExclusiveLatch<T>,
+) -> OwnedUpgradableLatch<T> {
+    ArcRwLockWriteGuard::downgrade_to_upgradable(latch)
+}
+
+#[inline]
+pub fn rw_downgrade_upgradable_to_shared<T>(latch: UpgradableLatch<'_, T>) -> SharedLatch<'_, T> {
+    RwLockUpgradableReadGuard::downgrade(latch)
+}
+
+#[inline]
+pub fn rw_downgrade_upgradable_to_shared_owned<T>(
+    latch: OwnedUpgradableLatch<T>,
+) -> OwnedSharedLatch<T> {
+    ArcRwLockUpgradableReadGuard::downgrade(latch)
+}
+
+/// Latch coupling: take the next latch while the current one is still held, then release the current one. A traversal
+/// that moves from node to node this way never observes a node that is not latched by it. The B-link tree moves right
+/// along a level this way
+#[inline]
+pub fn couple<H, N>(held: H, acquire_next: impl FnOnce() -> N) -> N {
+    let next = acquire_next();
+    drop(held);
+    next
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn transitions() {
+        let item: RwSynchronized<usize> = Arc::new(parking_lot::RwLock::new(0));
+
+        let upgradable = rw_acquire_upgradable(&item);
+        // shared latches coexist with an upgradable latch, exclusive ones do not
+        assert!(rw_try_acquire_shared(&item).is_some());
+        assert!(rw_try_acquire_excl(&item).is_none());
+
+        let reader = rw_acquire_shared(&item);
+        let upgradable = rw_try_upgrade(upgradable).unwrap_err();
+        drop(reader);
+
+        let mut exclusive = rw_upgrade(upgradable);
+        *exclusive += 1;
+        assert!(rw_try_acquire_shared(&item).is_none());
+
+        let upgradable = rw_downgrade_excl_to_upgradable(exclusive);
+        assert_eq!(*rw_acquire_shared(&item), 1);
+        let shared = rw_downgrade_upgradable_to_shared(upgradable);
+        assert!(rw_try_acquire_excl(&item).is_none());
+        drop(shared);
+
+        let exclusive = rw_acquire_excl_owned(&item);
+        let shared = rw_downgrade_excl_to_shared_owned(exclusive);
+        assert_eq!(*shared, 1);
+        drop(shared);
+        assert!(rw_try_acquire_excl(&item).is_some());
+    }
+
+    #[test]
+    fn coupling() {
+        let nodes: Vec<RwSynchronized<usize>> = (0..4)
+            .map(|i| Arc::new(parking_lot::RwLock::new(i)))
+            .collect();
+
+        // walk the chain holding at most two latches at a time
+        let mut latch = rw_acquire_shared_owned(&nodes[0]);
+        for next in &nodes[1..] {
+            latch = couple(latch, || rw_acquire_shared_owned(next));
+            assert!(rw_try_acquire_excl(next).is_none());
+        }
+        assert_eq!(*latch, 3);
+        drop(latch);
+        assert!(nodes.iter().all(|node| rw_try_acquire_excl(node).is_some()));
+    }
+}
@@ -2,6 +2,17 @@ mod bootstrap;
 mod concurrency;
 mod shared;
 mod storage;
-fn main() {
-    println!("Hello, world!");
+
+use bootstrap::{DbContext, Options};
+
+/// Where the database lives when no path is given on the command line
+const DEFAULT_PATH: &str = "data/db.bin";
+
+fn main() -> std::io::Result<()> {
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_PATH.to_string());
+    let ctx = DbContext::open(&path, Options::default())?;
+    println!("opened {}: {:?}", path, ctx.get_recovery_stats());
+    ctx.close()
 }
@@ -1,4 +1,4 @@
-#![allow(dead_code)]
+#![allow(dead_code, clippy::needless_return)]
 
 use serde::Serialize;
 use std::{env, fmt::Display};
@@ -6,11 +6,17 @@ use std::{env, fmt::Display};
 pub type FrameId = isize;
 pub type PageId = isize;
 pub type Oid = u16;
+/// Log sequence number: the offset of a record in the write-ahead log
+pub type Lsn = u64;
+pub type TxnId = u64;
 
 pub const HEADER_ID: usize = 0;
 pub const PAGE_SIZE: usize = 4096;
 pub const INVALID_FRAME_ID: isize = -1;
 pub const INVALID_PAGE_ID: isize = -1;
+pub const INVALID_LSN: Lsn = 0;
+/// Changes logged outside a transaction. They are atomic on their own and never undone
+pub const INVALID_TXN_ID: TxnId = 0;
 
 pub fn cwd() -> String {
     String::from(env::current_dir().unwrap().to_str().unwrap())
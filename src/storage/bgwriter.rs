@@ -0,0 +1,108 @@
+// SOURCES + USEFUL LINKS
+// https://www.postgresql.org/docs/current/runtime-config-resource.html#RUNTIME-CONFIG-RESOURCE-BACKGROUND-WRITER
+use std::sync::{Arc, Weak};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::concurrency::{acquire, rw_acquire_shared, Synchronized};
+use crate::storage::bufmgr::BufferPoolInternal;
+
+/// How much the background writer writes. At most `max_pages` pages every `delay` caps the write rate
+#[derive(Copy, Clone, Debug)]
+pub struct BgWriterPolicy {
+    /// Time between rounds
+    pub delay: Duration,
+    /// Frames at the head of the replacer's eviction order that each round looks at
+    pub lookahead: usize,
+    /// Dirty pages written in a round at most
+    pub max_pages: usize,
+}
+
+impl Default for BgWriterPolicy {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_millis(200),
+            lookahead: 64,
+            max_pages: 100,
+        }
+    }
+}
+
+/// A background thread that writes dirty pages back before the replacer gets to them, so that a fetch evicting a page
+/// rarely has to write it first. Every round looks at the next frames in eviction order and writes those whose pages are
+/// dirty and unpinned, each after the log records describing it (see `BufferPoolInternal::write_ahead_of_eviction`).
+///
+/// The buffer pool owns its writer and stops it when it is dropped. The thread only holds a weak handle to the pool, which
+/// it upgrades for the length of a round
+pub struct BgWriter {
+    stopped: Synchronized<bool>,
+    wakeup: Arc<parking_lot::Condvar>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl BgWriter {
+    pub(crate) fn start(
+        bufmgr: Weak<parking_lot::RwLock<BufferPoolInternal>>,
+        policy: BgWriterPolicy,
+    ) -> Self {
+        let stopped: Synchronized<bool> = Arc::default();
+        let wakeup = Arc::new(parking_lot::Condvar::new());
+        let handle = {
+            let (stopped, wakeup) = (stopped.clone(), wakeup.clone());
+            std::thread::spawn(move || Self::run(bufmgr, policy, stopped, wakeup))
+        };
+        Self {
+            stopped,
+            wakeup,
+            handle: Some(handle),
+        }
+    }
+
+    fn run(
+        bufmgr: Weak<parking_lot::RwLock<BufferPoolInternal>>,
+        policy: BgWriterPolicy,
+        stopped: Synchronized<bool>,
+        wakeup: Arc<parking_lot::Condvar>,
+    ) {
+        loop {
+            {
+                let mut stopped = acquire(&stopped);
+                if !*stopped {
+                    wakeup.wait_for(&mut stopped, policy.delay);
+                }
+                if *stopped {
+                    return;
+                }
+            }
+            let Some(bufmgr) = bufmgr.upgrade() else {
+                return;
+            };
+            // a page that fails to be written stays dirty, for eviction or the next round to try again
+            let _ = rw_acquire_shared(&bufmgr)
+                .write_ahead_of_eviction(policy.lookahead, policy.max_pages);
+        }
+    }
+
+    /// Stop the thread, waiting for a round in progress to finish
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        *acquire(&self.stopped) = true;
+        self.wakeup.notify_all();
+        if let Some(handle) = self.handle.take() {
+            // the pool is dropped on the writer's own thread if the round held its last handle. The thread sees the flag
+            // once the round is over
+            if handle.thread().id() != std::thread::current().id() {
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+impl Drop for BgWriter {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
@@ -0,0 +1,1897 @@
+// SOURCES + USEFUL LINKS
+// https://www.csd.uoc.gr/~hy460/pdf/p650-lehman.pdf (Efficient Locking for Concurrent Operations on B-Trees)
+// https://github.com/postgres/postgres/blob/master/src/backend/access/nbtree/README
+// https://dl.acm.org/doi/pdf/10.5555/324493.324589 (A Symmetric Concurrent B-Tree Algorithm)
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Display;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+
+use crate::concurrency::{acquire, couple, rw_acquire_shared, Synchronized};
+use crate::shared::{PageId, INVALID_LSN, INVALID_PAGE_ID, PAGE_SIZE};
+use crate::storage::bufmgr::{BufferPool, BufferPoolError, BufferPoolInternal};
+use crate::storage::index_page::{IndexMetaPage, IndexPage, MAX_KEY_SIZE};
+use crate::storage::key_codec::KeyCodec;
+use crate::storage::objptr::ObjectPtr;
+use crate::storage::page::{set_page_lsn, PAGE_LSN_SIZE};
+use crate::storage::page_guard::{ReadPageGuard, WritePageGuard};
+use crate::storage::txn::Transaction;
+
+/// Errors returned by index operations
+#[derive(Debug)]
+pub enum IndexError {
+    /// The key is already present
+    DuplicateKey,
+    /// The key is longer than `MAX_KEY_SIZE`
+    KeyTooLarge(usize),
+    /// Entry number `n` of the input to a bulk load sorts below the entry before it
+    Unsorted(usize),
+    /// The fill factor of a bulk load is not a percentage between 1 and 100
+    FillFactor(u8),
+    /// A page did not decode as the kind of index page the tree expected
+    Corrupt(PageId),
+    BufferPool(BufferPoolError),
+}
+
+pub type IndexResult<T> = Result<T, IndexError>;
+
+impl Display for IndexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IndexError::DuplicateKey => write!(f, "key already exists"),
+            IndexError::KeyTooLarge(len) => write!(
+                f,
+                "key of {} bytes exceeds the maximum of {} bytes",
+                len, MAX_KEY_SIZE
+            ),
+            IndexError::Unsorted(n) => write!(f, "entry {} of the input is out of order", n),
+            IndexError::FillFactor(fill_factor) => write!(
+                f,
+                "fill factor {} is not a percentage between 1 and 100",
+                fill_factor
+            ),
+            IndexError::Corrupt(page_id) => write!(f, "page {} is not a valid index page", page_id),
+            IndexError::BufferPool(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for IndexError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            IndexError::BufferPool(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<BufferPoolError> for IndexError {
+    fn from(e: BufferPoolError) -> Self {
+        IndexError::BufferPool(e)
+    }
+}
+
+impl From<std::io::Error> for IndexError {
+    fn from(e: std::io::Error) -> Self {
+        IndexError::BufferPool(BufferPoolError::Io(e))
+    }
+}
+
+impl From<IndexError> for std::io::Error {
+    fn from(e: IndexError) -> Self {
+        match e {
+            IndexError::BufferPool(e) => e.into(),
+            e => std::io::Error::other(e),
+        }
+    }
+}
+
+/// The entries of one leaf that fall in a scanned range, with the id of the leaf
+type Batch = (PageId, Vec<(Vec<u8>, ObjectPtr)>);
+
+/// The leaf a forward scan read last, where it resumes. The scan stays registered in the epoch it read the leaf in, so
+/// the leaf cannot be reclaimed and reused while the scan may still go back to it
+struct ScanPosition {
+    page_id: PageId,
+    epoch: u64,
+}
+
+/// A Lehman–Yao B-link tree mapping byte-string keys, compared lexicographically, to `ObjectPtr`s. `BLinkTree` puts typed
+/// keys on top of it.
+///
+/// Readers latch one node at a time and never couple latches: a node that was split after its parent was read is
+/// recognised by its high key, and the reader follows the right link. Writers descend the same way, then latch the leaf
+/// exclusively. An insert into a full leaf lets go of it, splits it and tries again. A split is carried up one level at
+/// a time, and the latches of a level are released before the level above is latched, so a writer holds at most a node,
+/// the node to its right it moves to or splits off, and the meta page when the root grows.
+///
+/// Deletion follows Lanin and Shasha's symmetric algorithm. An underfull node is merged with its right sibling, which is
+/// the mirror image of a split: the left node absorbs the right one's entries and the separator is removed from the
+/// parent. The emptied node stays in place, marked deleted with an outlink to the node that absorbed it, until no running
+/// operation can still reach it. When two siblings hold too much for one node their entries are redistributed instead.
+///
+/// Writers take latches in one global order, which is what rules out deadlocks: a node, then nodes to its right on the
+/// same level, then nodes on the level above, and the meta page last. A split takes child, right sibling, parent, meta;
+/// a merge takes left node, right node, parent; removing a root takes child, root, meta. The one step against the order,
+/// following the outlink of a merged node to its left, releases the latch it holds first.
+///
+/// Inserting or removing a key changes one leaf, in a transaction that keeps the leaf latched until it commits. Every
+/// structure modification is a system transaction of its own, so a crash never leaves half of one behind: each level of
+/// a split, each merge and each root removal. A rolled back insert leaves the splits it caused in place, since other
+/// operations may already depend on them. A transaction keeps the nodes it changed latched until it commits and releases
+/// the ones it only looked at right away. Holding on to latches until the end is safe because every latch it adds later
+/// comes after them in the order
+pub struct BLinkTreeInternal {
+    bufmgr: BufferPool,
+    meta_page_id: PageId,
+    drain: Synchronized<Drain>,
+}
+
+/// Tracks running operations so that deleted pages are only reclaimed once every operation that might hold a stale
+/// pointer to them has finished (the drain technique)
+#[derive(Default)]
+struct Drain {
+    epoch: u64,
+    /// Number of running operations by the epoch they started in
+    active: BTreeMap<u64, usize>,
+    /// Deleted pages with the epoch in which they became unreachable
+    retired: Vec<(u64, PageId)>,
+}
+
+/// A running tree operation. Dropping it reclaims the deleted pages nobody can reach anymore
+struct Operation<'a> {
+    tree: &'a BLinkTreeInternal,
+    pool: &'a BufferPoolInternal,
+    epoch: u64,
+}
+
+impl Drop for Operation<'_> {
+    fn drop(&mut self) {
+        let reclaimable: Vec<(u64, PageId)> = {
+            let mut drain = acquire(&self.tree.drain);
+            if let Some(count) = drain.active.get_mut(&self.epoch) {
+                *count -= 1;
+                if *count == 0 {
+                    drain.active.remove(&self.epoch);
+                }
+            }
+            let oldest = drain.active.keys().next().copied().unwrap_or(drain.epoch);
+            let (reclaimable, retired) =
+                drain.retired.iter().partition(|(epoch, _)| *epoch < oldest);
+            drain.retired = retired;
+            reclaimable
+        };
+        for (epoch, page_id) in reclaimable {
+            // a page that is still pinned, e.g. by a flush, is tried again by a later operation
+            if !matches!(self.pool.delete_page(page_id), Ok(true)) {
+                acquire(&self.tree.drain).retired.push((epoch, page_id));
+            }
+        }
+    }
+}
+
+impl BLinkTreeInternal {
+    /// Create an empty tree, consisting of a meta page and a single empty leaf as the root
+    pub fn create(bufmgr: BufferPool) -> IndexResult<Self> {
+        let meta_page_id = {
+            let pool = rw_acquire_shared(&bufmgr);
+            let txn = pool.begin();
+            let meta_page_id = Self::create_in(&txn)?;
+            txn.commit()?;
+            meta_page_id
+        };
+        Ok(Self::open(bufmgr, meta_page_id))
+    }
+
+    /// Write the pages of an empty tree as part of `txn`, and return its meta page
+    pub fn create_in<'a>(txn: &'a Transaction<'a>) -> IndexResult<PageId> {
+        let mut meta_guard = txn.new_page_write()?;
+        let mut root_guard = txn.new_page_write()?;
+        IndexPage::new_leaf().write_to(&mut root_guard);
+        IndexMetaPage::new(root_guard.get_page_id()).write_to(&mut meta_guard);
+        Ok(meta_guard.get_page_id())
+    }
+
+    /// Build a tree from entries sorted by key. See `bulk_load_in`
+    pub fn bulk_load(
+        bufmgr: BufferPool,
+        entries: impl IntoIterator<Item = (Vec<u8>, ObjectPtr)>,
+        fill_factor: u8,
+    ) -> IndexResult<Self> {
+        let meta_page_id = {
+            let pool = rw_acquire_shared(&bufmgr);
+            let txn = pool.begin();
+            let meta_page_id = Self::bulk_load_in(&pool, &txn, entries, fill_factor)?;
+            txn.commit()?;
+            meta_page_id
+        };
+        Ok(Self::open(bufmgr, meta_page_id))
+    }
+
+    /// Build a tree from entries sorted by key, and return its meta page. The nodes are built bottom up, each filled to
+    /// `fill_factor` percent of a page, and written straight through the disk manager in the order their pages were
+    /// allocated, without going through the buffer pool. The log only records their allocation, as part of `txn`. Once they
+    /// are synced, the meta page is written as part of `txn` too, which makes the tree reachable.
+    ///
+    /// Input that is not sorted, or has a key twice, is refused, and the pages written so far are freed when `txn` rolls
+    /// back, as they are if it never commits because of a crash. So is a fill factor that is not a percentage
+    pub fn bulk_load_in<'a>(
+        pool: &BufferPoolInternal,
+        txn: &'a Transaction<'a>,
+        entries: impl IntoIterator<Item = (Vec<u8>, ObjectPtr)>,
+        fill_factor: u8,
+    ) -> IndexResult<PageId> {
+        if !(1..=100).contains(&fill_factor) {
+            return Err(IndexError::FillFactor(fill_factor));
+        }
+        let mut loader = BulkLoader {
+            pool,
+            txn,
+            budget: (PAGE_SIZE - PAGE_LSN_SIZE) * fill_factor as usize / 100,
+        };
+        let meta = loader.build(entries.into_iter())?;
+        let mut meta_guard = txn.new_page_write()?;
+        meta.write_to(&mut meta_guard);
+        Ok(meta_guard.get_page_id())
+    }
+
+    /// Open an existing tree through its meta page
+    pub fn open(bufmgr: BufferPool, meta_page_id: PageId) -> Self {
+        Self {
+            bufmgr,
+            meta_page_id,
+            drain: Arc::new(parking_lot::Mutex::new(Drain::default())),
+        }
+    }
+
+    #[inline]
+    pub fn get_meta_page_id(&self) -> PageId {
+        self.meta_page_id
+    }
+
+    /// Free every page of the tree. Nothing may reach the tree anymore, and no operation may still be running on it
+    pub fn destroy(self) -> IndexResult<()> {
+        let pool = rw_acquire_shared(&self.bufmgr);
+        let mut pages = vec![self.meta_page_id];
+        // every live node is on its level's chain of right links, which starts at the leftmost node
+        let mut leftmost = self.read_meta(&pool)?.root;
+        while leftmost != INVALID_PAGE_ID {
+            let mut page_id = leftmost;
+            leftmost = INVALID_PAGE_ID;
+            while page_id != INVALID_PAGE_ID {
+                let node = self.read_node(&pool, page_id)?;
+                if leftmost == INVALID_PAGE_ID && !node.is_leaf() {
+                    leftmost = node.children[0];
+                }
+                pages.push(page_id);
+                page_id = node.right_link;
+            }
+        }
+        // merged nodes are off the chains, waiting to be reclaimed
+        pages.extend(
+            acquire(&self.drain)
+                .retired
+                .drain(..)
+                .map(|(_, page_id)| page_id),
+        );
+        for page_id in pages {
+            // a page that is still pinned, e.g. by a flush, is left allocated
+            pool.delete_page(page_id)?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: &[u8]) -> IndexResult<Option<ObjectPtr>> {
+        let pool = rw_acquire_shared(&self.bufmgr);
+        let _op = self.enter(&pool);
+        let (_, leaf) = self.descend(&pool, key, 0, None)?;
+        Ok(leaf.lookup(key))
+    }
+
+    /// Insert a key that is not yet in the tree
+    pub fn insert(&self, key: &[u8], value: ObjectPtr) -> IndexResult<()> {
+        if key.len() > MAX_KEY_SIZE {
+            return Err(IndexError::KeyTooLarge(key.len()));
+        }
+        let pool = rw_acquire_shared(&self.bufmgr);
+        let _op = self.enter(&pool);
+        let txn = pool.begin();
+        self.insert_in(&pool, &txn, key, value)?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    fn insert_in<'a>(
+        &self,
+        pool: &'a BufferPoolInternal,
+        txn: &'a Transaction<'a>,
+        key: &[u8],
+        value: ObjectPtr,
+    ) -> IndexResult<()> {
+        // the nodes the search went down through, one per level above the leaf
+        let mut stack = Vec::new();
+        loop {
+            let (mut guard, mut node) = self.lock_leaf(pool, txn, key, Some(&mut stack))?;
+            if !node.insert_value(key, value) {
+                return Err(IndexError::DuplicateKey);
+            }
+            if node.fits() {
+                node.write_to(&mut guard);
+                return Ok(());
+            }
+            // the transaction has not changed the leaf, so this releases it. Room is made outside of the transaction,
+            // and the insert tries again
+            drop(guard);
+            self.split(pool, key, &mut stack)?;
+        }
+    }
+
+    /// Split the leaf covering `key` if `key` does not fit in it, and post the split to the level above, splitting that
+    /// in turn if it overflows. Every level is a half-split in a system transaction of its own, which latches the node
+    /// and its new right sibling, commits, and releases them before the level above is latched. In between the tree is
+    /// valid: the new node is reached through the right link of its left neighbour until its separator is posted, and
+    /// stays that way if a crash keeps the level above from being split
+    fn split(
+        &self,
+        pool: &BufferPoolInternal,
+        key: &[u8],
+        stack: &mut Vec<PageId>,
+    ) -> IndexResult<()> {
+        let (mut left_id, mut separator, mut right_id) = {
+            let stx = pool.begin_system();
+            let (mut guard, mut node) = self.lock_leaf(pool, &stx, key, Some(stack))?;
+            let mut with_key = node.clone();
+            if !with_key.insert_value(key, ObjectPtr::new(INVALID_PAGE_ID, 0)) || with_key.fits() {
+                return Ok(());
+            }
+            let (separator, right_id) = Self::half_split(&stx, &mut guard, &mut node)?;
+            let left_id = guard.get_page_id();
+            drop(guard);
+            stx.commit()?;
+            (left_id, separator, right_id)
+        };
+        let mut level = 1;
+        loop {
+            let stx = pool.begin_system();
+            let (mut guard, mut node) = loop {
+                let parent_id = match stack.pop() {
+                    Some(parent_id) => parent_id,
+                    None => {
+                        match self.grow_root(pool, &stx, left_id, level, &separator, right_id)? {
+                            Some(parent_id) => parent_id,
+                            None => return Ok(stx.commit()?),
+                        }
+                    }
+                };
+                match self.lock_covering(&stx, parent_id, &separator, level)? {
+                    Some(found) => break found,
+                    // the parent was merged away or lost entries to its left neighbour: find it again from the root
+                    None => stack.clear(),
+                }
+            };
+            node.insert_child(separator, right_id);
+            if node.fits() {
+                node.write_to(&mut guard);
+                drop(guard);
+                return Ok(stx.commit()?);
+            }
+            (separator, right_id) = Self::half_split(&stx, &mut guard, &mut node)?;
+            left_id = guard.get_page_id();
+            drop(guard);
+            stx.commit()?;
+            level += 1;
+        }
+    }
+
+    /// Move the upper half of a node that overflowed into a new right sibling, as part of `txn`. The sibling is written
+    /// before the node that links to it, so the tree is valid at every step. Returns the separator and the sibling
+    fn half_split<'a>(
+        txn: &'a Transaction<'a>,
+        guard: &mut WritePageGuard<'a>,
+        node: &mut IndexPage,
+    ) -> IndexResult<(Vec<u8>, PageId)> {
+        let (right, separator) = node.split();
+        let mut right_guard = txn.new_page_write()?;
+        right.write_to(&mut right_guard);
+        let right_id = right_guard.get_page_id();
+        drop(right_guard);
+        node.right_link = right_id;
+        node.write_to(guard);
+        Ok((separator, right_id))
+    }
+
+    /// Remove a key from the tree. Returns false if it was not present
+    pub fn delete(&self, key: &[u8]) -> IndexResult<bool> {
+        let pool = rw_acquire_shared(&self.bufmgr);
+        let _op = self.enter(&pool);
+        let txn = pool.begin();
+        let node = {
+            let (mut guard, mut node) = self.lock_leaf(&pool, &txn, key, None)?;
+            if node.remove_value(key).is_none() {
+                return Ok(false);
+            }
+            node.write_to(&mut guard);
+            node
+        };
+        txn.commit()?;
+        if node.is_underfull() {
+            self.rebalance(&pool, key)?;
+        }
+        Ok(true)
+    }
+
+    fn enter<'a>(&'a self, pool: &'a BufferPoolInternal) -> Operation<'a> {
+        let mut drain = acquire(&self.drain);
+        let epoch = drain.epoch;
+        *drain.active.entry(epoch).or_insert(0) += 1;
+        Operation {
+            tree: self,
+            pool,
+            epoch,
+        }
+    }
+
+    /// Register one more running operation in `epoch`, which has one running already
+    fn join(&self, epoch: u64) -> u64 {
+        *acquire(&self.drain).active.entry(epoch).or_insert(0) += 1;
+        epoch
+    }
+
+    /// End an operation registered in `epoch`, reclaiming the deleted pages nobody can reach anymore
+    fn leave(&self, pool: &BufferPoolInternal, epoch: u64) {
+        drop(Operation {
+            tree: self,
+            pool,
+            epoch,
+        });
+    }
+
+    /// Queue a page that was just unlinked from the tree for reclamation
+    fn retire(&self, page_id: PageId) {
+        let mut drain = acquire(&self.drain);
+        let epoch = drain.epoch;
+        drain.retired.push((epoch, page_id));
+        drain.epoch += 1;
+    }
+
+    fn read_meta(&self, pool: &BufferPoolInternal) -> IndexResult<IndexMetaPage> {
+        let data = pool.read_page(self.meta_page_id)?;
+        IndexMetaPage::from_bytes(&data).ok_or(IndexError::Corrupt(self.meta_page_id))
+    }
+
+    fn decode(page_id: PageId, buf: &[u8; PAGE_SIZE]) -> IndexResult<IndexPage> {
+        IndexPage::from_bytes(buf).ok_or(IndexError::Corrupt(page_id))
+    }
+
+    /// Read a copy of a node. The page is copied out of the pool, optimistically if the pool is set up for it, and
+    /// decoded from the copy. Descents need nothing more: a node that changed after it was copied is caught by its high
+    /// key, low key or deleted flag like any other concurrent split or merge
+    fn read_node(&self, pool: &BufferPoolInternal, page_id: PageId) -> IndexResult<IndexPage> {
+        Self::decode(page_id, &pool.read_page(page_id)?)
+    }
+
+    /// Find the node at `level` whose range covers `key`, moving right wherever a split has not reached the parent yet
+    /// and following the outlinks of merged nodes. When `stack` is given, the last node visited on every level above
+    /// `level` is pushed onto it. The returned node is only below `level` if the tree is not that high
+    fn descend(
+        &self,
+        pool: &BufferPoolInternal,
+        key: &[u8],
+        level: u32,
+        mut stack: Option<&mut Vec<PageId>>,
+    ) -> IndexResult<(PageId, IndexPage)> {
+        'restart: loop {
+            if let Some(stack) = stack.as_mut() {
+                stack.clear();
+            }
+            let mut page_id = self.read_meta(pool)?.root;
+            loop {
+                let node = self.read_node(pool, page_id)?;
+                if node.deleted && node.outlink != INVALID_PAGE_ID {
+                    page_id = node.outlink;
+                    continue;
+                }
+                if node.deleted || node.is_left_of(key) {
+                    continue 'restart;
+                }
+                if node.must_move_right(key) {
+                    page_id = node.right_link;
+                    continue;
+                }
+                if node.level <= level {
+                    return Ok((page_id, node));
+                }
+                if let Some(stack) = stack.as_mut() {
+                    stack.push(page_id);
+                }
+                page_id = node.child_for(key);
+            }
+        }
+    }
+
+    /// Descend to the leaf covering `key` and latch it exclusively
+    fn lock_leaf<'a>(
+        &self,
+        pool: &BufferPoolInternal,
+        txn: &'a Transaction<'a>,
+        key: &[u8],
+        mut stack: Option<&mut Vec<PageId>>,
+    ) -> IndexResult<(WritePageGuard<'a>, IndexPage)> {
+        loop {
+            let (leaf_id, _) = self.descend(pool, key, 0, stack.as_deref_mut())?;
+            if let Some(found) = self.lock_covering(txn, leaf_id, key, 0)? {
+                return Ok(found);
+            }
+        }
+    }
+
+    /// Latch `page_id` exclusively, then move right, coupling latches, until reaching the node at `level` that covers
+    /// `key`. The outlink of a merged node points left, so its latch is released before the outlink is followed. Returns
+    /// None if the node covering `key` cannot be reached from `page_id` and has to be searched for from the root
+    fn lock_covering<'a>(
+        &self,
+        txn: &'a Transaction<'a>,
+        page_id: PageId,
+        key: &[u8],
+        level: u32,
+    ) -> IndexResult<Option<(WritePageGuard<'a>, IndexPage)>> {
+        let mut guard = txn.fetch_page_write(page_id)?;
+        let mut node = Self::decode(page_id, &guard)?;
+        loop {
+            if node.deleted && node.outlink != INVALID_PAGE_ID && node.level == level {
+                let outlink = node.outlink;
+                drop(guard);
+                guard = txn.fetch_page_write(outlink)?;
+                node = Self::decode(outlink, &guard)?;
+                continue;
+            }
+            if node.deleted || node.level != level || node.is_left_of(key) {
+                return Ok(None);
+            }
+            if !node.must_move_right(key) {
+                return Ok(Some((guard, node)));
+            }
+            // the right sibling is latched before the current node is released
+            let right_link = node.right_link;
+            guard = couple(guard, || txn.fetch_page_write(right_link))?;
+            node = Self::decode(guard.get_page_id(), &guard)?;
+        }
+    }
+
+    /// The rightmost leaf, found by following the last child on every level and moving right wherever a split has not
+    /// reached the parent yet
+    fn descend_last(&self, pool: &BufferPoolInternal) -> IndexResult<(PageId, IndexPage)> {
+        'restart: loop {
+            let mut page_id = self.read_meta(pool)?.root;
+            loop {
+                let node = self.read_node(pool, page_id)?;
+                if node.deleted && node.outlink != INVALID_PAGE_ID {
+                    page_id = node.outlink;
+                    continue;
+                }
+                if node.deleted {
+                    continue 'restart;
+                }
+                if node.right_link != INVALID_PAGE_ID {
+                    page_id = node.right_link;
+                    continue;
+                }
+                if node.is_leaf() {
+                    return Ok((page_id, node));
+                }
+                page_id = *node.children.last().unwrap();
+            }
+        }
+    }
+
+    /// Latch the leaf `page_id` in shared mode, then move right, coupling latches, until reaching the leaf that covers
+    /// `key`. Returns None if that leaf has to be searched for from the root
+    fn read_covering<'a>(
+        &self,
+        pool: &'a BufferPoolInternal,
+        page_id: PageId,
+        key: &[u8],
+    ) -> IndexResult<Option<(ReadPageGuard<'a>, IndexPage)>> {
+        let mut guard = pool.fetch_page_read(page_id)?;
+        let mut node = Self::decode(page_id, &guard)?;
+        loop {
+            if node.deleted || node.level != 0 || node.is_left_of(key) {
+                return Ok(None);
+            }
+            if !node.must_move_right(key) {
+                return Ok(Some((guard, node)));
+            }
+            let right_link = node.right_link;
+            guard = couple(guard, || pool.fetch_page_read(right_link))?;
+            node = Self::decode(guard.get_page_id(), &guard)?;
+        }
+    }
+
+    /// The entries in the range of the first leaf, from the left, that has any. The search resumes at `position`, the
+    /// leaf the scan read last, and moves right with latch coupling: the right sibling is latched before the current leaf
+    /// is released, so it cannot be merged away in between. Only if that leaf was merged away or lost entries to its
+    /// left is the leaf covering the lower bound searched for from the root. Returns the leaf's id with its entries, or
+    /// None at the end. `position` is moved to the leaf the entries came from
+    fn next_batch(
+        &self,
+        lower: &Bound<Vec<u8>>,
+        upper: &Bound<Vec<u8>>,
+        position: &mut Option<ScanPosition>,
+    ) -> IndexResult<Option<Batch>> {
+        let pool = rw_acquire_shared(&self.bufmgr);
+        let op = self.enter(&pool);
+        let key: &[u8] = match lower {
+            Bound::Included(key) | Bound::Excluded(key) => key,
+            Bound::Unbounded => &[],
+        };
+        let resumed = match position.take() {
+            Some(last) => {
+                let found = self.read_covering(&pool, last.page_id, key);
+                // this operation keeps the leaves from being reclaimed from here on
+                self.leave(&pool, last.epoch);
+                found?
+            }
+            None => None,
+        };
+        let (mut guard, mut node) = match resumed {
+            Some(found) => found,
+            None => loop {
+                let (leaf_id, _) = self.descend(&pool, key, 0, None)?;
+                if let Some(found) = self.read_covering(&pool, leaf_id, key)? {
+                    break found;
+                }
+            },
+        };
+        loop {
+            let batch = Self::entries_in(&node, lower, upper);
+            if !batch.is_empty() {
+                *position = Some(ScanPosition {
+                    page_id: guard.get_page_id(),
+                    epoch: self.join(op.epoch),
+                });
+                return Ok(Some((guard.get_page_id(), batch)));
+            }
+            // the keys to the right are above the high key, which is at or past the end of the range
+            let past_end = match (&node.high_key, upper) {
+                (None, _) => true,
+                (Some(_), Bound::Unbounded) => false,
+                (Some(high), Bound::Included(end) | Bound::Excluded(end)) => high >= end,
+            };
+            if past_end || node.right_link == INVALID_PAGE_ID {
+                return Ok(None);
+            }
+            let right_link = node.right_link;
+            guard = couple(guard, || pool.fetch_page_read(right_link))?;
+            node = Self::decode(guard.get_page_id(), &guard)?;
+        }
+    }
+
+    /// The entries in the range of the first leaf, from the right, that has any. Leaves only link to the right, so the
+    /// leaf to the left of one without entries in the range is found by searching for that leaf's low key, which its
+    /// left neighbour covers
+    fn prev_batch(
+        &self,
+        lower: &Bound<Vec<u8>>,
+        upper: &Bound<Vec<u8>>,
+    ) -> IndexResult<Option<Batch>> {
+        let pool = rw_acquire_shared(&self.bufmgr);
+        let _op = self.enter(&pool);
+        let mut key = match upper {
+            Bound::Included(key) | Bound::Excluded(key) => Some(key.clone()),
+            Bound::Unbounded => None,
+        };
+        loop {
+            let (page_id, node) = match &key {
+                Some(key) => self.descend(&pool, key, 0, None)?,
+                None => self.descend_last(&pool)?,
+            };
+            let batch = Self::entries_in(&node, lower, upper);
+            if !batch.is_empty() {
+                return Ok(Some((page_id, batch)));
+            }
+            match node.low_key {
+                Some(low) if Self::above(&low, lower) => key = Some(low),
+                _ => return Ok(None),
+            }
+        }
+    }
+
+    /// True if `key` is at or after the lower bound
+    fn above(key: &[u8], lower: &Bound<Vec<u8>>) -> bool {
+        match lower {
+            Bound::Included(start) => key >= start.as_slice(),
+            Bound::Excluded(start) => key > start.as_slice(),
+            Bound::Unbounded => true,
+        }
+    }
+
+    /// True if `key` is at or before the upper bound
+    fn below(key: &[u8], upper: &Bound<Vec<u8>>) -> bool {
+        match upper {
+            Bound::Included(end) => key <= end.as_slice(),
+            Bound::Excluded(end) => key < end.as_slice(),
+            Bound::Unbounded => true,
+        }
+    }
+
+    fn entries_in(
+        node: &IndexPage,
+        lower: &Bound<Vec<u8>>,
+        upper: &Bound<Vec<u8>>,
+    ) -> Vec<(Vec<u8>, ObjectPtr)> {
+        node.keys
+            .iter()
+            .zip(&node.values)
+            .filter(|(key, _)| Self::above(key, lower) && Self::below(key, upper))
+            .map(|(key, &value)| (key.clone(), value))
+            .collect()
+    }
+
+    /// Called after splitting a node that had no parent on the way down. If the node is still the root, install a new
+    /// root above it and return None. Otherwise the root has grown since the descent, and the id of the node at `level`
+    /// that now covers `separator` is returned so the split can be posted there. The node itself need not be latched: a
+    /// split of it that happened since moves entries to its right, where the new root reaches them through right links
+    fn grow_root<'a>(
+        &self,
+        pool: &BufferPoolInternal,
+        txn: &'a Transaction<'a>,
+        child_id: PageId,
+        level: u32,
+        separator: &[u8],
+        right_id: PageId,
+    ) -> IndexResult<Option<PageId>> {
+        {
+            let mut meta_guard = txn.fetch_page_write(self.meta_page_id)?;
+            let mut meta = IndexMetaPage::from_bytes(&meta_guard)
+                .ok_or(IndexError::Corrupt(self.meta_page_id))?;
+            if meta.root == child_id {
+                let mut root_guard = txn.new_page_write()?;
+                let root = IndexPage::new_root(level, child_id, separator.to_vec(), right_id);
+                root.write_to(&mut root_guard);
+                meta.root = root_guard.get_page_id();
+                meta.height += 1;
+                meta.write_to(&mut meta_guard);
+                return Ok(None);
+            }
+        }
+        let (parent_id, _) = self.descend(pool, separator, level, None)?;
+        Ok(Some(parent_id))
+    }
+
+    /// Merge or redistribute the underfull leaf covering `key` with a sibling, then work upwards for as long as merging
+    /// leaves the parent underfull. Rebalancing is best effort: if a concurrent operation changed the nodes involved,
+    /// the node is simply left underfull
+    fn rebalance(&self, pool: &BufferPoolInternal, key: &[u8]) -> IndexResult<()> {
+        let mut level = 0;
+        loop {
+            let (parent_id, parent) = self.descend(pool, key, level + 1, None)?;
+            if parent.level != level + 1 {
+                break;
+            }
+            if parent.children.len() < 2 {
+                level += 1;
+                continue;
+            }
+            let idx = parent.keys.partition_point(|k| k.as_slice() < key);
+            let left_idx = idx.min(parent.children.len() - 2);
+            let left_id = parent.children[left_idx];
+            let right_id = parent.children[left_idx + 1];
+            if !self.merge_siblings(pool, parent_id, left_id, &parent.keys[left_idx], right_id)? {
+                break;
+            }
+            level += 1;
+        }
+        self.shrink_root(pool)
+    }
+
+    /// Merge two adjacent children of a parent, or even out their entries if they do not fit in one node. Latches are
+    /// taken left to right and then on the parent. Returns true if a merge left the parent underfull
+    fn merge_siblings(
+        &self,
+        pool: &BufferPoolInternal,
+        parent_id: PageId,
+        left_id: PageId,
+        separator: &[u8],
+        right_id: PageId,
+    ) -> IndexResult<bool> {
+        let txn = pool.begin_system();
+        let mut left_guard = txn.fetch_page_write(left_id)?;
+        let mut left = Self::decode(left_id, &left_guard)?;
+        if left.deleted
+            || left.right_link != right_id
+            || left.high_key.as_deref() != Some(separator)
+        {
+            return Ok(false);
+        }
+        let mut right_guard = txn.fetch_page_write(right_id)?;
+        let mut right = Self::decode(right_id, &right_guard)?;
+        if right.deleted || !(left.is_underfull() || right.is_underfull()) {
+            return Ok(false);
+        }
+        let mut parent_guard = txn.fetch_page_write(parent_id)?;
+        let mut parent = Self::decode(parent_id, &parent_guard)?;
+        let idx = match parent
+            .children
+            .windows(2)
+            .position(|pair| pair == [left_id, right_id])
+        {
+            Some(idx) if !parent.deleted => idx,
+            _ => return Ok(false),
+        };
+
+        left.absorb(&right, separator.to_vec());
+        if left.encoded_size() <= PAGE_SIZE * 3 / 4 {
+            // until the right node is marked deleted both nodes hold its entries, which searchers tolerate
+            left.write_to(&mut left_guard);
+            right.mark_deleted(left_id);
+            right.write_to(&mut right_guard);
+            parent.remove_child(idx);
+            parent.write_to(&mut parent_guard);
+            drop((parent_guard, right_guard, left_guard));
+            txn.commit()?;
+            self.retire(right_id);
+            return Ok(parent.is_underfull());
+        }
+
+        // entries that move left are no longer reachable through the right node, which searchers detect by its low key
+        let (right, separator) = left.split();
+        left.right_link = right_id;
+        parent.keys[idx] = separator;
+        if !parent.fits() {
+            return Ok(false);
+        }
+        right.write_to(&mut right_guard);
+        left.write_to(&mut left_guard);
+        parent.write_to(&mut parent_guard);
+        drop((parent_guard, right_guard, left_guard));
+        txn.commit()?;
+        Ok(false)
+    }
+
+    /// Remove roots that are left with a single child, lowering the tree. The child is latched before the root, in the
+    /// same order as a split of the child would take them
+    fn shrink_root(&self, pool: &BufferPoolInternal) -> IndexResult<()> {
+        loop {
+            let root_id = self.read_meta(pool)?.root;
+            let root = self.read_node(pool, root_id)?;
+            if root.is_leaf() || root.children.len() != 1 {
+                return Ok(());
+            }
+            let child_id = root.children[0];
+            let txn = pool.begin_system();
+            let child_guard = txn.fetch_page_write(child_id)?;
+            let child = Self::decode(child_id, &child_guard)?;
+            let mut root_guard = txn.fetch_page_write(root_id)?;
+            let mut root = Self::decode(root_id, &root_guard)?;
+            let mut meta_guard = txn.fetch_page_write(self.meta_page_id)?;
+            let mut meta = IndexMetaPage::from_bytes(&meta_guard)
+                .ok_or(IndexError::Corrupt(self.meta_page_id))?;
+            // a split of the child that is still on its way to the root keeps it
+            if meta.root != root_id
+                || root.deleted
+                || root.children != [child_id]
+                || child.deleted
+                || child.right_link != INVALID_PAGE_ID
+            {
+                return Ok(());
+            }
+            meta.root = child_id;
+            meta.height -= 1;
+            meta.write_to(&mut meta_guard);
+            // searchers that still reach the old root start over from the meta page
+            root.mark_deleted(INVALID_PAGE_ID);
+            root.write_to(&mut root_guard);
+            drop((meta_guard, root_guard, child_guard));
+            txn.commit()?;
+            self.retire(root_id);
+        }
+    }
+}
+
+/// Writes the nodes of a bulk-loaded tree level by level, from left to right, straight to the disk
+struct BulkLoader<'a> {
+    pool: &'a BufferPoolInternal,
+    /// Allocates the pages, and frees them again if the load fails
+    txn: &'a Transaction<'a>,
+    /// Bytes of a page filled before a node is closed and the next one started
+    budget: usize,
+}
+
+impl BulkLoader<'_> {
+    /// Write every level of the tree and sync them. Returns the contents of the meta page
+    fn build(
+        &mut self,
+        entries: impl Iterator<Item = (Vec<u8>, ObjectPtr)>,
+    ) -> IndexResult<IndexMetaPage> {
+        let mut nodes = self.build_leaves(entries)?;
+        let mut meta = IndexMetaPage::new(INVALID_PAGE_ID);
+        while nodes.len() > 1 {
+            nodes = self.build_internal(meta.height, nodes)?;
+            meta.height += 1;
+        }
+        meta.root = nodes[0].0;
+        rw_acquire_shared(self.pool.get_diskmgr()).sync()?;
+        Ok(meta)
+    }
+
+    /// Write the leaves. Returns each leaf with its high key, which the level above uses as separators
+    fn build_leaves(
+        &mut self,
+        entries: impl Iterator<Item = (Vec<u8>, ObjectPtr)>,
+    ) -> IndexResult<Vec<(PageId, Option<Vec<u8>>)>> {
+        let mut nodes = Vec::new();
+        let mut node = IndexPage::new_leaf();
+        let mut page_id = self.allocate()?;
+        let mut size = node.encoded_size();
+        for (n, (key, value)) in entries.enumerate() {
+            if key.len() > MAX_KEY_SIZE {
+                return Err(IndexError::KeyTooLarge(key.len()));
+            }
+            match node.keys.last().or(node.low_key.as_ref()) {
+                Some(last) if key == *last => return Err(IndexError::DuplicateKey),
+                Some(last) if key < *last => return Err(IndexError::Unsorted(n)),
+                _ => {}
+            }
+            let cost = 8 + key.len() + ObjectPtr::SIZE;
+            // the last key of a leaf is stored a second time as its high key
+            if !node.keys.is_empty() && size + cost + 8 + key.len() > self.budget {
+                let next_id = self.allocate()?;
+                let high_key = node.keys.last().unwrap().clone();
+                node.high_key = Some(high_key.clone());
+                node.right_link = next_id;
+                self.write(page_id, &node)?;
+                nodes.push((page_id, Some(high_key.clone())));
+                node = IndexPage::new_leaf();
+                node.low_key = Some(high_key);
+                page_id = next_id;
+                size = node.encoded_size();
+            }
+            node.keys.push(key);
+            node.values.push(value);
+            size += cost;
+        }
+        self.write(page_id, &node)?;
+        nodes.push((page_id, None));
+        Ok(nodes)
+    }
+
+    /// Write the internal nodes at `level` above `children`. Returns each node with its high key
+    fn build_internal(
+        &mut self,
+        level: u32,
+        children: Vec<(PageId, Option<Vec<u8>>)>,
+    ) -> IndexResult<Vec<(PageId, Option<Vec<u8>>)>> {
+        let mut nodes = Vec::new();
+        let mut node = IndexPage::new_internal(level);
+        let mut page_id = self.allocate()?;
+        let mut size = node.encoded_size();
+        for (child, high_key) in children {
+            // the high key of a child separates it from the next one, or becomes the node's high key
+            let cost = 8 + high_key.as_ref().map_or(0, |key| 8 + key.len());
+            if !node.children.is_empty() && size + cost > self.budget {
+                let next_id = self.allocate()?;
+                let node_high_key = node.keys.pop().unwrap();
+                node.high_key = Some(node_high_key.clone());
+                node.right_link = next_id;
+                self.write(page_id, &node)?;
+                nodes.push((page_id, Some(node_high_key.clone())));
+                node = IndexPage::new_internal(level);
+                node.low_key = Some(node_high_key);
+                page_id = next_id;
+                size = node.encoded_size();
+            }
+            node.children.push(child);
+            node.keys.extend(high_key);
+            size += cost;
+        }
+        self.write(page_id, &node)?;
+        nodes.push((page_id, None));
+        Ok(nodes)
+    }
+
+    fn allocate(&mut self) -> IndexResult<PageId> {
+        Ok(self.txn.allocate_page()?)
+    }
+
+    fn write(&self, page_id: PageId, node: &IndexPage) -> IndexResult<()> {
+        let mut buf = [0u8; PAGE_SIZE];
+        node.write_to(&mut buf);
+        // redo skips log records up to a page's LSN. The page was allocated before this, after any record left behind by
+        // an earlier use of its id, including one logged since the load started, so stamping it with the end of the log
+        // keeps all of them from being applied to it
+        let page_lsn = self
+            .pool
+            .get_wal()
+            .map_or(INVALID_LSN, |wal| rw_acquire_shared(wal).get_next_lsn());
+        set_page_lsn(&mut buf, page_lsn);
+        rw_acquire_shared(self.pool.get_diskmgr()).write_page(page_id, &buf)?;
+        Ok(())
+    }
+}
+
+/// A B-link tree over keys of type `K`, which are stored in their order-preserving encoding. Handles are cheap to clone,
+/// and clones share the same `BLinkTreeInternal`
+pub struct BLinkTree<K: KeyCodec = Vec<u8>> {
+    tree: Arc<BLinkTreeInternal>,
+    _key: PhantomData<fn(K) -> K>,
+}
+
+impl<K: KeyCodec> Clone for BLinkTree<K> {
+    fn clone(&self) -> Self {
+        Self::from_internal(self.tree.clone())
+    }
+}
+
+impl<K: KeyCodec> BLinkTree<K> {
+    /// Create an empty tree
+    pub fn create(bufmgr: BufferPool) -> IndexResult<Self> {
+        Ok(Self::from_internal(Arc::new(BLinkTreeInternal::create(
+            bufmgr,
+        )?)))
+    }
+
+    /// Open an existing tree through its meta page
+    pub fn open(bufmgr: BufferPool, meta_page_id: PageId) -> Self {
+        Self::from_internal(Arc::new(BLinkTreeInternal::open(bufmgr, meta_page_id)))
+    }
+
+    /// Build a tree from entries sorted by key, filling its nodes to `fill_factor` percent. Input that is not sorted, or
+    /// has a key twice, is refused
+    pub fn bulk_load(
+        bufmgr: BufferPool,
+        entries: impl IntoIterator<Item = (K, ObjectPtr)>,
+        fill_factor: u8,
+    ) -> IndexResult<Self> {
+        let entries = entries
+            .into_iter()
+            .map(|(key, value)| (key.encode(), value));
+        Ok(Self::from_internal(Arc::new(BLinkTreeInternal::bulk_load(
+            bufmgr,
+            entries,
+            fill_factor,
+        )?)))
+    }
+
+    /// A typed handle to a tree whose keys are known to be of type `K`
+    pub fn from_internal(tree: Arc<BLinkTreeInternal>) -> Self {
+        Self {
+            tree,
+            _key: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn get_internal(&self) -> &Arc<BLinkTreeInternal> {
+        &self.tree
+    }
+
+    #[inline]
+    pub fn get_meta_page_id(&self) -> PageId {
+        self.tree.get_meta_page_id()
+    }
+
+    pub fn get(&self, key: &K) -> IndexResult<Option<ObjectPtr>> {
+        self.tree.get(&key.encode())
+    }
+
+    /// Insert a key that is not yet in the tree. The limit on the key size applies to its encoding
+    pub fn insert(&self, key: &K, value: ObjectPtr) -> IndexResult<()> {
+        self.tree.insert(&key.encode(), value)
+    }
+
+    /// Remove a key from the tree. Returns false if it was not present
+    pub fn delete(&self, key: &K) -> IndexResult<bool> {
+        self.tree.delete(&key.encode())
+    }
+
+    /// Iterate over the keys in `range` in order, or in reverse order through `rev`
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Cursor<'_, K> {
+        let encode = |bound: Bound<&K>| match bound {
+            Bound::Included(key) => Bound::Included(key.encode()),
+            Bound::Excluded(key) => Bound::Excluded(key.encode()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        Cursor::new(
+            &self.tree,
+            encode(range.start_bound()),
+            encode(range.end_bound()),
+        )
+    }
+
+    /// Iterate over the keys that start with `prefix`, which is made of the leading components of a tuple key
+    pub fn prefix<P: KeyCodec>(&self, prefix: &P) -> Cursor<'_, K> {
+        let start = prefix.encode();
+        // the first byte string that sorts after every string starting with the prefix
+        let mut end = start.clone();
+        while end.last() == Some(&0xff) {
+            end.pop();
+        }
+        let upper = match end.last_mut() {
+            Some(last) => {
+                *last += 1;
+                Bound::Excluded(end)
+            }
+            None => Bound::Unbounded,
+        };
+        Cursor::new(&self.tree, Bound::Included(start), upper)
+    }
+}
+
+/// Iterator over a range of keys, created by `BLinkTree::range` and `BLinkTree::prefix`. No latch is held between calls:
+/// the cursor buffers the entries of one leaf at a time. Going forward, it finds the next leaf through the right link of
+/// the one it read last, and only searches for the key after the last one it returned if that leaf was merged away.
+/// Going backward, it searches for every leaf. Either way, concurrent splits and merges never make it skip or repeat a
+/// key. Keys inserted or deleted while the scan runs may or may not be seen. While a cursor lives, pages deleted from
+/// the tree are not reclaimed
+pub struct Cursor<'a, K: KeyCodec> {
+    tree: &'a BLinkTreeInternal,
+    /// What is left of the range. Each end moves past the keys returned from it
+    lower: Bound<Vec<u8>>,
+    upper: Bound<Vec<u8>>,
+    /// The leaf the front batch came from
+    position: Option<ScanPosition>,
+    front: VecDeque<(Vec<u8>, K, ObjectPtr)>,
+    back: VecDeque<(Vec<u8>, K, ObjectPtr)>,
+    /// Set once the two ends have met
+    done: bool,
+}
+
+impl<'a, K: KeyCodec> Cursor<'a, K> {
+    fn new(tree: &'a BLinkTreeInternal, lower: Bound<Vec<u8>>, upper: Bound<Vec<u8>>) -> Self {
+        Self {
+            tree,
+            lower,
+            upper,
+            position: None,
+            front: VecDeque::new(),
+            back: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    fn decode_batch(batch: Option<Batch>) -> IndexResult<VecDeque<(Vec<u8>, K, ObjectPtr)>> {
+        let Some((page_id, entries)) = batch else {
+            return Ok(VecDeque::new());
+        };
+        entries
+            .into_iter()
+            .map(|(bytes, value)| {
+                let key = K::decode(&bytes).ok_or(IndexError::Corrupt(page_id))?;
+                Ok((bytes, key, value))
+            })
+            .collect()
+    }
+
+    fn finish(&mut self) {
+        self.front.clear();
+        self.back.clear();
+        self.done = true;
+    }
+}
+
+impl<K: KeyCodec> Iterator for Cursor<'_, K> {
+    type Item = IndexResult<(K, ObjectPtr)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if self.front.is_empty() {
+            match self
+                .tree
+                .next_batch(&self.lower, &self.upper, &mut self.position)
+                .and_then(Self::decode_batch)
+            {
+                Ok(batch) => self.front = batch,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        let (bytes, key, value) = self.front.pop_front()?;
+        // the other end may have returned this key already
+        if !BLinkTreeInternal::below(&bytes, &self.upper) {
+            self.finish();
+            return None;
+        }
+        self.lower = Bound::Excluded(bytes);
+        Some(Ok((key, value)))
+    }
+}
+
+impl<K: KeyCodec> Drop for Cursor<'_, K> {
+    fn drop(&mut self) {
+        if let Some(position) = self.position.take() {
+            self.tree
+                .leave(&rw_acquire_shared(&self.tree.bufmgr), position.epoch);
+        }
+    }
+}
+
+impl<K: KeyCodec> DoubleEndedIterator for Cursor<'_, K> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if self.back.is_empty() {
+            match BLinkTreeInternal::prev_batch(self.tree, &self.lower, &self.upper)
+                .and_then(Self::decode_batch)
+            {
+                Ok(batch) => self.back = batch,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        let (bytes, key, value) = self.back.pop_back()?;
+        if !BLinkTreeInternal::above(&bytes, &self.lower) {
+            self.finish();
+            return None;
+        }
+        self.upper = Bound::Excluded(bytes);
+        Some(Ok((key, value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    use rand::seq::SliceRandom;
+
+    use super::*;
+    use crate::bootstrap::{DbContext, Options};
+    use crate::shared::TxnId;
+    use crate::storage::bufmgr::BufferPoolInternal;
+    use crate::storage::catalog::{IndexOptions, ValueType};
+    use crate::storage::diskmgr::{DiskMgr, DiskMgrInternal, OpenMode};
+    use crate::storage::page::{get_page_lsn, SlotId};
+    use crate::storage::wal::{LogBody, LogRecord, WalInternal};
+
+    fn make_diskmgr(name: &str) -> DiskMgr {
+        Arc::new(parking_lot::RwLock::new(DiskMgrInternal::recreate(
+            &(crate::shared::cwd() + "/data/test/__blink_tree__/" + name),
+        )))
+    }
+
+    fn make_pool(name: &str, pool_size: usize) -> BufferPool {
+        Arc::new(parking_lot::RwLock::new(BufferPoolInternal::new(
+            pool_size,
+            2,
+            make_diskmgr(name),
+        )))
+    }
+
+    /// A database path with nothing left behind by an earlier run
+    fn db_path(name: &str) -> String {
+        let path = crate::shared::cwd() + "/data/test/__blink_tree__/" + name;
+        for file in [path.clone(), DbContext::wal_path(&path)] {
+            let _ = std::fs::remove_file(file);
+        }
+        path
+    }
+
+    fn make_tree(name: &str, pool_size: usize) -> BLinkTree {
+        BLinkTree::create(make_pool(name, pool_size)).unwrap()
+    }
+
+    fn ptr(i: usize) -> ObjectPtr {
+        ObjectPtr::new(i as PageId, (i % 64) as SlotId)
+    }
+
+    /// Keys are padded so that a few thousand of them are enough to build a tree of several levels
+    fn key(i: usize) -> Vec<u8> {
+        format!("song-{:08}-{}", i, "la".repeat(48)).into_bytes()
+    }
+
+    fn height<K: KeyCodec>(tree: &BLinkTree<K>) -> u32 {
+        let pool = rw_acquire_shared(&tree.tree.bufmgr);
+        tree.tree.read_meta(&pool).unwrap().height
+    }
+
+    #[test]
+    fn insert_get() {
+        let tree = make_tree("insert_get.bin", 16);
+        let mut ids: Vec<usize> = (0..3000).collect();
+        ids.shuffle(&mut rand::thread_rng());
+        for &i in &ids {
+            tree.insert(&key(i), ptr(i)).unwrap();
+        }
+        assert!(height(&tree) > 2);
+        for i in 0..3000 {
+            assert_eq!(tree.get(&key(i)).unwrap(), Some(ptr(i)));
+        }
+        assert_eq!(tree.get(&key(3000)).unwrap(), None);
+        assert!(matches!(
+            tree.insert(&key(42), ptr(0)),
+            Err(IndexError::DuplicateKey)
+        ));
+        assert!(matches!(
+            tree.insert(&vec![1u8; MAX_KEY_SIZE], ObjectPtr::default()),
+            Err(IndexError::KeyTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn concurrent_insert() {
+        let tree = Arc::new(make_tree("concurrent_insert.bin", 64));
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(8)
+            .build()
+            .unwrap();
+        pool.scope(|scope| {
+            for t in 0..8 {
+                let tree = tree.clone();
+                scope.spawn(move |_| {
+                    // interleave the threads' keys so they keep splitting the same nodes
+                    for i in (t..2000).step_by(8) {
+                        tree.insert(&key(i), ptr(i)).unwrap();
+                        if i % 64 == 0 {
+                            assert_eq!(tree.get(&key(i)).unwrap(), Some(ptr(i)));
+                        }
+                    }
+                });
+            }
+        });
+        for i in 0..2000 {
+            assert_eq!(tree.get(&key(i)).unwrap(), Some(ptr(i)));
+        }
+    }
+
+    #[test]
+    fn delete_merge() {
+        let tree = make_tree("delete_merge.bin", 16);
+        for i in 0..3000 {
+            tree.insert(&key(i), ptr(i)).unwrap();
+        }
+        let full_height = height(&tree);
+        assert!(!tree.delete(&key(3000)).unwrap());
+
+        let mut ids: Vec<usize> = (0..3000).filter(|i| i % 4 != 0).collect();
+        ids.shuffle(&mut rand::thread_rng());
+        for &i in &ids {
+            assert!(tree.delete(&key(i)).unwrap());
+        }
+        assert!(!tree.delete(&key(1)).unwrap());
+        for i in 0..3000 {
+            let expected = (i % 4 == 0).then(|| ptr(i));
+            assert_eq!(tree.get(&key(i)).unwrap(), expected);
+        }
+
+        for i in (0..3000).step_by(4) {
+            assert!(tree.delete(&key(i)).unwrap());
+        }
+        assert!(height(&tree) < full_height);
+        assert_eq!(tree.get(&key(0)).unwrap(), None);
+        // every merged node was reclaimed once the operation that unlinked it finished
+        assert!(acquire(&tree.tree.drain).retired.is_empty());
+
+        for i in 0..500 {
+            tree.insert(&key(i), ptr(i)).unwrap();
+        }
+        for i in 0..500 {
+            assert_eq!(tree.get(&key(i)).unwrap(), Some(ptr(i)));
+        }
+    }
+
+    #[test]
+    fn concurrent_insert_delete() {
+        let tree = Arc::new(make_tree("concurrent_insert_delete.bin", 64));
+        for i in 0..2000 {
+            tree.insert(&key(i), ptr(i)).unwrap();
+        }
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(8)
+            .build()
+            .unwrap();
+        pool.scope(|scope| {
+            for t in 0..8 {
+                let tree = tree.clone();
+                scope.spawn(move |_| {
+                    for i in (t..2000).step_by(8) {
+                        // half the threads delete the odd keys, the others insert new ones, and all of them check that
+                        // the even keys stay visible while nodes merge and split around them
+                        if t % 2 == 0 {
+                            tree.insert(&key(i + 2000), ptr(i + 2000)).unwrap();
+                        } else {
+                            assert!(tree.delete(&key(i)).unwrap());
+                        }
+                        let even = i & !1;
+                        assert_eq!(tree.get(&key(even)).unwrap(), Some(ptr(even)));
+                    }
+                });
+            }
+        });
+        for i in 0..4000 {
+            let expected = (i % 2 == 0).then(|| ptr(i));
+            assert_eq!(tree.get(&key(i)).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn reopen() {
+        let tree = make_tree("reopen.bin", 8);
+        for i in 0..1000 {
+            tree.insert(&key(i), ptr(i)).unwrap();
+        }
+        let tree = BLinkTree::open(tree.tree.bufmgr.clone(), tree.get_meta_page_id());
+        for i in 0..1000 {
+            assert_eq!(tree.get(&key(i)).unwrap(), Some(ptr(i)));
+        }
+    }
+
+    #[test]
+    fn typed_keys() {
+        let bufmgr = make_pool("typed_keys.bin", 16);
+        let tree = BLinkTree::<(String, i32)>::create(bufmgr.clone()).unwrap();
+        let artists = ["Lorde", "The Neighbourhood", "", "Lorde\0"];
+        let mut ids: Vec<i32> = (-1000..1000).collect();
+        ids.shuffle(&mut rand::thread_rng());
+        for &id in &ids {
+            for (a, artist) in artists.iter().enumerate() {
+                let key = (artist.to_string(), id);
+                tree.insert(&key, ObjectPtr::new(a as PageId, id as SlotId))
+                    .unwrap();
+            }
+        }
+        assert!(height(&tree) > 1);
+        for &id in &ids[..500] {
+            assert!(tree.delete(&("Lorde".to_string(), id)).unwrap());
+        }
+        for &id in &ids {
+            let expected = (!ids[..500].contains(&id)).then(|| ObjectPtr::new(0, id as SlotId));
+            assert_eq!(tree.get(&("Lorde".to_string(), id)).unwrap(), expected);
+        }
+
+        // the leaves hold the keys in the order of the typed keys, not of their bytes in memory
+        let pool = rw_acquire_shared(&bufmgr);
+        let (mut page_id, _) = tree.get_internal().descend(&pool, &[], 0, None).unwrap();
+        let mut keys = Vec::new();
+        while page_id != INVALID_PAGE_ID {
+            let leaf = tree.get_internal().read_node(&pool, page_id).unwrap();
+            keys.extend(
+                leaf.keys
+                    .iter()
+                    .map(|k| <(String, i32)>::decode(k).unwrap()),
+            );
+            page_id = leaf.right_link;
+        }
+        assert_eq!(keys.len(), 4 * 2000 - 500);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    fn int_tree(name: &str) -> BLinkTree<i32> {
+        BLinkTree::create(make_pool(name, 32)).unwrap()
+    }
+
+    fn keys_of(cursor: impl Iterator<Item = IndexResult<(i32, ObjectPtr)>>) -> Vec<i32> {
+        cursor.map(|entry| entry.unwrap().0).collect()
+    }
+
+    #[test]
+    fn range_scans() {
+        let tree = int_tree("range_scans.bin");
+        // the even numbers, enough of them for dozens of leaves
+        let mut ids: Vec<i32> = (-8000..8000).step_by(2).collect();
+        ids.shuffle(&mut rand::thread_rng());
+        for &i in &ids {
+            tree.insert(&i, ObjectPtr::new(i as PageId, 0)).unwrap();
+        }
+        let evens = |range: std::ops::Range<i32>| range.filter(|i| i % 2 == 0).collect::<Vec<_>>();
+
+        assert_eq!(keys_of(tree.range(..)), evens(-8000..8000));
+        // a forward scan reaches every leaf after the first through the right link of the one before, and the first
+        // through a single descent
+        let pool = tree.tree.bufmgr.clone();
+        rw_acquire_shared(&pool).reset_stats();
+        assert_eq!(tree.range(..).count(), 8000);
+        let stats = rw_acquire_shared(&pool).stats();
+        let fetches = stats.hits + stats.misses;
+        assert!(fetches <= 2 * leaf_count(&tree) as u64 + height(&tree) as u64 + 1);
+        assert_eq!(keys_of(tree.range(-11..11)), evens(-11..11));
+        assert_eq!(keys_of(tree.range(-10..=10)), evens(-10..11));
+        assert_eq!(
+            keys_of(tree.range((Bound::Excluded(-10), Bound::Excluded(10)))),
+            evens(-9..10)
+        );
+        assert_eq!(keys_of(tree.range(..-7990)), evens(-8000..-7990));
+        assert_eq!(keys_of(tree.range(7990..)), evens(7990..8000));
+        assert!(keys_of(tree.range(5..5)).is_empty());
+        assert!(keys_of(tree.range(9000..)).is_empty());
+
+        let mut reversed = evens(-1000..1001);
+        reversed.reverse();
+        assert_eq!(keys_of(tree.range(-1000..=1000).rev()), reversed);
+        let mut reversed = evens(-8000..8000);
+        reversed.reverse();
+        assert_eq!(keys_of(tree.range(..).rev()), reversed);
+        assert!(keys_of(tree.range(..-8000).rev()).is_empty());
+
+        // both ends meet in the middle without returning a key twice
+        let mut cursor = tree.range(-3000..3000);
+        let mut seen = Vec::new();
+        loop {
+            let front = cursor.next().map(|entry| entry.unwrap().0);
+            let back = cursor.next_back().map(|entry| entry.unwrap().0);
+            seen.extend(front.into_iter().chain(back));
+            if front.is_none() && back.is_none() {
+                break;
+            }
+        }
+        seen.sort();
+        assert_eq!(seen, evens(-3000..3000));
+    }
+
+    #[test]
+    fn prefix_scans() {
+        let tree = BLinkTree::<(String, i32)>::create(make_pool("prefix_scans.bin", 16)).unwrap();
+        let artists = ["Lorde", "Lorde Jr", "Lord", "The Neighbourhood"];
+        for id in 0..500 {
+            for artist in artists {
+                tree.insert(&(artist.to_string(), id), ObjectPtr::new(id as PageId, 0))
+                    .unwrap();
+            }
+        }
+        let ids: Vec<i32> = tree
+            .prefix(&"Lorde".to_string())
+            .map(|entry| {
+                let ((artist, id), _) = entry.unwrap();
+                assert_eq!(artist, "Lorde");
+                id
+            })
+            .collect();
+        assert_eq!(ids, (0..500).collect::<Vec<_>>());
+        let last: Vec<i32> = tree
+            .prefix(&"The Neighbourhood".to_string())
+            .rev()
+            .take(3)
+            .map(|entry| entry.unwrap().0 .1)
+            .collect();
+        assert_eq!(last, [499, 498, 497]);
+        assert_eq!(tree.prefix(&"Lor".to_string()).count(), 0);
+        // a prefix of every component is the whole key
+        assert_eq!(tree.prefix(&("Lord".to_string(), 7)).count(), 1);
+    }
+
+    #[test]
+    fn scans_during_splits_and_merges() {
+        let tree = Arc::new(int_tree("concurrent_scans.bin"));
+        // the multiples of four stay put while the other keys come and go around them
+        for i in (0..8000).step_by(4) {
+            tree.insert(&i, ObjectPtr::new(i as PageId, 0)).unwrap();
+        }
+        let stable: Vec<i32> = (0..8000).step_by(4).collect();
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        std::thread::scope(|scope| {
+            for t in 0..2 {
+                let (tree, done) = (tree.clone(), done.clone());
+                scope.spawn(move || {
+                    let moving: Vec<i32> = (0..8000).filter(|i| i % 4 == 1 + t).collect();
+                    while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                        for &i in &moving {
+                            tree.insert(&i, ObjectPtr::new(i as PageId, 0)).unwrap();
+                        }
+                        for &i in &moving {
+                            assert!(tree.delete(&i).unwrap());
+                        }
+                    }
+                });
+            }
+            for round in 0..20 {
+                let scanned: Vec<i32> = if round % 2 == 0 {
+                    keys_of(tree.range(..))
+                } else {
+                    let mut keys = keys_of(tree.range(..).rev());
+                    keys.reverse();
+                    keys
+                };
+                assert!(scanned.windows(2).all(|pair| pair[0] < pair[1]));
+                let kept: Vec<i32> = scanned.into_iter().filter(|i| i % 4 == 0).collect();
+                assert_eq!(kept, stable);
+            }
+            done.store(true, std::sync::atomic::Ordering::Relaxed);
+        });
+    }
+
+    #[test]
+    fn logged_operations() {
+        let diskmgr = make_diskmgr("logged.bin");
+        let wal_path = db_path("logged_wal.bin");
+        let wal = Arc::new(parking_lot::RwLock::new(
+            WalInternal::open(&wal_path, OpenMode::CreateNew).unwrap(),
+        ));
+        let bufmgr = Arc::new(parking_lot::RwLock::new(BufferPoolInternal::with_wal(
+            16,
+            2,
+            diskmgr.clone(),
+            wal.clone(),
+        )));
+        let tree = BLinkTree::create(bufmgr.clone()).unwrap();
+        let mut ids: Vec<usize> = (0..1500).collect();
+        ids.shuffle(&mut rand::thread_rng());
+        for &i in &ids {
+            tree.insert(&key(i), ptr(i)).unwrap();
+        }
+        for &i in &ids[..1000] {
+            assert!(tree.delete(&key(i)).unwrap());
+        }
+        for &i in &ids[1000..] {
+            assert_eq!(tree.get(&key(i)).unwrap(), Some(ptr(i)));
+        }
+
+        // every change was made by a transaction that committed. Frees are logged outside of any transaction
+        let wal = wal.read();
+        let records: Vec<LogRecord> = wal.iter_from(0).unwrap().map(|(_, r)| r).collect();
+        let committed: HashSet<TxnId> = records
+            .iter()
+            .filter(|r| r.body == LogBody::Commit)
+            .map(|r| r.txn_id)
+            .collect();
+        assert!(committed.len() > 2500);
+        assert!(records.iter().all(|r| committed.contains(&r.txn_id)
+            || matches!(
+                r.body,
+                LogBody::AllocatePage { .. } | LogBody::FreePage { .. }
+            )));
+
+        // no page reaches the disk ahead of its log records
+        bufmgr.read().flush_all().unwrap();
+        let mut page_buf = [0u8; PAGE_SIZE];
+        for page_id in 2..diskmgr.read().get_page_count() {
+            diskmgr.read().read_page(page_id, &mut page_buf).unwrap();
+            assert!(get_page_lsn(&page_buf) < wal.get_flushed_lsn());
+        }
+    }
+
+    #[test]
+    fn split_survives_rollback_of_its_insert() {
+        let path = db_path("uncommitted.bin");
+        let (meta_page_id, split_key, leaves) = {
+            let ctx = DbContext::open(&path, Options::default()).unwrap();
+            let tree: BLinkTree = BLinkTree::create(ctx.get_bufmgr().clone()).unwrap();
+            let pool = rw_acquire_shared(ctx.get_bufmgr());
+            // the leaf stays latched until the insert commits, so a split is told apart by the pages it allocated
+            let pages_in_use = || {
+                let diskmgr = rw_acquire_shared(pool.get_diskmgr());
+                (0..diskmgr.get_page_count())
+                    .filter(|&page_id| diskmgr.check_page_id(page_id).is_ok())
+                    .count()
+            };
+            let mut i = 0;
+            loop {
+                let leaves = leaf_count(&tree);
+                let pages = pages_in_use();
+                let txn = pool.begin();
+                tree.tree
+                    .insert_in(&pool, &txn, &key(i).encode(), ptr(i))
+                    .unwrap();
+                if pages_in_use() == pages {
+                    txn.commit().unwrap();
+                    i += 1;
+                    continue;
+                }
+                // the insert split a leaf. Its records reach the log but the process dies before it commits
+                rw_acquire_shared(ctx.get_wal()).flush_all().unwrap();
+                std::mem::forget(txn);
+                break (tree.get_meta_page_id(), i, leaves + 1);
+            }
+        };
+
+        let ctx = DbContext::open(&path, Options::default()).unwrap();
+        let stats = ctx.get_recovery_stats();
+        // only the insert is undone: the split committed on its own
+        assert_eq!((stats.losers, stats.undone), (1, 1));
+        let tree = BLinkTree::open(ctx.get_bufmgr().clone(), meta_page_id);
+        assert_eq!(leaf_count(&tree), leaves);
+        for i in 0..split_key {
+            assert_eq!(tree.get(&key(i)).unwrap(), Some(ptr(i)));
+        }
+        assert_eq!(tree.get(&key(split_key)).unwrap(), None);
+        for i in split_key..split_key + 500 {
+            tree.insert(&key(i), ptr(i)).unwrap();
+        }
+        for i in 0..split_key + 500 {
+            assert_eq!(tree.get(&key(i)).unwrap(), Some(ptr(i)));
+        }
+    }
+
+    /// Number of leaves, counted along the right links from the leftmost one
+    fn leaf_count<K: KeyCodec>(tree: &BLinkTree<K>) -> usize {
+        let pool = rw_acquire_shared(&tree.tree.bufmgr);
+        let mut page_id = tree.tree.read_meta(&pool).unwrap().root;
+        let mut node = tree.tree.read_node(&pool, page_id).unwrap();
+        while !node.is_leaf() {
+            page_id = node.children[0];
+            node = tree.tree.read_node(&pool, page_id).unwrap();
+        }
+        let mut leaves = 1;
+        while node.right_link != INVALID_PAGE_ID {
+            node = tree.tree.read_node(&pool, node.right_link).unwrap();
+            leaves += 1;
+        }
+        leaves
+    }
+
+    #[test]
+    fn bulk_load() {
+        let mut leaves = Vec::new();
+        for fill_factor in [70, 100] {
+            let bufmgr = make_pool(&format!("bulk_{}.bin", fill_factor), 16);
+            let entries = (0..4000).step_by(2).map(|i| (key(i), ptr(i)));
+            let tree: BLinkTree = BLinkTree::bulk_load(bufmgr, entries, fill_factor).unwrap();
+            assert!(height(&tree) > 2);
+            leaves.push(leaf_count(&tree));
+            for i in 0..4000 {
+                let expected = (i % 2 == 0).then(|| ptr(i));
+                assert_eq!(tree.get(&key(i)).unwrap(), expected);
+            }
+            let scanned: Vec<Vec<u8>> = tree
+                .range(key(1000)..key(2000))
+                .map(|entry| entry.unwrap().0)
+                .collect();
+            assert_eq!(
+                scanned,
+                (1000..2000).step_by(2).map(key).collect::<Vec<_>>()
+            );
+            assert_eq!(tree.range(..).rev().count(), 2000);
+
+            // the loaded tree takes ordinary inserts and deletes, splitting and merging its packed nodes
+            for i in (1..4000).step_by(2) {
+                tree.insert(&key(i), ptr(i)).unwrap();
+            }
+            for i in (0..4000).step_by(3) {
+                assert!(tree.delete(&key(i)).unwrap());
+            }
+            for i in 0..4000 {
+                let expected = (i % 3 != 0).then(|| ptr(i));
+                assert_eq!(tree.get(&key(i)).unwrap(), expected);
+            }
+            assert_eq!(tree.range(..).count(), 2666);
+        }
+        // 2000 keys of about 120 bytes fill some 90 leaves of 4KB, more of them when they are packed less tightly
+        assert!(leaves[0] > leaves[1], "{:?}", leaves);
+        assert!(leaves[1] <= 70, "{:?}", leaves);
+
+        let tree: BLinkTree =
+            BLinkTree::bulk_load(make_pool("bulk_empty.bin", 4), Vec::new(), 100).unwrap();
+        assert_eq!(height(&tree), 1);
+        assert_eq!(tree.range(..).count(), 0);
+        tree.insert(&key(1), ptr(1)).unwrap();
+        assert_eq!(tree.get(&key(1)).unwrap(), Some(ptr(1)));
+    }
+
+    #[test]
+    fn bulk_load_refuses_unsorted_input() {
+        let bufmgr = make_pool("bulk_unsorted.bin", 16);
+        let page_count =
+            || rw_acquire_shared(rw_acquire_shared(&bufmgr).get_diskmgr()).get_page_count();
+        let mut ids: Vec<usize> = (0..2000).collect();
+        ids.swap(1500, 1501);
+        let unsorted = ids.iter().map(|&i| (key(i), ptr(i)));
+        assert!(matches!(
+            BLinkTree::bulk_load(bufmgr.clone(), unsorted, 100),
+            Err(IndexError::Unsorted(1501))
+        ));
+        let duplicate = (0..2000).map(|i| (key(i.min(1000)), ptr(i)));
+        assert!(matches!(
+            BLinkTree::bulk_load(bufmgr.clone(), duplicate, 100),
+            Err(IndexError::DuplicateKey)
+        ));
+        let too_large = [
+            (vec![0u8; 4], ptr(0)),
+            (vec![1u8; MAX_KEY_SIZE + 1], ptr(1)),
+        ];
+        assert!(matches!(
+            BLinkTreeInternal::bulk_load(bufmgr.clone(), too_large, 100),
+            Err(IndexError::KeyTooLarge(_))
+        ));
+        for fill_factor in [0, 101] {
+            assert!(matches!(
+                BLinkTree::bulk_load(bufmgr.clone(), (0..10).map(|i| (key(i), ptr(i))), fill_factor),
+                Err(IndexError::FillFactor(f)) if f == fill_factor
+            ));
+        }
+
+        // the pages written before the input was refused were freed, and are reused
+        let before = page_count();
+        let tree: BLinkTree =
+            BLinkTree::bulk_load(bufmgr.clone(), (0..1000).map(|i| (key(i), ptr(i))), 100).unwrap();
+        assert_eq!(page_count(), before);
+        assert_eq!(tree.range(..).count(), 1000);
+    }
+
+    #[test]
+    fn bulk_loaded_index_survives_crash() {
+        let path = db_path("bulk_crash.bin");
+        {
+            let ctx = DbContext::open(&path, Options::default()).unwrap();
+            let options = IndexOptions { fill_factor: 80 };
+            let entries = (-3000..3000).map(|i| (i as i64, ObjectPtr::new(i as PageId, 0)));
+            let tree = ctx
+                .bulk_load_index("plays", ValueType::ObjectPtr, options, entries)
+                .unwrap();
+            tree.insert(&5000, ObjectPtr::new(5000, 0)).unwrap();
+            // the process dies without writing anything back
+        }
+
+        let ctx = DbContext::open(&path, Options::default()).unwrap();
+        let tree = ctx.open_index::<i64>("plays").unwrap();
+        let keys: Vec<i64> = tree.range(..).map(|entry| entry.unwrap().0).collect();
+        assert_eq!(keys, (-3000..3000).chain([5000]).collect::<Vec<_>>());
+        assert_eq!(tree.get(&-42).unwrap(), Some(ObjectPtr::new(-42, 0)));
+        assert_eq!(ctx.list_indexes().unwrap()[0].options.fill_factor, 80);
+    }
+
+    #[test]
+    fn bulk_load_reuses_pages_freed_meanwhile() {
+        let path = db_path("bulk_reuse.bin");
+        let meta_page_id = {
+            let ctx = DbContext::open(&path, Options::default()).unwrap();
+            let bufmgr = ctx.get_bufmgr().clone();
+            // once the load has started, pages are changed, committed and freed again, so that the load reuses them
+            let entries = (0..3000).map(move |i| {
+                if i == 0 {
+                    let pool = rw_acquire_shared(&bufmgr);
+                    let txn = pool.begin();
+                    let page_ids: Vec<PageId> = (0..8)
+                        .map(|_| {
+                            let mut page = txn.new_page_write().unwrap();
+                            page[..PAGE_SIZE - PAGE_LSN_SIZE].fill(0xaa);
+                            page.get_page_id()
+                        })
+                        .collect();
+                    txn.commit().unwrap();
+                    for page_id in page_ids {
+                        assert!(pool.delete_page(page_id).unwrap());
+                    }
+                }
+                (key(i), ptr(i))
+            });
+            let tree: BLinkTree =
+                BLinkTree::bulk_load(ctx.get_bufmgr().clone(), entries, 100).unwrap();
+            tree.get_meta_page_id()
+            // the process dies without writing anything back
+        };
+
+        let ctx = DbContext::open(&path, Options::default()).unwrap();
+        let tree: BLinkTree = BLinkTree::open(ctx.get_bufmgr().clone(), meta_page_id);
+        for i in 0..3000 {
+            assert_eq!(tree.get(&key(i)).unwrap(), Some(ptr(i)));
+        }
+        assert_eq!(tree.range(..).count(), 3000);
+    }
+
+    #[test]
+    fn optimistic_reads_during_splits() {
+        let bufmgr = Arc::new(parking_lot::RwLock::new(
+            BufferPoolInternal::new(64, 2, make_diskmgr("optimistic.bin"))
+                .with_optimistic_reads(true),
+        ));
+        let entries = (0..3000).step_by(2).map(|i| (key(i), ptr(i)));
+        let tree: BLinkTree = BLinkTree::bulk_load(bufmgr.clone(), entries, 100).unwrap();
+        let writing = std::sync::atomic::AtomicUsize::new(4);
+        std::thread::scope(|scope| {
+            for t in 0..4 {
+                let (tree, writing) = (&tree, &writing);
+                scope.spawn(move || {
+                    // every insert lands in a full leaf or one that was just split
+                    for i in (2 * t + 1..3000).step_by(8) {
+                        tree.insert(&key(i), ptr(i)).unwrap();
+                    }
+                    writing.fetch_sub(1, std::sync::atomic::Ordering::Release);
+                });
+            }
+            for t in 0..4 {
+                let (tree, writing) = (&tree, &writing);
+                scope.spawn(move || {
+                    while writing.load(std::sync::atomic::Ordering::Acquire) > 0 {
+                        for i in (2 * t..3000).step_by(8) {
+                            assert_eq!(tree.get(&key(i)).unwrap(), Some(ptr(i)));
+                        }
+                    }
+                });
+            }
+        });
+        for i in 0..3000 {
+            assert_eq!(tree.get(&key(i)).unwrap(), Some(ptr(i)));
+        }
+    }
+}
@@ -1,21 +1,29 @@
 #![allow(dead_code, unused_imports)]
 
-use parking_lot::RawRwLock as _;
-use std::borrow::BorrowMut;
-use std::collections::{HashMap, LinkedList};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::concurrency::{
-    rw_acquire_upgradable, rw_release_excl, rw_release_upgradable, rw_upgrade_shared,
-    RwSynchronized, Synchronized,
+    acquire, rw_acquire_excl, rw_acquire_shared, rw_acquire_upgradable, rw_upgrade, RwSynchronized,
+    Synchronized,
 };
-use crate::shared::{FrameId, PageId, PAGE_SIZE};
+use crate::shared::{
+    FrameId, Lsn, PageId, INVALID_LSN, INVALID_PAGE_ID, INVALID_TXN_ID, PAGE_SIZE,
+};
+use crate::storage::bgwriter::{BgWriter, BgWriterPolicy};
 use crate::storage::diskmgr::DiskMgr;
 use crate::storage::free_list::FreeList;
-use crate::storage::page::Page;
+use crate::storage::page::{get_page_lsn, Page, PageReadLatch, PageWriteLatch};
+use crate::storage::page_guard::{ReadPageGuard, WritePageGuard};
 use crate::storage::page_table::PageTable;
-
-use super::diskmgr::{self, DiskMgrInternal};
+use crate::storage::replacer::lrukreplacer::LruKReplacer;
+use crate::storage::replacer::lrureplacer::LruReplacer;
+use crate::storage::replacer::Replacer;
+use crate::storage::txn::Transaction;
+use crate::storage::wal::{LogBody, LogRecord, Wal};
 
 pub struct BufferPoolFrameInternal {
     frame_id: FrameId,
@@ -29,65 +37,704 @@ impl BufferPoolFrameInternal {
             page: Page::default(),
         }
     }
+
+    #[inline]
+    pub fn get_frame_id(&self) -> FrameId {
+        self.frame_id
+    }
+
+    #[inline]
+    pub fn get_page(&self) -> &Page {
+        &self.page
+    }
 }
 
-pub type BufferPoolFrame = Synchronized<BufferPoolFrameInternal>;
-pub type BufferPoolFrames = RwSynchronized<Vec<BufferPoolFrame>>;
+/// Optimistic reads of a page restarted in a row before the reader falls back to its shared latch
+const OPTIMISTIC_RETRIES: usize = 64;
+
+/// Frames are shared rather than locked as a whole. The page inside a frame carries its own latch
+pub type BufferPoolFrame = Arc<BufferPoolFrameInternal>;
+/// The pool never adds or removes frames, so they are looked up without a latch
+pub type BufferPoolFrames = Box<[BufferPoolFrame]>;
 
 pub struct BufferPoolInternal {
     pool_size: usize,
     replacer_k: usize,
     diskmgr: DiskMgr,
     page_table: PageTable,
-    free_list: FreeList<FrameId>,
+    free_list: FreeList,
+    replacer: Box<dyn Replacer>,
     frames: BufferPoolFrames,
+    wal: Option<Wal>,
+    /// Copy pages out for readers without latching them. See `read_page`
+    optimistic_reads: bool,
+    counters: BufferPoolCounters,
+    /// The background writer, if one was started
+    bgwriter: Synchronized<Option<BgWriter>>,
+}
+
+/// The counters behind `BufferPoolStats`
+#[derive(Default)]
+struct BufferPoolCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    dirty_write_backs: AtomicU64,
+    background_write_backs: AtomicU64,
+    optimistic_restarts: AtomicU64,
+    latch_waits: AtomicU64,
+    latch_wait_nanos: AtomicU64,
+}
+
+/// What the buffer pool did since it was created or its counters were last reset
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    /// Fetches that found the page resident
+    pub hits: u64,
+    /// Fetches that read the page from disk
+    pub misses: u64,
+    /// Pages removed from their frame to make room for another
+    pub evictions: u64,
+    /// Dirty pages written to disk, on eviction, by a flush or by the background writer
+    pub dirty_write_backs: u64,
+    /// The share of `dirty_write_backs` done by the background writer
+    pub background_write_backs: u64,
+    /// Optimistic reads restarted because a writer latched the page during the copy
+    pub optimistic_restarts: u64,
+    /// Page latches taken through guards that were not free, and the time spent waiting for them
+    pub latch_waits: u64,
+    pub latch_wait_time: Duration,
+    /// Frames pinned when the snapshot was taken. A gauge rather than a counter, so resetting leaves it alone
+    pub pinned_frames: usize,
+}
+
+impl BufferPoolStats {
+    /// The share of fetches that found the page resident, or None before the first fetch
+    pub fn hit_ratio(&self) -> Option<f64> {
+        let fetches = self.hits + self.misses;
+        (fetches > 0).then(|| self.hits as f64 / fetches as f64)
+    }
+}
+
+/// The state of one frame, as listed by `frame_dump`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FrameInfo {
+    pub frame_id: FrameId,
+    /// INVALID_PAGE_ID if the frame is free
+    pub page_id: PageId,
+    pub pin_count: usize,
+    pub dirty: bool,
+    /// Whether the replacer may choose the frame as its next victim
+    pub evictable: bool,
 }
 
 impl BufferPoolInternal {
     pub fn new(pool_size: usize, replacer_k: usize, diskmgr: DiskMgr) -> Self {
-        let mut free_list_internal: LinkedList<FrameId> = LinkedList::new();
-        let mut frames_internal = Vec::new();
-        for i in 0..pool_size {
-            free_list_internal.push_back(i as isize);
-            frames_internal.push(Arc::new(parking_lot::Mutex::new(
-                BufferPoolFrameInternal::new(i as isize),
-            )));
-        }
+        Self::build(pool_size, replacer_k, diskmgr, None)
+    }
+
+    /// A buffer pool whose page changes are logged to `wal`. A dirty page is only written back once the log is durable
+    /// up to the page's LSN
+    pub fn with_wal(pool_size: usize, replacer_k: usize, diskmgr: DiskMgr, wal: Wal) -> Self {
+        Self::build(pool_size, replacer_k, diskmgr, Some(wal))
+    }
+
+    fn build(pool_size: usize, replacer_k: usize, diskmgr: DiskMgr, wal: Option<Wal>) -> Self {
+        let frames_internal = (0..pool_size)
+            .map(|i| Arc::new(BufferPoolFrameInternal::new(i as isize)))
+            .collect();
         Self {
             pool_size,
             replacer_k,
             diskmgr,
-            page_table: Arc::new(parking_lot::RwLock::new(HashMap::new())),
-            free_list: Arc::new(parking_lot::RwLock::new(free_list_internal)),
-            frames: Arc::new(parking_lot::RwLock::new(frames_internal)),
+            page_table: PageTable::default(),
+            free_list: FreeList::full(pool_size),
+            replacer: Self::make_replacer(pool_size, replacer_k),
+            frames: frames_internal,
+            wal,
+            optimistic_reads: false,
+            counters: BufferPoolCounters::default(),
+            bgwriter: Arc::default(),
         }
     }
 
-    pub fn fetch_page(&self, page_id: PageId) -> std::io::Result<FrameId> {
-        unsafe { rw_acquire_upgradable(&self.page_table) };
-        let page_table = unsafe { &mut *self.page_table.data_ptr() };
-        if let Some(frame_id) = page_table.get(&page_id) {
-            unsafe { rw_release_upgradable(&self.page_table) };
-            return Ok(*frame_id);
+    /// Let `read_page` copy pages without latching them, validating each copy against the page version instead. Readers
+    /// then never write to a shared latch, which is what limits read-mostly workloads on many cores
+    pub fn with_optimistic_reads(mut self, enabled: bool) -> Self {
+        self.optimistic_reads = enabled;
+        self
+    }
+
+    /// Split the page table into `shards` shards instead of `DEFAULT_SHARDS`. Must be called before any page is fetched
+    pub fn with_page_table_shards(mut self, shards: usize) -> Self {
+        self.page_table = PageTable::new(shards);
+        self
+    }
+
+    #[inline]
+    pub fn has_optimistic_reads(&self) -> bool {
+        self.optimistic_reads
+    }
+
+    /// Start a background writer for the pool behind `bufmgr`, replacing the one already running, if any
+    pub fn start_bgwriter(bufmgr: &BufferPool, policy: BgWriterPolicy) {
+        let writer = BgWriter::start(Arc::downgrade(bufmgr), policy);
+        let previous = acquire(&rw_acquire_shared(bufmgr).bgwriter).replace(writer);
+        drop(previous);
+    }
+
+    /// Stop the background writer, if one is running, and wait for its current round to finish
+    pub fn stop_bgwriter(&self) {
+        let writer = acquire(&self.bgwriter).take();
+        if let Some(writer) = writer {
+            writer.stop();
         }
-        unsafe { rw_upgrade_shared(&self.page_table) };
-        let diskmgr = self.diskmgr.read();
+    }
+
+    /// Write back up to `max_pages` dirty pages among the next `lookahead` frames the replacer would evict, so that the
+    /// eviction finds them clean. Pinned pages are skipped, and so are pages a writer holds latched. Every page goes
+    /// through `write_back` and so obeys the WAL rule. Returns the number of pages written
+    pub fn write_ahead_of_eviction(
+        &self,
+        lookahead: usize,
+        max_pages: usize,
+    ) -> BufferPoolResult<usize> {
+        let mut written = 0;
+        for frame_id in self.replacer.candidates(lookahead) {
+            if written == max_pages {
+                break;
+            }
+            let frame = self.frame(frame_id);
+            let page_id = frame.page.get_id();
+            // latching the shard keeps the page from being evicted or deleted while it is written
+            let page_table = rw_acquire_shared(self.page_table.shard(page_id));
+            if page_table.get(&page_id) != Some(&frame_id)
+                || frame.page.get_pin_count() > 0
+                || !frame.page.is_dirty()
+            {
+                continue;
+            }
+            // a writer that pins the page after the check waits for this latch, and dirties the page again afterwards
+            let Some(data) = frame.page.try_r_latch() else {
+                continue;
+            };
+            self.write_back(page_id, &data)?;
+            frame.page.set_dirty(false);
+            self.counters
+                .dirty_write_backs
+                .fetch_add(1, Ordering::Relaxed);
+            self.counters
+                .background_write_backs
+                .fetch_add(1, Ordering::Relaxed);
+            written += 1;
+        }
+        Ok(written)
+    }
+
+    /// A snapshot of the counters. They are read one at a time while other threads keep counting, so the snapshot is
+    /// only consistent if the pool is idle
+    pub fn stats(&self) -> BufferPoolStats {
+        let counters = &self.counters;
+        BufferPoolStats {
+            hits: counters.hits.load(Ordering::Relaxed),
+            misses: counters.misses.load(Ordering::Relaxed),
+            evictions: counters.evictions.load(Ordering::Relaxed),
+            dirty_write_backs: counters.dirty_write_backs.load(Ordering::Relaxed),
+            background_write_backs: counters.background_write_backs.load(Ordering::Relaxed),
+            optimistic_restarts: counters.optimistic_restarts.load(Ordering::Relaxed),
+            latch_waits: counters.latch_waits.load(Ordering::Relaxed),
+            latch_wait_time: Duration::from_nanos(
+                counters.latch_wait_nanos.load(Ordering::Relaxed),
+            ),
+            pinned_frames: self
+                .frames
+                .iter()
+                .filter(|frame| frame.page.get_pin_count() > 0)
+                .count(),
+        }
+    }
+
+    /// Start counting from zero again. The disk manager keeps its own counters
+    pub fn reset_stats(&self) {
+        let counters = &self.counters;
+        for counter in [
+            &counters.hits,
+            &counters.misses,
+            &counters.evictions,
+            &counters.dirty_write_backs,
+            &counters.background_write_backs,
+            &counters.optimistic_restarts,
+            &counters.latch_waits,
+            &counters.latch_wait_nanos,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// The state of every frame, in frame order. Like `dirty_page_table`, this latches nothing and is only a snapshot
+    pub fn frame_dump(&self) -> Vec<FrameInfo> {
+        self.frames
+            .iter()
+            .map(|frame| FrameInfo {
+                frame_id: frame.frame_id,
+                page_id: frame.page.get_id(),
+                pin_count: frame.page.get_pin_count(),
+                dirty: frame.page.is_dirty(),
+                evictable: self.replacer.is_evictable(frame.frame_id),
+            })
+            .collect()
+    }
+
+    /// Latch a page in shared mode on behalf of a guard, timing the wait if the latch is not free
+    pub(crate) fn latch_shared(&self, page: &Page) -> PageReadLatch {
+        if let Some(latch) = page.try_r_latch() {
+            return latch;
+        }
+        let start = Instant::now();
+        let latch = page.r_latch();
+        self.count_latch_wait(start.elapsed());
+        latch
+    }
+
+    /// Latch a page in exclusive mode on behalf of a guard, timing the wait if the latch is not free
+    pub(crate) fn latch_exclusive(&self, page: &Page) -> PageWriteLatch {
+        if let Some(latch) = page.try_w_latch() {
+            return latch;
+        }
+        let start = Instant::now();
+        let latch = page.w_latch();
+        self.count_latch_wait(start.elapsed());
+        latch
+    }
+
+    fn count_latch_wait(&self, waited: Duration) {
+        self.counters.latch_waits.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .latch_wait_nanos
+            .fetch_add(waited.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn get_diskmgr(&self) -> &DiskMgr {
+        &self.diskmgr
+    }
+
+    #[inline]
+    pub fn get_wal(&self) -> Option<&Wal> {
+        self.wal.as_ref()
+    }
+
+    /// Start a transaction. Pages changed through it are logged under its id and stay latched until it ends
+    pub fn begin(&self) -> Transaction<'_> {
+        Transaction::new(self, false)
+    }
+
+    /// Start a system transaction, which commits without waiting for the log. See `Transaction`
+    pub fn begin_system(&self) -> Transaction<'_> {
+        Transaction::new(self, true)
+    }
+
+    /// LRU-1 is plain LRU, so the cheaper LruReplacer is used unless the pool asks for K > 1
+    fn make_replacer(pool_size: usize, replacer_k: usize) -> Box<dyn Replacer> {
+        if replacer_k > 1 {
+            Box::new(LruKReplacer::new(pool_size, replacer_k))
+        } else {
+            Box::new(LruReplacer::new(pool_size))
+        }
+    }
+
+    /// Return the frame holding `page_id`, reading the page from disk if it is not resident. The page is pinned once per
+    /// call and stays in its frame until every pin is released
+    pub fn fetch_page(&self, page_id: PageId) -> BufferPoolResult<FrameId> {
+        let shard = self.page_table.shard(page_id);
+        if let Some(&frame_id) = rw_acquire_shared(shard).get(&page_id) {
+            self.pin_frame(frame_id);
+            self.counters.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(frame_id);
+        }
+        // the frame is found before the shard is latched, since evicting a victim latches the victim's shard
+        let frame_id = self.acquire_frame()?;
+        let mut page_table = rw_acquire_excl(shard);
+        if let Some(&resident) = page_table.get(&page_id) {
+            // another thread read the page in while this one was looking for a frame
+            self.pin_frame(resident);
+            self.free_list.push(frame_id);
+            self.counters.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(resident);
+        }
+
         let mut page_buf = [0u8; PAGE_SIZE];
-        diskmgr.read_page(page_id, &mut page_buf)?;
-        let page = Page::new(page_id, &page_buf);
-        let mut free_list = self.free_list.write();
-        if free_list.len() > 0 {
-            let frame_res = free_list.pop_front();
-            assert!(!frame_res.is_none());
-            let frame_id = frame_res.unwrap();
-            page_table.insert(page_id, frame_id);
+        if let Err(e) = rw_acquire_shared(&self.diskmgr).read_page(page_id, &mut page_buf) {
+            self.free_list.push(frame_id);
+            return Err(e.into());
+        }
+        self.counters.misses.fetch_add(1, Ordering::Relaxed);
+        let frame = self.frame(frame_id);
+        frame.page.reset(page_id, &page_buf);
+        frame.page.pin();
+        self.replacer.pin(frame_id);
+        page_table.insert(page_id, frame_id);
+        Ok(frame_id)
+    }
+
+    /// Fetch a page and take its latch in shared mode. The pin and the latch are released when the guard is dropped
+    pub fn fetch_page_read(&self, page_id: PageId) -> BufferPoolResult<ReadPageGuard<'_>> {
+        let frame_id = self.fetch_page(page_id)?;
+        Ok(ReadPageGuard::new(self, self.frame(frame_id)))
+    }
+
+    /// Fetch a page and take its latch in exclusive mode. The page is marked dirty, unlatched and unpinned when the guard
+    /// is dropped
+    pub fn fetch_page_write(&self, page_id: PageId) -> BufferPoolResult<WritePageGuard<'_>> {
+        let frame_id = self.fetch_page(page_id)?;
+        Ok(WritePageGuard::new(self, self.frame(frame_id), None, false))
+    }
+
+    /// Return a copy of a page.
+    ///
+    /// With optimistic reads, a resident page is copied without pinning or latching it: its frame is looked up under a
+    /// shared latch on its shard, which is released before the copy, and the copy is taken between two reads of the page
+    /// version. The version also covers the page id, so a copy is thrown away if a writer latched the page, or the frame
+    /// was given to another page, in the meantime, and the lookup starts over. Neither the pin count nor the replacer is
+    /// touched. After `OPTIMISTIC_RETRIES` restarts in a row the reader gives up on a busy page, and a page that is not
+    /// resident is read in as usual. Otherwise the page is pinned and copied under a shared latch
+    pub fn read_page(&self, page_id: PageId) -> BufferPoolResult<[u8; PAGE_SIZE]> {
+        if self.optimistic_reads {
+            if let Some(data) = self.read_unpinned(page_id) {
+                self.counters.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(data);
+            }
+        }
+        let frame_id = self.fetch_page(page_id)?;
+        let data = self.frame(frame_id).get_page().get_data();
+        self.unpin_frame(frame_id, false);
+        Ok(data)
+    }
+
+    /// Copy a resident page without pinning it. Returns None if the page is not resident or stayed busy
+    fn read_unpinned(&self, page_id: PageId) -> Option<[u8; PAGE_SIZE]> {
+        for _ in 0..OPTIMISTIC_RETRIES {
+            let frame_id = self.page_table.get(page_id)?;
+            if let Some(data) = self.frames[frame_id as usize].page.read_optimistic(page_id) {
+                return Some(data);
+            }
+            self.counters
+                .optimistic_restarts
+                .fetch_add(1, Ordering::Relaxed);
+            std::hint::spin_loop();
+        }
+        None
+    }
+
+    /// Allocate a new page on disk and place it, zeroed and pinned, in a frame. Returns the new page id and its frame
+    pub fn new_page(&self) -> BufferPoolResult<(PageId, FrameId)> {
+        self.new_page_with(|| self.allocate_page_id())
+    }
+
+    /// Place a page that `allocate` takes from the disk manager, zeroed and pinned, in a frame. The frame is found first,
+    /// so that a full pool does not leave a page allocated
+    pub(crate) fn new_page_with(
+        &self,
+        allocate: impl FnOnce() -> std::io::Result<PageId>,
+    ) -> BufferPoolResult<(PageId, FrameId)> {
+        let frame_id = self.acquire_frame()?;
+        let page_id = match allocate() {
+            Ok(page_id) => page_id,
+            Err(e) => {
+                self.free_list.push(frame_id);
+                return Err(e.into());
+            }
+        };
+        let mut page_table = rw_acquire_excl(self.page_table.shard(page_id));
+        let frame = self.frame(frame_id);
+        frame.page.reset(page_id, &[0u8; PAGE_SIZE]);
+        frame.page.pin();
+        self.replacer.pin(frame_id);
+        page_table.insert(page_id, frame_id);
+        Ok((page_id, frame_id))
+    }
+
+    /// Allocate a new page and take its latch in exclusive mode
+    pub fn new_page_write(&self) -> BufferPoolResult<WritePageGuard<'_>> {
+        let (_, frame_id) = self.new_page()?;
+        Ok(WritePageGuard::new(self, self.frame(frame_id), None, true))
+    }
+
+    /// Release one pin on a resident page, marking it dirty if the caller modified it. A page is never marked clean here,
+    /// since another pin holder may have dirtied it. Returns false if the page is not resident or not pinned
+    pub fn unpin_page(&self, page_id: PageId, is_dirty: bool) -> bool {
+        let page_table = rw_acquire_shared(self.page_table.shard(page_id));
+        match page_table.get(&page_id) {
+            Some(&frame_id) => self.unpin_frame(frame_id, is_dirty),
+            None => false,
+        }
+    }
+
+    /// Write a resident page to disk regardless of its dirty flag and mark it clean. Returns false if the page is not
+    /// resident
+    pub fn flush_page(&self, page_id: PageId) -> BufferPoolResult<bool> {
+        match self.page_table.get(page_id) {
+            Some(frame_id) => self.flush_frame(frame_id, page_id),
+            None => Ok(false),
+        }
+    }
+
+    /// Write every resident page to disk
+    pub fn flush_all(&self) -> BufferPoolResult<()> {
+        for shard in self.page_table.shards() {
+            let resident: Vec<(PageId, FrameId)> = rw_acquire_shared(shard)
+                .iter()
+                .map(|(&page_id, &frame_id)| (page_id, frame_id))
+                .collect();
+            for (page_id, frame_id) in resident {
+                self.flush_frame(frame_id, page_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The resident pages with logged changes that may not have reached the disk, each with the LSN of the first such
+    /// change (its recLSN). The frames are read without latching their pages, so writers are never held up, and the
+    /// result is only a snapshot
+    pub fn dirty_page_table(&self) -> Vec<(PageId, Lsn)> {
+        self.frames
+            .iter()
+            .filter_map(|frame| {
+                let rec_lsn = frame.page.get_rec_lsn();
+                let page_id = frame.page.get_id();
+                (rec_lsn != INVALID_LSN && page_id != INVALID_PAGE_ID).then_some((page_id, rec_lsn))
+            })
+            .collect()
+    }
+
+    /// Remove a page from the pool and give its id back to the disk manager. Returns false if the page is pinned, in
+    /// which case nothing happens
+    pub fn delete_page(&self, page_id: PageId) -> BufferPoolResult<bool> {
+        // an id that is not in use must not reach the log, where recovery would have to replay it
+        rw_acquire_shared(&self.diskmgr).check_page_id(page_id)?;
+        if !self.remove_page(page_id) {
+            return Ok(false);
+        }
+        self.deallocate_page_id(page_id)?;
+        // flushing the free also flushes the changes that made the page unreachable, which come before it
+        if let Some(wal) = &self.wal {
+            rw_acquire_shared(wal).flush_all()?;
+        }
+        Ok(true)
+    }
+
+    /// Give back pages whose allocation was rolled back. A page that is still pinned, or that an earlier attempt already
+    /// freed, is left alone
+    pub(crate) fn discard_pages(&self, page_ids: &[PageId]) -> BufferPoolResult<()> {
+        if page_ids.is_empty() {
+            return Ok(());
+        }
+        for &page_id in page_ids {
+            let in_use = rw_acquire_shared(&self.diskmgr)
+                .check_page_id(page_id)
+                .is_ok();
+            if in_use && self.remove_page(page_id) {
+                self.deallocate_page_id(page_id)?;
+            }
+        }
+        if let Some(wal) = &self.wal {
+            rw_acquire_shared(wal).flush_all()?;
+        }
+        Ok(())
+    }
+
+    /// Drop a page from the pool without writing it back. Returns false if it is pinned
+    fn remove_page(&self, page_id: PageId) -> bool {
+        let mut page_table = rw_acquire_excl(self.page_table.shard(page_id));
+        if let Some(&frame_id) = page_table.get(&page_id) {
+            let frame = self.frame(frame_id);
+            if frame.page.get_pin_count() > 0 {
+                return false;
+            }
+            page_table.remove(&page_id);
+            self.replacer.remove(frame_id);
+            frame.page.reset(INVALID_PAGE_ID, &[0u8; PAGE_SIZE]);
+            self.free_list.push(frame_id);
+        }
+        true
+    }
+
+    /// Take an unused page id from the disk manager for use outside of any transaction. With a log, the allocation is
+    /// logged, and is never undone
+    fn allocate_page_id(&self) -> std::io::Result<PageId> {
+        let diskmgr = rw_acquire_shared(&self.diskmgr);
+        match &self.wal {
+            Some(wal) => diskmgr.allocate_page_logged(|page_id| {
+                rw_acquire_shared(wal).append(&LogRecord {
+                    txn_id: INVALID_TXN_ID,
+                    prev_lsn: INVALID_LSN,
+                    body: LogBody::AllocatePage { page_id },
+                });
+            }),
+            None => diskmgr.allocate_page(),
+        }
+    }
+
+    /// Give a page id that is no longer resident back to the disk manager, logging the free if there is a log
+    fn deallocate_page_id(&self, page_id: PageId) -> std::io::Result<()> {
+        let diskmgr = rw_acquire_shared(&self.diskmgr);
+        match &self.wal {
+            Some(wal) => diskmgr.deallocate_page_logged(page_id, || {
+                rw_acquire_shared(wal).append(&LogRecord {
+                    txn_id: INVALID_TXN_ID,
+                    prev_lsn: INVALID_LSN,
+                    body: LogBody::FreePage { page_id },
+                });
+            }),
+            None => diskmgr.deallocate_page(page_id),
+        }
+    }
+
+    /// Return a handle to the frame with the given id
+    pub fn frame(&self, frame_id: FrameId) -> BufferPoolFrame {
+        self.frames[frame_id as usize].clone()
+    }
+
+    /// Write back the page that `frame_id` held when it was looked up in the page table. The page latch can be held by a
+    /// transaction until it commits, and the transaction may need to latch the shard exclusively before then, so the
+    /// shard must not be latched while waiting for the page. Returns false if the frame was given to another page in the
+    /// meantime, which wrote this one back if it was dirty
+    fn flush_frame(&self, frame_id: FrameId, page_id: PageId) -> BufferPoolResult<bool> {
+        let frame = self.frame(frame_id);
+        let data = frame.page.r_latch();
+        // the frame cannot be reused while the latch is held, since resetting it takes the latch exclusively
+        if frame.page.get_id() != page_id {
+            return Ok(false);
+        }
+        self.write_back(page_id, &data)?;
+        if frame.page.is_dirty() {
+            self.counters
+                .dirty_write_backs
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        frame.page.set_dirty(false);
+        Ok(true)
+    }
+
+    /// Write a page to disk, first flushing the log up to the page's LSN so that no change reaches the disk before the
+    /// record describing it (the WAL rule)
+    fn write_back(&self, page_id: PageId, data: &[u8; PAGE_SIZE]) -> std::io::Result<()> {
+        if let Some(wal) = &self.wal {
+            let lsn = get_page_lsn(data);
+            if lsn != INVALID_LSN {
+                rw_acquire_shared(wal).flush(lsn)?;
+            }
+        }
+        rw_acquire_shared(&self.diskmgr).write_page(page_id, data)
+    }
+
+    /// Pin a resident frame. Must be called with the page's shard of the page table latched so the frame cannot be evicted
+    /// concurrently
+    fn pin_frame(&self, frame_id: FrameId) {
+        self.frame(frame_id).page.pin();
+        self.replacer.pin(frame_id);
+    }
+
+    /// Release one pin on a frame. The frame becomes evictable when its last pin is released. This does not need the page
+    /// table: an unpin racing with a pin can leave a pinned frame in the replacer, which `acquire_frame` checks for
+    pub(crate) fn unpin_frame(&self, frame_id: FrameId, is_dirty: bool) -> bool {
+        let frame = self.frame(frame_id);
+        if frame.page.get_pin_count() == 0 {
+            return false;
+        }
+        if is_dirty {
+            frame.page.set_dirty(true);
+        }
+        if frame.page.unpin() == 0 {
+            self.replacer.unpin(frame_id);
+        }
+        true
+    }
+
+    /// Find an empty frame, taking one from the free list if possible and evicting a victim otherwise. A dirty victim is
+    /// written back before its frame is reused. The victim's shard of the page table is latched exclusively while it is
+    /// evicted, so the page cannot be pinned or read back in from disk before its last version is written. Must not be
+    /// called with any shard latched, or two threads evicting from each other's shards could deadlock
+    fn acquire_frame(&self) -> BufferPoolResult<FrameId> {
+        if let Some(frame_id) = self.free_list.pop() {
             return Ok(frame_id);
-        } else {
-            // evict page using buffer pool policy
         }
-        // page_table.insert(page_id, frame_id);
-        unsafe { rw_release_excl(&self.page_table) };
-        Ok(0)
+        loop {
+            let frame_id = self
+                .replacer
+                .victim()
+                .ok_or(BufferPoolError::PoolExhausted)?;
+            let frame = self.frame(frame_id);
+            let page_id = frame.page.get_id();
+            let mut page_table = rw_acquire_excl(self.page_table.shard(page_id));
+            // the frame was pinned again after it became evictable, and its next unpin puts it back in the replacer. Or
+            // its page was deleted in the meantime, which put the frame on the free list
+            if frame.page.get_pin_count() > 0 || page_table.get(&page_id) != Some(&frame_id) {
+                continue;
+            }
+            if frame.page.is_dirty() {
+                let data = frame.page.r_latch();
+                if let Err(e) = self.write_back(page_id, &data) {
+                    // the page stays resident, so it must remain a candidate for eviction
+                    self.replacer.unpin(frame_id);
+                    return Err(e.into());
+                }
+                self.counters
+                    .dirty_write_backs
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+            page_table.remove(&page_id);
+            frame.page.reset(INVALID_PAGE_ID, &[0u8; PAGE_SIZE]);
+            return Ok(frame_id);
+        }
+    }
+}
+
+/// Errors returned by the buffer pool
+#[derive(Debug)]
+pub enum BufferPoolError {
+    /// Every frame is pinned, so there is nowhere to put another page
+    PoolExhausted,
+    /// The disk manager failed to read or write a page
+    Io(std::io::Error),
+}
+
+pub type BufferPoolResult<T> = Result<T, BufferPoolError>;
+
+impl Display for BufferPoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BufferPoolError::PoolExhausted => {
+                write!(f, "buffer pool exhausted: every frame is pinned")
+            }
+            BufferPoolError::Io(e) => write!(f, "buffer pool io error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BufferPoolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BufferPoolError::PoolExhausted => None,
+            BufferPoolError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<std::io::Error> for BufferPoolError {
+    fn from(e: std::io::Error) -> Self {
+        BufferPoolError::Io(e)
+    }
+}
+
+/// Lets callers that deal in `std::io::Result` propagate buffer pool errors with `?`
+impl From<BufferPoolError> for std::io::Error {
+    fn from(e: BufferPoolError) -> Self {
+        match e {
+            BufferPoolError::Io(e) => e,
+            e => std::io::Error::other(e),
+        }
     }
 }
 
@@ -95,16 +742,20 @@ pub type BufferPool = RwSynchronized<BufferPoolInternal>;
 
 #[cfg(test)]
 mod tests {
-    #![allow(unused_variables)]
+    #![allow(unused_variables, clippy::nonminimal_bool)]
     use lazy_static::lazy_static;
     use std::sync::Arc;
 
-    use crate::shared::{Song, PAGE_SIZE};
-    use crate::storage::diskmgr::{DiskMgr, DiskMgrInternal};
+    use crate::shared::{Song, INVALID_LSN, PAGE_SIZE};
+    use crate::storage::diskmgr::{DiskMgr, DiskMgrInternal, OpenMode};
     use crate::storage::ioutil;
-    use crate::storage::page::Page;
+    use crate::storage::page::{get_page_lsn, Page};
+    use crate::storage::page_guard::{ReadPageGuard, WritePageGuard};
+    use crate::storage::wal::{Wal, WalInternal};
 
-    use super::{BufferPool, BufferPoolFrameInternal, BufferPoolInternal};
+    use super::{BufferPool, BufferPoolError, BufferPoolFrameInternal, BufferPoolInternal};
+    use crate::shared::{FrameId, PageId};
+    use crate::storage::bgwriter::BgWriterPolicy;
 
     lazy_static! {
         static ref BUFMGR_TEST_FILE: String =
@@ -113,83 +764,603 @@ mod tests {
             Arc::new(parking_lot::RwLock::new(BufferPoolInternal::new(
                 10,
                 1,
-                Arc::new(parking_lot::RwLock::new(DiskMgrInternal::new(
+                Arc::new(parking_lot::RwLock::new(DiskMgrInternal::recreate(
                     &BUFMGR_TEST_FILE,
                 ))),
             )));
-        static ref STATE: Arc<(parking_lot::Mutex<bool>, parking_lot::Condvar)> =
-            Arc::new((parking_lot::Mutex::new(false), parking_lot::Condvar::new()));
     }
 
+    fn make_diskmgr(name: &str) -> DiskMgr {
+        Arc::new(parking_lot::RwLock::new(DiskMgrInternal::recreate(
+            &(crate::shared::cwd() + "/data/test/__bufmgr__/" + name),
+        )))
+    }
+
+    /// An empty log, with anything left behind by an earlier run removed
+    fn make_wal(name: &str) -> Wal {
+        let path = crate::shared::cwd() + "/data/test/__bufmgr__/" + name;
+        let _ = std::fs::remove_file(&path);
+        Arc::new(parking_lot::RwLock::new(
+            WalInternal::open(&path, OpenMode::CreateNew).unwrap(),
+        ))
+    }
+
+    /// Guards the songs written by `setup_full_bufmgr`. Tests that need them call the setup themselves because the test
+    /// harness may run them on a single thread in any order
+    static SETUP: std::sync::Once = std::sync::Once::new();
+
     #[test]
     fn create() {
         let buffer_pool = Arc::new(parking_lot::RwLock::new(BufferPoolInternal::new(
             10,
             1,
-            Arc::new(parking_lot::RwLock::new(DiskMgrInternal::new(
+            Arc::new(parking_lot::RwLock::new(DiskMgrInternal::recreate(
                 &BUFMGR_TEST_FILE,
             ))),
         )));
 
         let internal = (*buffer_pool).data_ptr();
-        let frames = unsafe { (*internal).frames.read() };
+        let frames = unsafe { &(*internal).frames };
         assert!(frames.len() == 10);
     }
 
-    #[test]
-    fn setup_full_bufmgr_test() {
-        let (mutex, condvar) = &**STATE;
+    fn setup_full_bufmgr() {
+        SETUP.call_once(write_songs);
+    }
+
+    /// Page ids of the songs written by `write_songs`, in order. Each song's id is the id of the page it is stored in
+    fn song_pages() -> std::ops::RangeInclusive<PageId> {
+        // the first page after the disk manager's header and bitmap pages
+        2..=5
+    }
+
+    fn write_songs() {
         let diskmgr = unsafe { &(*BUFMGR.data_ptr()).diskmgr };
         let diskmgr_handle = diskmgr.read();
-        let afraid = Song::new(1, "Afraid", "The Neighbourhood");
-        let reflections = Song::new(2, "Reflections", "The Neighbourhood");
-        let chlorine = Song::new(3, "Chlorine", "21 Pilots");
-        let nervous = Song::new(4, "Nervous", "The Neighbourhood");
+        let songs = [
+            ("Afraid", "The Neighbourhood"),
+            ("Reflections", "The Neighbourhood"),
+            ("Chlorine", "21 Pilots"),
+            ("Nervous", "The Neighbourhood"),
+        ];
 
-        let mut prepared = mutex.lock();
         assert!(!diskmgr_handle.clear().is_err());
 
-        assert!(!diskmgr_handle
-            .write_page(afraid.id as isize, &ioutil::to_buffer(afraid).unwrap())
-            .is_err());
-        assert!(!diskmgr_handle
-            .write_page(
-                reflections.id as isize,
-                &ioutil::to_buffer(reflections).unwrap()
-            )
-            .is_err());
-        assert!(!diskmgr_handle
-            .write_page(chlorine.id as isize, &ioutil::to_buffer(chlorine).unwrap())
-            .is_err());
-        assert!(!diskmgr_handle
-            .write_page(nervous.id as isize, &ioutil::to_buffer(nervous).unwrap())
-            .is_err());
+        for ((title, artist), expected_id) in songs.into_iter().zip(song_pages()) {
+            let page_id = diskmgr_handle.allocate_page().unwrap();
+            assert_eq!(page_id, expected_id);
+            let song = Song::new(page_id as i32, title, artist);
+            assert!(!diskmgr_handle
+                .write_page(page_id, &ioutil::to_buffer(song).unwrap())
+                .is_err());
+        }
+    }
 
-        *prepared = true;
-        condvar.notify_one();
+    #[test]
+    fn setup_full_bufmgr_test() {
+        setup_full_bufmgr();
     }
 
     #[test]
     fn load_page() {
-        let (mutex, condvar) = &**STATE;
-        let mut prepared = mutex.lock();
-        while !*prepared {
-            condvar.wait(&mut prepared);
-        }
-        assert!(*prepared);
+        setup_full_bufmgr();
 
         let bufmgr = BUFMGR.read();
         let diskmgr = &bufmgr.diskmgr;
 
         let diskmgr_handle = unsafe { &(*diskmgr.data_ptr()) };
         let mut page_buf = [0u8; PAGE_SIZE];
-        assert!(!diskmgr_handle.read_page(1, &mut page_buf).is_err());
+        assert!(!diskmgr_handle
+            .read_page(*song_pages().start(), &mut page_buf)
+            .is_err());
         let song = ioutil::from_buffer::<Song>(&page_buf);
         assert!(!song.is_none());
 
-        let frames_handle = bufmgr.frames.write();
+        assert_eq!(bufmgr.frames.len(), bufmgr.pool_size);
     }
 
     #[test]
-    fn full_bufmgr_test() {}
+    fn full_bufmgr_test() {
+        setup_full_bufmgr();
+        let bufmgr = BUFMGR.read();
+
+        for id in song_pages() {
+            let frame_id = bufmgr.fetch_page(id).unwrap();
+            let frame = bufmgr.frame(frame_id);
+            let song = ioutil::from_buffer::<Song>(&frame.page.get_data()).unwrap();
+            assert_eq!(song.id as isize, id);
+            // fetching a resident page returns the same frame and pins it again
+            assert_eq!(bufmgr.fetch_page(id).unwrap(), frame_id);
+            assert_eq!(frame.page.get_pin_count(), 2);
+            assert!(bufmgr.unpin_page(id, false));
+            assert!(bufmgr.unpin_page(id, false));
+            assert!(!bufmgr.unpin_page(id, false));
+        }
+    }
+
+    #[test]
+    fn evict_dirty_page() {
+        let diskmgr = make_diskmgr("evict.bin");
+        let bufmgr = BufferPoolInternal::new(2, 2, diskmgr.clone());
+        let ids: Vec<PageId> = (0..3)
+            .map(|_| {
+                let id = diskmgr.read().allocate_page().unwrap();
+                let song = Song::new(id as i32, "Sweater Weather", "The Neighbourhood");
+                diskmgr
+                    .read()
+                    .write_page(id, &ioutil::to_buffer(song).unwrap())
+                    .unwrap();
+                id
+            })
+            .collect();
+
+        let first = bufmgr.fetch_page(ids[0]).unwrap();
+        let second = bufmgr.fetch_page(ids[1]).unwrap();
+        assert!(matches!(
+            bufmgr.fetch_page(ids[2]),
+            Err(BufferPoolError::PoolExhausted)
+        ));
+
+        let modified = Song::new(ids[0] as i32, "Softcore", "The Neighbourhood");
+        bufmgr
+            .frame(first)
+            .page
+            .set_data(&ioutil::to_buffer(modified).unwrap());
+        assert!(bufmgr.unpin_page(ids[0], true));
+
+        // the first page is the only unpinned page, so it is evicted and written back
+        assert_eq!(bufmgr.fetch_page(ids[2]).unwrap(), first);
+        assert_eq!(bufmgr.page_table.get(ids[0]), None);
+        let mut page_buf = [0u8; PAGE_SIZE];
+        diskmgr.read().read_page(ids[0], &mut page_buf).unwrap();
+        let song = ioutil::from_buffer::<Song>(&page_buf).unwrap();
+        assert_eq!(song.title, modified.title);
+
+        assert!(bufmgr.unpin_page(ids[1], false));
+        let frame_id = bufmgr.fetch_page(ids[0]).unwrap();
+        assert_eq!(frame_id, second);
+        let song = ioutil::from_buffer::<Song>(&bufmgr.frame(frame_id).page.get_data()).unwrap();
+        assert_eq!(song.title, modified.title);
+    }
+
+    #[test]
+    fn new_flush_delete() {
+        let diskmgr = make_diskmgr("new_flush_delete.bin");
+        let bufmgr = BufferPoolInternal::new(3, 1, diskmgr.clone());
+
+        let mut page_ids: Vec<PageId> = Vec::new();
+        for i in 0..3 {
+            let (page_id, frame_id) = bufmgr.new_page().unwrap();
+            let song = Song::new(i, "Daddy Issues", "The Neighbourhood");
+            bufmgr
+                .frame(frame_id)
+                .page
+                .set_data(&ioutil::to_buffer(song).unwrap());
+            page_ids.push(page_id);
+        }
+        assert!(matches!(
+            bufmgr.new_page(),
+            Err(BufferPoolError::PoolExhausted)
+        ));
+
+        // pinned pages cannot be deleted
+        assert!(!bufmgr.delete_page(page_ids[0]).unwrap());
+
+        assert!(bufmgr.unpin_page(page_ids[0], true));
+        assert!(bufmgr.unpin_page(page_ids[1], true));
+        assert!(bufmgr.flush_page(page_ids[0]).unwrap());
+        let frame_id = bufmgr.page_table.get(page_ids[0]).unwrap();
+        assert!(!bufmgr.frame(frame_id).page.is_dirty());
+        let mut page_buf = [0u8; PAGE_SIZE];
+        diskmgr
+            .read()
+            .read_page(page_ids[0], &mut page_buf)
+            .unwrap();
+        assert_eq!(ioutil::from_buffer::<Song>(&page_buf).unwrap().id, 0);
+
+        // deleting frees the frame and the page id
+        assert!(bufmgr.delete_page(page_ids[1]).unwrap());
+        assert_eq!(bufmgr.page_table.get(page_ids[1]), None);
+        assert!(matches!(
+            bufmgr.delete_page(page_ids[1]),
+            Err(BufferPoolError::Io(e)) if e.kind() == std::io::ErrorKind::InvalidInput
+        ));
+        let (reused_page_id, _) = bufmgr.new_page().unwrap();
+        assert_eq!(reused_page_id, page_ids[1]);
+
+        assert!(bufmgr.unpin_page(page_ids[2], true));
+        bufmgr.flush_all().unwrap();
+        diskmgr
+            .read()
+            .read_page(page_ids[2], &mut page_buf)
+            .unwrap();
+        assert_eq!(ioutil::from_buffer::<Song>(&page_buf).unwrap().id, 2);
+        assert!(!bufmgr.flush_page(page_ids[1] + 100).unwrap());
+    }
+
+    #[test]
+    fn page_guards() {
+        let diskmgr = make_diskmgr("page_guards.bin");
+        let bufmgr = BufferPoolInternal::new(2, 1, diskmgr.clone());
+
+        let page_id = {
+            let mut guard = bufmgr.new_page_write().unwrap();
+            let song = Song::new(7, "Stargazing", "The Neighbourhood");
+            *guard = ioutil::to_buffer(song).unwrap();
+            guard.get_page_id()
+        };
+        // the write guard marked the page dirty and released its pin
+        let frame_id = bufmgr.page_table.get(page_id).unwrap();
+        let frame = bufmgr.frame(frame_id);
+        assert!(frame.page.is_dirty());
+        assert_eq!(frame.page.get_pin_count(), 0);
+
+        {
+            // any number of readers can share the latch
+            let first = bufmgr.fetch_page_read(page_id).unwrap();
+            let second = bufmgr.fetch_page_read(page_id).unwrap();
+            assert_eq!(frame.page.get_pin_count(), 2);
+            assert_eq!(ioutil::from_buffer::<Song>(&first).unwrap().id, 7);
+            assert_eq!(*first, *second);
+        }
+        assert_eq!(frame.page.get_pin_count(), 0);
+
+        // a writer waits for readers, and readers wait for the writer
+        let guard = bufmgr.fetch_page_read(page_id).unwrap();
+        std::thread::scope(|scope| {
+            let writer = scope.spawn(|| {
+                let mut guard = bufmgr.fetch_page_write(page_id).unwrap();
+                let song = Song::new(8, "Stargazing", "The Neighbourhood");
+                *guard = ioutil::to_buffer(song).unwrap();
+            });
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            assert_eq!(ioutil::from_buffer::<Song>(&guard).unwrap().id, 7);
+            drop(guard);
+            writer.join().unwrap();
+        });
+        let guard = bufmgr.fetch_page_read(page_id).unwrap();
+        assert_eq!(ioutil::from_buffer::<Song>(&guard).unwrap().id, 8);
+        drop(guard);
+
+        // evicting the page writes the last version to disk
+        bufmgr.new_page().unwrap();
+        bufmgr.new_page().unwrap();
+        let mut page_buf = [0u8; PAGE_SIZE];
+        diskmgr.read().read_page(page_id, &mut page_buf).unwrap();
+        assert_eq!(ioutil::from_buffer::<Song>(&page_buf).unwrap().id, 8);
+    }
+
+    /// A flush waiting for a page latch must not keep a miss on another page of the same shard from reading it in, since
+    /// the holder of the latch may be the one missing
+    #[test]
+    fn flush_waits_for_latch_without_shard() {
+        let diskmgr = make_diskmgr("flush_latch.bin");
+        let bufmgr = BufferPoolInternal::new(4, 1, diskmgr.clone());
+        let page_id = bufmgr.new_page_write().unwrap().get_page_id();
+        let neighbour = loop {
+            let id = diskmgr.read().allocate_page().unwrap();
+            if std::ptr::eq(
+                bufmgr.page_table.shard(id),
+                bufmgr.page_table.shard(page_id),
+            ) {
+                break id;
+            }
+        };
+
+        let mut guard = bufmgr.fetch_page_write(page_id).unwrap();
+        std::thread::scope(|scope| {
+            let flusher = scope.spawn(|| bufmgr.flush_page(page_id).unwrap());
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            // the latch holder misses on the neighbour while the flusher waits for the latch
+            let frame_id = bufmgr.fetch_page(neighbour).unwrap();
+            assert!(bufmgr.unpin_page(neighbour, false));
+            guard[PAGE_SIZE - 1] = 9;
+            drop(guard);
+            assert!(flusher.join().unwrap());
+        });
+        let mut page_buf = [0u8; PAGE_SIZE];
+        diskmgr.read().read_page(page_id, &mut page_buf).unwrap();
+        assert_eq!(page_buf[PAGE_SIZE - 1], 9);
+        bufmgr.flush_all().unwrap();
+    }
+
+    #[test]
+    fn wal_rule() {
+        let diskmgr = make_diskmgr("wal_rule.bin");
+        let wal = make_wal("wal_rule_wal.bin");
+        let bufmgr = BufferPoolInternal::with_wal(1, 1, diskmgr.clone(), wal.clone());
+
+        let page_id = {
+            let mut guard = bufmgr.new_page_write().unwrap();
+            guard[100] = 5;
+            guard.get_page_id()
+        };
+        let lsn = get_page_lsn(&bufmgr.fetch_page_read(page_id).unwrap());
+        assert_ne!(lsn, INVALID_LSN);
+        assert!(wal.read().get_flushed_lsn() <= lsn);
+
+        // evicting the page forces its log record out first
+        let (other, _) = bufmgr.new_page().unwrap();
+        assert!(wal.read().get_flushed_lsn() > lsn);
+        let mut page_buf = [0u8; PAGE_SIZE];
+        diskmgr.read().read_page(page_id, &mut page_buf).unwrap();
+        assert_eq!((get_page_lsn(&page_buf), page_buf[100]), (lsn, 5));
+
+        // latching a page exclusively without changing it neither logs nor dirties it
+        bufmgr.unpin_page(other, false);
+        let next_lsn = wal.read().get_next_lsn();
+        let frame_id = bufmgr.fetch_page_write(page_id).unwrap().get_frame_id();
+        let frame = bufmgr.frame(frame_id);
+        assert!(!frame.page.is_dirty());
+        assert_eq!(frame.page.get_rec_lsn(), INVALID_LSN);
+        assert_eq!(wal.read().get_next_lsn(), next_lsn);
+    }
+
+    #[test]
+    fn optimistic_reads() {
+        let diskmgr = make_diskmgr("optimistic.bin");
+        let bufmgr = BufferPoolInternal::new(2, 1, diskmgr).with_optimistic_reads(true);
+        let page_id = bufmgr.new_page_write().unwrap().get_page_id();
+        let frame = bufmgr.frame(bufmgr.page_table.get(page_id).unwrap());
+
+        // a reader never gets a copy while the page is latched exclusively, and the version moves on with every write
+        let version = frame.page.get_version();
+        let latch = frame.page.w_latch();
+        assert!(frame.page.read_optimistic(page_id).is_none());
+        drop(latch);
+        assert_eq!(frame.page.get_version(), version + 2);
+        assert!(frame.page.read_optimistic(page_id).is_some());
+        // nor a copy of the page the frame held before
+        assert!(frame.page.read_optimistic(page_id + 1).is_none());
+
+        // every write fills the whole page with one byte, so a torn copy would hold two different bytes
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for round in 1..=2000u32 {
+                    let mut guard = bufmgr.fetch_page_write(page_id).unwrap();
+                    guard.fill(round as u8);
+                }
+            });
+            for _ in 0..3 {
+                scope.spawn(|| {
+                    for _ in 0..2000 {
+                        let data = bufmgr.read_page(page_id).unwrap();
+                        assert!(data.iter().all(|&byte| byte == data[0]));
+                    }
+                });
+            }
+        });
+        assert_eq!(
+            bufmgr.read_page(page_id).unwrap(),
+            [2000u32 as u8; PAGE_SIZE]
+        );
+        assert_eq!(frame.page.get_pin_count(), 0);
+
+        // readers that do not pin keep getting the page they asked for while frames are handed from page to page;
+        // a miss pins one frame, so there are as many readers as frames
+        let page_ids: Vec<PageId> = (0..6)
+            .map(|i| {
+                let mut guard = bufmgr.new_page_write().unwrap();
+                guard.fill(i);
+                guard.get_page_id()
+            })
+            .collect();
+        std::thread::scope(|scope| {
+            for t in 0..2 {
+                let page_ids = &page_ids;
+                let bufmgr = &bufmgr;
+                scope.spawn(move || {
+                    for round in 0..3000 {
+                        let i = (round * 7 + t) % page_ids.len();
+                        let data = bufmgr.read_page(page_ids[i]).unwrap();
+                        assert!(data.iter().all(|&byte| byte == i as u8));
+                    }
+                });
+            }
+        });
+    }
+
+    /// Compares `read_page` on resident pages with optimistic reads off, where every read pins its frame in the replacer
+    /// and latches the page, and on, where it does neither. Run with
+    /// `cargo test --release optimistic_read_scaling -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn optimistic_read_scaling() {
+        use rand::Rng;
+        use std::time::{Duration, Instant};
+
+        const PAGES: usize = 256;
+        const RUN: Duration = Duration::from_millis(500);
+        let pools: Vec<_> = [false, true]
+            .into_iter()
+            .map(|optimistic| {
+                let diskmgr = make_diskmgr(&format!("read_scaling_{}.bin", optimistic));
+                let bufmgr =
+                    BufferPoolInternal::new(PAGES, 2, diskmgr).with_optimistic_reads(optimistic);
+                let page_ids: Vec<PageId> = (0..PAGES)
+                    .map(|_| bufmgr.new_page_write().unwrap().get_page_id())
+                    .collect();
+                (bufmgr, page_ids)
+            })
+            .collect();
+        let throughput =
+            |threads: usize, (bufmgr, page_ids): &(BufferPoolInternal, Vec<PageId>)| {
+                let start = Instant::now();
+                let reads: usize = std::thread::scope(|scope| {
+                    let handles: Vec<_> = (0..threads)
+                        .map(|_| {
+                            scope.spawn(|| {
+                                let mut rng = rand::thread_rng();
+                                let mut reads = 0;
+                                while start.elapsed() < RUN {
+                                    for _ in 0..256 {
+                                        let page_id = page_ids[rng.gen_range(0..PAGES)];
+                                        std::hint::black_box(bufmgr.read_page(page_id).unwrap());
+                                    }
+                                    reads += 256;
+                                }
+                                reads
+                            })
+                        })
+                        .collect();
+                    handles.into_iter().map(|h| h.join().unwrap()).sum()
+                });
+                reads as f64 / start.elapsed().as_secs_f64()
+            };
+        for threads in [1, 2, 4, 8, 16] {
+            let pinned = throughput(threads, &pools[0]);
+            let optimistic = throughput(threads, &pools[1]);
+            println!(
+                "{:>2} threads: pinned {:>12.0} reads/s, optimistic {:>12.0} reads/s ({:.1}x)",
+                threads,
+                pinned,
+                optimistic,
+                optimistic / pinned
+            );
+        }
+    }
+
+    #[test]
+    fn stats_and_frame_dump() {
+        let diskmgr = make_diskmgr("stats.bin");
+        let bufmgr = BufferPoolInternal::new(2, 2, diskmgr.clone());
+        diskmgr.read().reset_stats();
+        let page_ids: Vec<PageId> = (0..3)
+            .map(|i| {
+                let mut guard = bufmgr.new_page_write().unwrap();
+                guard[PAGE_SIZE - 1] = i;
+                guard.get_page_id()
+            })
+            .collect();
+        // the third page evicted the first, which was dirty
+        let stats = bufmgr.stats();
+        assert_eq!((stats.hits, stats.misses), (0, 0));
+        assert_eq!((stats.evictions, stats.dirty_write_backs), (1, 1));
+        assert_eq!(stats.pinned_frames, 0);
+        assert_eq!(stats.hit_ratio(), None);
+
+        let guard = bufmgr.fetch_page_read(page_ids[2]).unwrap();
+        assert_eq!(guard[PAGE_SIZE - 1], 2);
+        assert_eq!(
+            bufmgr.fetch_page_read(page_ids[0]).unwrap()[PAGE_SIZE - 1],
+            0
+        );
+        let stats = bufmgr.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (1, 1, 2));
+        assert_eq!(stats.pinned_frames, 1);
+        assert_eq!(stats.hit_ratio(), Some(0.5));
+
+        let dump = bufmgr.frame_dump();
+        assert_eq!(dump.len(), 2);
+        let pinned = dump.iter().find(|f| f.page_id == page_ids[2]).unwrap();
+        assert_eq!(
+            (pinned.pin_count, pinned.dirty, pinned.evictable),
+            (1, true, false)
+        );
+        let unpinned = dump.iter().find(|f| f.page_id == page_ids[0]).unwrap();
+        assert_eq!(
+            (unpinned.pin_count, unpinned.dirty, unpinned.evictable),
+            (0, false, true)
+        );
+
+        // a writer waits for the reader to let go of the latch
+        std::thread::scope(|scope| {
+            let writer = scope.spawn(|| {
+                bufmgr.fetch_page_write(page_ids[2]).unwrap()[0] = 1;
+            });
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            drop(guard);
+            writer.join().unwrap();
+        });
+        let stats = bufmgr.stats();
+        assert_eq!(stats.latch_waits, 1);
+        assert!(stats.latch_wait_time >= std::time::Duration::from_millis(10));
+
+        let disk = diskmgr.read().stats();
+        // two pages written back, and the one read in. Allocating the pages wrote the header and bitmap too
+        assert_eq!(disk.pages_read, 1);
+        assert!(disk.pages_written >= 2);
+        // growing the file synced the header
+        let fsyncs = disk.fsyncs;
+        assert!(fsyncs >= 1);
+        bufmgr.flush_all().unwrap();
+        diskmgr.read().sync().unwrap();
+        assert_eq!(diskmgr.read().stats().fsyncs, fsyncs + 1);
+
+        bufmgr.reset_stats();
+        diskmgr.read().reset_stats();
+        let stats = bufmgr.stats();
+        assert_eq!((stats.hits, stats.misses, stats.latch_waits), (0, 0, 0));
+        assert_eq!(stats.dirty_write_backs, 0);
+        assert_eq!(diskmgr.read().stats(), Default::default());
+    }
+
+    #[test]
+    fn background_writer() {
+        let diskmgr = make_diskmgr("bgwriter.bin");
+        let wal = make_wal("bgwriter_wal.bin");
+        let bufmgr: BufferPool = Arc::new(parking_lot::RwLock::new(BufferPoolInternal::with_wal(
+            4,
+            2,
+            diskmgr.clone(),
+            wal.clone(),
+        )));
+        let pool = bufmgr.read();
+        let page_ids: Vec<PageId> = (0..4)
+            .map(|i| {
+                let mut guard = pool.new_page_write().unwrap();
+                guard[100] = i + 1;
+                guard.get_page_id()
+            })
+            .collect();
+        let is_dirty = |page_id: PageId| {
+            pool.frame_dump()
+                .iter()
+                .any(|frame| frame.page_id == page_id && frame.dirty)
+        };
+        let on_disk = |page_id: PageId| {
+            let mut page_buf = [0u8; PAGE_SIZE];
+            diskmgr.read().read_page(page_id, &mut page_buf).unwrap();
+            page_buf[100]
+        };
+
+        // one round writes the first pages in eviction order, and skips a pinned one
+        let pinned = pool.fetch_page_read(page_ids[0]).unwrap();
+        assert_eq!(pool.write_ahead_of_eviction(4, 2).unwrap(), 2);
+        assert!(is_dirty(page_ids[0]));
+        for &page_id in &page_ids[1..3] {
+            assert!(!is_dirty(page_id));
+            assert_ne!(on_disk(page_id), 0);
+            // the WAL rule: the log reached the disk before the page did
+            let lsn = get_page_lsn(&pool.fetch_page_read(page_id).unwrap());
+            assert!(wal.read().get_flushed_lsn() > lsn);
+        }
+        assert!(is_dirty(page_ids[3]));
+        drop(pinned);
+
+        BufferPoolInternal::start_bgwriter(
+            &bufmgr,
+            BgWriterPolicy {
+                delay: std::time::Duration::from_millis(5),
+                lookahead: 4,
+                max_pages: 1,
+            },
+        );
+        let start = std::time::Instant::now();
+        while page_ids.iter().any(|&page_id| is_dirty(page_id)) {
+            assert!(start.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        pool.stop_bgwriter();
+        let stats = pool.stats();
+        assert_eq!(stats.background_write_backs, 4);
+        assert_eq!(stats.dirty_write_backs, 4);
+
+        // eviction finds the pages clean
+        pool.new_page().unwrap();
+        assert_eq!(pool.stats().dirty_write_backs, 4);
+        for (i, &page_id) in page_ids.iter().enumerate() {
+            assert_eq!(on_disk(page_id), i as u8 + 1);
+        }
+    }
 }
@@ -0,0 +1,531 @@
+// SOURCES + USEFUL LINKS
+// https://www.postgresql.org/docs/current/catalog-pg-class.html
+// https://www.sqlite.org/schematab.html
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::concurrency::{acquire, rw_acquire_shared, Synchronized};
+use crate::shared::{PageId, INVALID_PAGE_ID, PAGE_SIZE};
+use crate::storage::blink_tree::{BLinkTree, BLinkTreeInternal, IndexError, IndexResult};
+use crate::storage::bufmgr::{BufferPool, BufferPoolError, BufferPoolInternal};
+use crate::storage::ioutil;
+use crate::storage::key_codec::KeyCodec;
+use crate::storage::objptr::ObjectPtr;
+use crate::storage::page::PAGE_LSN_SIZE;
+use crate::storage::txn::Transaction;
+
+/// Bytes of a catalog page available to its entries and link
+const PAGE_BODY_SIZE: usize = PAGE_SIZE - PAGE_LSN_SIZE;
+
+/// Errors returned by catalog operations
+#[derive(Debug)]
+pub enum CatalogError {
+    /// An index with this name already exists
+    IndexExists(String),
+    /// There is no index with this name
+    NoSuchIndex(String),
+    /// The index cannot be dropped while handles to it are still open
+    IndexInUse(String),
+    /// The entry for this index does not fit in a catalog page
+    EntryTooLarge(String),
+    /// The index was opened with a key type other than the one it was created with
+    KeyTypeMismatch {
+        name: String,
+        expected: KeyType,
+        found: KeyType,
+    },
+    /// A page did not decode as a catalog page
+    Corrupt(PageId),
+    Index(IndexError),
+}
+
+pub type CatalogResult<T> = Result<T, CatalogError>;
+
+impl Display for CatalogError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CatalogError::IndexExists(name) => write!(f, "index {} already exists", name),
+            CatalogError::NoSuchIndex(name) => write!(f, "index {} does not exist", name),
+            CatalogError::IndexInUse(name) => write!(f, "index {} is still in use", name),
+            CatalogError::EntryTooLarge(name) => {
+                write!(
+                    f,
+                    "the catalog entry of index {} does not fit in a page",
+                    name
+                )
+            }
+            CatalogError::KeyTypeMismatch {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "index {} has keys of type {:?}, not {:?}",
+                name, expected, found
+            ),
+            CatalogError::Corrupt(page_id) => {
+                write!(f, "page {} is not a valid catalog page", page_id)
+            }
+            CatalogError::Index(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CatalogError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CatalogError::Index(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<IndexError> for CatalogError {
+    fn from(e: IndexError) -> Self {
+        CatalogError::Index(e)
+    }
+}
+
+impl From<BufferPoolError> for CatalogError {
+    fn from(e: BufferPoolError) -> Self {
+        CatalogError::Index(e.into())
+    }
+}
+
+impl From<std::io::Error> for CatalogError {
+    fn from(e: std::io::Error) -> Self {
+        CatalogError::Index(e.into())
+    }
+}
+
+impl From<CatalogError> for std::io::Error {
+    fn from(e: CatalogError) -> Self {
+        match e {
+            CatalogError::Index(e) => e.into(),
+            e => std::io::Error::other(e),
+        }
+    }
+}
+
+/// The type of the keys of an index
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum KeyType {
+    Int32,
+    Int64,
+    UInt32,
+    UInt64,
+    String,
+    Bytes,
+    /// A composite key, ordered by its first component, then its second, and so on
+    Tuple(Vec<KeyType>),
+}
+
+/// The type of the values of an index
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueType {
+    ObjectPtr,
+}
+
+/// Options an index was created with
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexOptions {
+    /// How full, in percent, nodes are packed when the index is built in bulk
+    pub fill_factor: u8,
+}
+
+impl Default for IndexOptions {
+    fn default() -> Self {
+        Self { fill_factor: 100 }
+    }
+}
+
+/// What the catalog knows about an index
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub name: String,
+    /// The page the index is reached through. It records the current root, and stays the same for the life of the index
+    pub meta_page_id: PageId,
+    pub key_type: KeyType,
+    pub value_type: ValueType,
+    pub options: IndexOptions,
+}
+
+/// One page of the catalog
+#[derive(Serialize, Deserialize)]
+struct CatalogPage {
+    next: PageId,
+    entries: Vec<IndexEntry>,
+}
+
+impl CatalogPage {
+    fn new() -> Self {
+        CatalogPage {
+            next: INVALID_PAGE_ID,
+            entries: Vec::new(),
+        }
+    }
+}
+
+/// The named indexes of a database, kept in a chain of pages whose first page is recorded in the file header.
+///
+/// An entry names the meta page of its index rather than the root. A root split swaps the root in the meta page, in the
+/// same transaction as the split, so the catalog always leads to the current root without ever changing itself.
+/// Creating an index writes its pages and its entry in one transaction as well.
+///
+/// Changes to the catalog are serialised. Opened indexes are shared, so every handle to an index goes through the same
+/// `BLinkTreeInternal`, which has to know about all running operations before it can reclaim pages
+pub struct Catalog {
+    bufmgr: BufferPool,
+    first_page_id: PageId,
+    /// The indexes handed out so far, by name
+    open: Synchronized<HashMap<String, Arc<BLinkTreeInternal>>>,
+}
+
+impl Catalog {
+    /// Create an empty catalog, consisting of a single page
+    pub fn create(bufmgr: BufferPool) -> CatalogResult<Self> {
+        let first_page_id = {
+            let pool = rw_acquire_shared(&bufmgr);
+            let txn = pool.begin();
+            let first_page_id = {
+                let mut guard = txn.new_page_write()?;
+                ioutil::to_page(&CatalogPage::new(), &mut guard).unwrap();
+                guard.get_page_id()
+            };
+            txn.commit()?;
+            first_page_id
+        };
+        Ok(Self::open(bufmgr, first_page_id))
+    }
+
+    /// Open an existing catalog through its first page
+    pub fn open(bufmgr: BufferPool, first_page_id: PageId) -> Self {
+        Self {
+            bufmgr,
+            first_page_id,
+            open: Arc::new(parking_lot::Mutex::new(HashMap::new())),
+        }
+    }
+
+    #[inline]
+    pub fn get_first_page_id(&self) -> PageId {
+        self.first_page_id
+    }
+
+    /// Create an empty index with keys of type `K` and return a handle to it
+    pub fn create_index<K: KeyCodec>(
+        &self,
+        name: &str,
+        value_type: ValueType,
+        options: IndexOptions,
+    ) -> CatalogResult<BLinkTree<K>> {
+        self.add_index::<K>(name, value_type, options, |_, txn| {
+            BLinkTreeInternal::create_in(txn)
+        })
+    }
+
+    /// Create an index with keys of type `K` from entries sorted by key, packing its nodes to the fill factor in
+    /// `options`, and return a handle to it
+    pub fn bulk_load_index<K: KeyCodec>(
+        &self,
+        name: &str,
+        value_type: ValueType,
+        options: IndexOptions,
+        entries: impl IntoIterator<Item = (K, ObjectPtr)>,
+    ) -> CatalogResult<BLinkTree<K>> {
+        let entries = entries
+            .into_iter()
+            .map(|(key, value)| (key.encode(), value));
+        self.add_index::<K>(name, value_type, options, |pool, txn| {
+            BLinkTreeInternal::bulk_load_in(pool, txn, entries, options.fill_factor)
+        })
+    }
+
+    /// Add an entry for an index whose pages `build` creates, in the same transaction as the entry
+    fn add_index<K: KeyCodec>(
+        &self,
+        name: &str,
+        value_type: ValueType,
+        options: IndexOptions,
+        build: impl for<'a> FnOnce(&BufferPoolInternal, &'a Transaction<'a>) -> IndexResult<PageId>,
+    ) -> CatalogResult<BLinkTree<K>> {
+        let mut open = acquire(&self.open);
+        let pool = rw_acquire_shared(&self.bufmgr);
+        if self.find(&pool, name)?.is_some() {
+            return Err(CatalogError::IndexExists(name.into()));
+        }
+        let mut entry = IndexEntry {
+            name: name.into(),
+            meta_page_id: INVALID_PAGE_ID,
+            key_type: K::key_type(),
+            value_type,
+            options,
+        };
+        let mut page = CatalogPage::new();
+        page.entries.push(entry.clone());
+        if ioutil::encode(&page).is_none_or(|encoded| encoded.len() > PAGE_BODY_SIZE) {
+            return Err(CatalogError::EntryTooLarge(name.into()));
+        }
+
+        let txn = pool.begin();
+        entry.meta_page_id = build(&pool, &txn)?;
+        let mut page_id = self.first_page_id;
+        loop {
+            let mut guard = txn.fetch_page_write(page_id)?;
+            let mut page = Self::decode(page_id, &guard)?;
+            page.entries.push(entry.clone());
+            if ioutil::to_page(&page, &mut guard).is_some() {
+                break;
+            }
+            if page.next == INVALID_PAGE_ID {
+                // the new page is written before the page that links to it
+                let mut next_guard = txn.new_page_write()?;
+                let mut next = CatalogPage::new();
+                next.entries.push(entry.clone());
+                ioutil::to_page(&next, &mut next_guard).unwrap();
+                page.entries.pop();
+                page.next = next_guard.get_page_id();
+                ioutil::to_page(&page, &mut guard).unwrap();
+                break;
+            }
+            page_id = page.next;
+        }
+        txn.commit()?;
+
+        let tree = Arc::new(BLinkTreeInternal::open(
+            self.bufmgr.clone(),
+            entry.meta_page_id,
+        ));
+        open.insert(entry.name, tree.clone());
+        Ok(BLinkTree::from_internal(tree))
+    }
+
+    /// Return a handle to an existing index, whose keys have to be of type `K`
+    pub fn open_index<K: KeyCodec>(&self, name: &str) -> CatalogResult<BLinkTree<K>> {
+        let mut open = acquire(&self.open);
+        let entry = self
+            .find(&rw_acquire_shared(&self.bufmgr), name)?
+            .ok_or_else(|| CatalogError::NoSuchIndex(name.into()))?;
+        if entry.key_type != K::key_type() {
+            return Err(CatalogError::KeyTypeMismatch {
+                name: entry.name,
+                expected: entry.key_type,
+                found: K::key_type(),
+            });
+        }
+        let tree = open
+            .entry(entry.name)
+            .or_insert_with(|| {
+                Arc::new(BLinkTreeInternal::open(
+                    self.bufmgr.clone(),
+                    entry.meta_page_id,
+                ))
+            })
+            .clone();
+        Ok(BLinkTree::from_internal(tree))
+    }
+
+    /// Remove an index from the catalog and free its pages. Fails if a handle to the index is still held elsewhere
+    pub fn drop_index(&self, name: &str) -> CatalogResult<()> {
+        let mut open = acquire(&self.open);
+        let pool = rw_acquire_shared(&self.bufmgr);
+        let entry = self
+            .find(&pool, name)?
+            .ok_or_else(|| CatalogError::NoSuchIndex(name.into()))?;
+        let tree = match open.remove(name).map(Arc::try_unwrap) {
+            Some(Ok(tree)) => tree,
+            Some(Err(tree)) => {
+                open.insert(name.into(), tree);
+                return Err(CatalogError::IndexInUse(name.into()));
+            }
+            None => BLinkTreeInternal::open(self.bufmgr.clone(), entry.meta_page_id),
+        };
+
+        let txn = pool.begin();
+        let mut page_id = self.first_page_id;
+        loop {
+            let mut guard = txn.fetch_page_write(page_id)?;
+            let mut page = Self::decode(page_id, &guard)?;
+            if let Some(idx) = page.entries.iter().position(|e| e.name == name) {
+                page.entries.remove(idx);
+                ioutil::to_page(&page, &mut guard).unwrap();
+                break;
+            }
+            page_id = page.next;
+        }
+        txn.commit()?;
+        drop(pool);
+        // a crash from here on leaks the pages of the index, but cannot bring it back
+        tree.destroy()?;
+        Ok(())
+    }
+
+    /// The entries of every index, in no particular order
+    pub fn list_indexes(&self) -> CatalogResult<Vec<IndexEntry>> {
+        let pool = rw_acquire_shared(&self.bufmgr);
+        let mut entries = Vec::new();
+        let mut page_id = self.first_page_id;
+        while page_id != INVALID_PAGE_ID {
+            let page = self.read_page(&pool, page_id)?;
+            entries.extend(page.entries);
+            page_id = page.next;
+        }
+        Ok(entries)
+    }
+
+    fn find(&self, pool: &BufferPoolInternal, name: &str) -> CatalogResult<Option<IndexEntry>> {
+        let mut page_id = self.first_page_id;
+        while page_id != INVALID_PAGE_ID {
+            let page = self.read_page(pool, page_id)?;
+            if let Some(entry) = page.entries.into_iter().find(|e| e.name == name) {
+                return Ok(Some(entry));
+            }
+            page_id = page.next;
+        }
+        Ok(None)
+    }
+
+    fn read_page(&self, pool: &BufferPoolInternal, page_id: PageId) -> CatalogResult<CatalogPage> {
+        let guard = pool.fetch_page_read(page_id)?;
+        Self::decode(page_id, &guard)
+    }
+
+    fn decode(page_id: PageId, buf: &[u8; PAGE_SIZE]) -> CatalogResult<CatalogPage> {
+        ioutil::from_page(buf).ok_or(CatalogError::Corrupt(page_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CatalogError, IndexOptions, KeyType, ValueType};
+    use crate::bootstrap::{DbContext, Options};
+    use crate::concurrency::rw_acquire_shared;
+    use crate::shared::PageId;
+    use crate::storage::blink_tree::BLinkTree;
+    use crate::storage::objptr::ObjectPtr;
+
+    fn db_path(name: &str) -> String {
+        let path = crate::shared::cwd() + "/data/test/__catalog__/" + name;
+        for file in [path.clone(), DbContext::wal_path(&path)] {
+            let _ = std::fs::remove_file(file);
+        }
+        path
+    }
+
+    fn ptr(i: i32) -> ObjectPtr {
+        ObjectPtr::new(i as PageId, 0)
+    }
+
+    #[test]
+    fn create_open_drop() {
+        let path = db_path("indexes.bin");
+        let options = Options {
+            pool_size: 16,
+            ..Options::default()
+        };
+        {
+            let ctx = DbContext::open(&path, options).unwrap();
+            let songs: BLinkTree<i32> = ctx
+                .create_index("songs_by_id", ValueType::ObjectPtr, IndexOptions::default())
+                .unwrap();
+            ctx.create_index::<(String, i32)>(
+                "songs_by_artist",
+                ValueType::ObjectPtr,
+                IndexOptions { fill_factor: 70 },
+            )
+            .unwrap();
+            assert!(matches!(
+                ctx.create_index::<Vec<u8>>(
+                    "songs_by_id",
+                    ValueType::ObjectPtr,
+                    IndexOptions::default()
+                ),
+                Err(CatalogError::IndexExists(_))
+            ));
+            // enough keys for the root to split a few times
+            for i in 0..3000 {
+                songs.insert(&i, ptr(i)).unwrap();
+            }
+            // every handle to an index shares the same tree
+            let reopened = ctx.open_index::<i32>("songs_by_id").unwrap();
+            assert!(std::sync::Arc::ptr_eq(
+                songs.get_internal(),
+                reopened.get_internal()
+            ));
+            drop((songs, reopened));
+            ctx.close().unwrap();
+        }
+
+        let ctx = DbContext::open(&path, options).unwrap();
+        let mut entries = ctx.list_indexes().unwrap();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["songs_by_artist", "songs_by_id"]);
+        assert_eq!(
+            entries[0].key_type,
+            KeyType::Tuple(vec![KeyType::String, KeyType::Int32])
+        );
+        assert_eq!(entries[0].options.fill_factor, 70);
+        assert_eq!(entries[1].key_type, KeyType::Int32);
+        assert!(matches!(
+            ctx.open_index::<i64>("songs_by_id"),
+            Err(CatalogError::KeyTypeMismatch { .. })
+        ));
+
+        let songs = ctx.open_index::<i32>("songs_by_id").unwrap();
+        for i in 0..3000 {
+            assert_eq!(songs.get(&i).unwrap(), Some(ptr(i)));
+        }
+        assert!(matches!(
+            ctx.drop_index("songs_by_id"),
+            Err(CatalogError::IndexInUse(_))
+        ));
+        let meta_page_id = songs.get_meta_page_id();
+        drop(songs);
+        ctx.drop_index("songs_by_id").unwrap();
+        assert!(matches!(
+            ctx.open_index::<i32>("songs_by_id"),
+            Err(CatalogError::NoSuchIndex(_))
+        ));
+        assert_eq!(ctx.list_indexes().unwrap().len(), 1);
+        // the pages of the dropped index are free again
+        let diskmgr = rw_acquire_shared(ctx.get_diskmgr());
+        let reused = diskmgr.allocate_page().unwrap();
+        assert!(reused <= meta_page_id);
+        diskmgr.deallocate_page(reused).unwrap();
+    }
+
+    #[test]
+    fn catalog_spans_pages() {
+        let path = db_path("spanning.bin");
+        let name = |i: i32| format!("index_{:03}_{}", i, "x".repeat(100));
+        {
+            let ctx = DbContext::open(&path, Options::default()).unwrap();
+            for i in 0..100 {
+                ctx.create_index::<i32>(&name(i), ValueType::ObjectPtr, IndexOptions::default())
+                    .unwrap()
+                    .insert(&i, ptr(i))
+                    .unwrap();
+            }
+            assert!(matches!(
+                ctx.create_index::<i32>(
+                    &"y".repeat(5000),
+                    ValueType::ObjectPtr,
+                    IndexOptions::default()
+                ),
+                Err(CatalogError::EntryTooLarge(_))
+            ));
+            // closed by a crash, so the catalog comes back through recovery
+        }
+
+        let ctx = DbContext::open(&path, Options::default()).unwrap();
+        assert_eq!(ctx.list_indexes().unwrap().len(), 100);
+        for i in 0..100 {
+            let index = ctx.open_index::<i32>(&name(i)).unwrap();
+            assert_eq!(index.get(&i).unwrap(), Some(ptr(i)));
+        }
+    }
+}
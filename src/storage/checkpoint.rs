@@ -0,0 +1,256 @@
+// SOURCES + USEFUL LINKS
+// https://web.stanford.edu/class/cs345d-01/rl/aries.pdf (ARIES, section 5.4: fuzzy checkpoints)
+// https://www.postgresql.org/docs/current/wal-configuration.html
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::concurrency::{acquire, rw_acquire_shared, Synchronized};
+use crate::shared::{Lsn, INVALID_LSN, INVALID_TXN_ID};
+use crate::storage::bufmgr::{BufferPool, BufferPoolInternal};
+use crate::storage::wal::{LogBody, LogRecord};
+
+/// How often the background checkpointer checks whether a checkpoint is due
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Take a fuzzy checkpoint and return the LSN of its begin record, from which the next recovery will start.
+///
+/// Writers keep running throughout: the dirty page table and the active transactions are read without latching
+/// anything, between a begin and an end record. Whatever changes in the meantime is logged after the begin record, where
+/// recovery finds it. Once the end record is durable, the free space bitmap as of the begin record is written, and with
+/// the pages written so far it is synced before the checkpoint is recorded in the header page
+pub fn checkpoint(bufmgr: &BufferPoolInternal) -> std::io::Result<Lsn> {
+    let wal = rw_acquire_shared(
+        bufmgr
+            .get_wal()
+            .expect("checkpoints need a write-ahead log"),
+    );
+    let diskmgr = rw_acquire_shared(bufmgr.get_diskmgr());
+    // the bitmap is copied as of the begin record, so the allocations and frees it is missing are all logged after it
+    let (begin_lsn, bitmaps) = diskmgr.snapshot_bitmaps(|| {
+        wal.append(&LogRecord {
+            txn_id: INVALID_TXN_ID,
+            prev_lsn: INVALID_LSN,
+            body: LogBody::BeginCheckpoint,
+        })
+    });
+    let end = LogRecord {
+        txn_id: INVALID_TXN_ID,
+        prev_lsn: begin_lsn,
+        body: LogBody::EndCheckpoint {
+            dirty_pages: bufmgr.dirty_page_table(),
+            active_txns: wal.active_txns(),
+        },
+    };
+    let end_lsn = wal.append(&end);
+    wal.flush(end_lsn)?;
+    diskmgr.write_bitmaps(&bitmaps)?;
+    diskmgr.set_checkpoint_lsn(begin_lsn)?;
+    Ok(begin_lsn)
+}
+
+/// When the background checkpointer takes a checkpoint. A trigger that is None never fires
+#[derive(Copy, Clone, Debug)]
+pub struct CheckpointPolicy {
+    /// Time between checkpoints
+    pub interval: Option<Duration>,
+    /// Bytes of log written since the last checkpoint
+    pub log_bytes: Option<u64>,
+}
+
+/// A background thread taking checkpoints according to a `CheckpointPolicy`. It is stopped when dropped
+pub struct Checkpointer {
+    stopped: Synchronized<bool>,
+    wakeup: Arc<parking_lot::Condvar>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Checkpointer {
+    pub fn start(bufmgr: BufferPool, policy: CheckpointPolicy) -> Self {
+        let stopped: Synchronized<bool> = Arc::default();
+        let wakeup = Arc::new(parking_lot::Condvar::new());
+        let handle = {
+            let (stopped, wakeup) = (stopped.clone(), wakeup.clone());
+            std::thread::spawn(move || Self::run(bufmgr, policy, stopped, wakeup))
+        };
+        Self {
+            stopped,
+            wakeup,
+            handle: Some(handle),
+        }
+    }
+
+    fn run(
+        bufmgr: BufferPool,
+        policy: CheckpointPolicy,
+        stopped: Synchronized<bool>,
+        wakeup: Arc<parking_lot::Condvar>,
+    ) {
+        let poll = policy
+            .interval
+            .map_or(POLL_INTERVAL, |i| i.min(POLL_INTERVAL));
+        let mut last = Instant::now();
+        loop {
+            {
+                let mut stopped = acquire(&stopped);
+                if !*stopped {
+                    wakeup.wait_for(&mut stopped, poll);
+                }
+                if *stopped {
+                    return;
+                }
+            }
+            let pool = rw_acquire_shared(&bufmgr);
+            let due_by_time = policy.interval.is_some_and(|i| last.elapsed() >= i);
+            let due_by_size = policy.log_bytes.is_some_and(|bytes| {
+                let since = rw_acquire_shared(pool.get_diskmgr()).get_checkpoint_lsn();
+                let next = rw_acquire_shared(pool.get_wal().unwrap()).get_next_lsn();
+                next - since >= bytes
+            });
+            // a failed checkpoint leaves the previous one in place, and the next trigger tries again
+            if (due_by_time || due_by_size) && checkpoint(&pool).is_ok() {
+                last = Instant::now();
+            }
+        }
+    }
+
+    /// Stop the thread, waiting for a checkpoint in progress to finish
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        *acquire(&self.stopped) = true;
+        self.wakeup.notify_all();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Checkpointer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{CheckpointPolicy, Checkpointer};
+    use crate::bootstrap::{DbContext, Options};
+    use crate::concurrency::rw_acquire_shared;
+    use crate::shared::{PageId, PAGE_SIZE};
+    use crate::storage::blink_tree::BLinkTree;
+    use crate::storage::objptr::ObjectPtr;
+
+    fn db_path(name: &str) -> String {
+        let path = crate::shared::cwd() + "/data/test/__checkpoint__/" + name;
+        for file in [path.clone(), DbContext::wal_path(&path)] {
+            let _ = std::fs::remove_file(file);
+        }
+        path
+    }
+
+    #[test]
+    fn recovery_starts_at_checkpoint() {
+        let path = db_path("bounded.bin");
+        let (page_ids, logged) = {
+            let ctx = DbContext::open(&path, Options::default()).unwrap();
+            let pool = rw_acquire_shared(ctx.get_bufmgr());
+            let page_ids: Vec<PageId> = (0..3)
+                .map(|_| pool.new_page_write().unwrap().get_page_id())
+                .collect();
+            for round in 0..200 {
+                let txn = pool.begin();
+                txn.fetch_page_write(page_ids[0]).unwrap()[PAGE_SIZE - 1] = round as u8;
+                txn.commit().unwrap();
+            }
+            // a transaction that is still running when the checkpoint is taken, and never finishes
+            let loser = pool.begin();
+            loser.fetch_page_write(page_ids[1]).unwrap()[100] = 1;
+            let checkpoint_lsn = ctx.checkpoint().unwrap();
+            assert!(pool
+                .dirty_page_table()
+                .iter()
+                .any(|&(page_id, rec_lsn)| page_id == page_ids[0] && rec_lsn < checkpoint_lsn));
+
+            // changes after the checkpoint, and none of the pages are written back
+            let winner = pool.begin();
+            winner.fetch_page_write(page_ids[2]).unwrap()[200] = 2;
+            winner.commit().unwrap();
+            let logged = rw_acquire_shared(ctx.get_wal())
+                .iter_from(0)
+                .unwrap()
+                .count();
+            std::mem::forget(loser);
+            (page_ids, logged)
+        };
+
+        let ctx = DbContext::open(&path, Options::default()).unwrap();
+        let stats = ctx.get_recovery_stats();
+        assert!(stats.analyzed < 10, "{:?} of {} records", stats, logged);
+        assert_eq!((stats.losers, stats.undone), (1, 1));
+        let pool = rw_acquire_shared(ctx.get_bufmgr());
+        // the dirty page table of the checkpoint sends redo back to changes logged before it
+        assert_eq!(
+            pool.fetch_page_read(page_ids[0]).unwrap()[PAGE_SIZE - 1],
+            199
+        );
+        assert_eq!(pool.fetch_page_read(page_ids[1]).unwrap()[100], 0);
+        assert_eq!(pool.fetch_page_read(page_ids[2]).unwrap()[200], 2);
+    }
+
+    #[test]
+    fn checkpoints_alongside_writers() {
+        let path = db_path("concurrent.bin");
+        let (meta_page_id, checkpoints) = {
+            let ctx = DbContext::open(&path, Options::default()).unwrap();
+            let tree = Arc::new(BLinkTree::create(ctx.get_bufmgr().clone()).unwrap());
+            let first = rw_acquire_shared(ctx.get_bufmgr())
+                .get_diskmgr()
+                .read()
+                .get_checkpoint_lsn();
+            let checkpointer = Checkpointer::start(
+                ctx.get_bufmgr().clone(),
+                CheckpointPolicy {
+                    interval: Some(Duration::from_millis(5)),
+                    log_bytes: Some(1 << 16),
+                },
+            );
+            std::thread::scope(|scope| {
+                for t in 0..4 {
+                    let tree = tree.clone();
+                    scope.spawn(move || {
+                        for i in (t..2000).step_by(4) {
+                            tree.insert(
+                                &i.to_string().into_bytes(),
+                                ObjectPtr::new(i as PageId, 0),
+                            )
+                            .unwrap();
+                        }
+                    });
+                }
+            });
+            checkpointer.stop();
+            let last = rw_acquire_shared(ctx.get_bufmgr())
+                .get_diskmgr()
+                .read()
+                .get_checkpoint_lsn();
+            // the process dies without writing anything back
+            (tree.get_meta_page_id(), last > first)
+        };
+        assert!(checkpoints);
+
+        let ctx = DbContext::open(&path, Options::default()).unwrap();
+        let tree = BLinkTree::open(ctx.get_bufmgr().clone(), meta_page_id);
+        for i in 0..2000usize {
+            assert_eq!(
+                tree.get(&i.to_string().into_bytes()).unwrap(),
+                Some(ObjectPtr::new(i as PageId, 0))
+            );
+        }
+    }
+}
@@ -1,86 +1,720 @@
 #![allow(unused_imports)]
+// SOURCES + USEFUL LINKS
+// https://github.com/cmu-db/bustub/blob/master/src/include/storage/disk/disk_manager.h
+// https://www.postgresql.org/docs/current/storage-fsm.html
+use std::fmt::Display;
 use std::fs::{File, OpenOptions};
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 
-use crate::concurrency::Synchronized;
-use crate::shared::{PageId, PAGE_SIZE};
+use serde::{Deserialize, Serialize};
+
+use crate::concurrency::{acquire, Synchronized};
+use crate::shared::{Lsn, PageId, HEADER_ID, INVALID_LSN, INVALID_PAGE_ID, PAGE_SIZE};
 use crate::storage::fsutil::{read_bytes, write_bytes};
+use crate::storage::ioutil;
+
+/// Pages tracked by one bitmap page
+const BITS_PER_BITMAP: PageId = (PAGE_SIZE * 8) as PageId;
+
+/// Pages the file grows by at a time. The header is synced whenever the file grows, so this is how many allocations
+/// share a sync
+const EXTENT_SIZE: PageId = 64;
+
+/// Identifies a database file. Stored at the start of the header page
+const MAGIC: [u8; 8] = *b"SYMBTREE";
+/// Bumped whenever the on-disk format changes incompatibly
+const FORMAT_VERSION: u32 = 3;
+
+/// The contents of the header page
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+struct FileHeader {
+    magic: [u8; 8],
+    version: u32,
+    page_size: u32,
+    /// Number of pages in the file, including the header and bitmap pages
+    page_count: u64,
+    /// LSN of the begin record of the last complete checkpoint, where recovery starts reading the log
+    checkpoint_lsn: Lsn,
+    /// First page of the catalog of indexes, or INVALID_PAGE_ID if it has not been created yet
+    catalog_page_id: PageId,
+}
+
+/// How `DiskMgrInternal::open` treats the file at the given path. An existing file is never truncated
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum OpenMode {
+    /// Create a new database file, failing if the path already exists
+    CreateNew,
+    /// Open an existing database file, failing if there is none
+    OpenExisting,
+    /// Open the database file if it exists, otherwise create it
+    OpenOrCreate,
+}
+
+/// Errors returned when opening a database file
+#[derive(Debug)]
+pub enum DiskError {
+    /// `OpenMode::CreateNew` was given a path that exists
+    AlreadyExists(String),
+    /// `OpenMode::OpenExisting` was given a path that does not exist
+    NotFound(String),
+    /// The file does not start with a database header
+    NotADatabase(String),
+    /// The file was written by an incompatible version of the format
+    UnsupportedVersion {
+        path: String,
+        version: u32,
+    },
+    /// The file was written with a different page size
+    PageSizeMismatch {
+        path: String,
+        page_size: u32,
+    },
+    /// The header records no pages, not even itself
+    SizeMismatch {
+        path: String,
+        page_count: u64,
+        file_len: u64,
+    },
+    Io(std::io::Error),
+}
+
+pub type DiskResult<T> = Result<T, DiskError>;
+
+impl Display for DiskError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiskError::AlreadyExists(path) => write!(f, "database file {} already exists", path),
+            DiskError::NotFound(path) => write!(f, "database file {} does not exist", path),
+            DiskError::NotADatabase(path) => {
+                write!(f, "{} is not a database file: bad magic number", path)
+            }
+            DiskError::UnsupportedVersion { path, version } => write!(
+                f,
+                "database file {} has format version {}, but only version {} is supported",
+                path, version, FORMAT_VERSION
+            ),
+            DiskError::PageSizeMismatch { path, page_size } => write!(
+                f,
+                "database file {} uses {} byte pages, but this build uses {} byte pages",
+                path, page_size, PAGE_SIZE
+            ),
+            DiskError::SizeMismatch {
+                path,
+                page_count,
+                file_len,
+            } => write!(
+                f,
+                "database file {} is {} bytes long, but its header records only {} pages",
+                path, file_len, page_count
+            ),
+            DiskError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for DiskError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DiskError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for DiskError {
+    fn from(e: std::io::Error) -> Self {
+        DiskError::Io(e)
+    }
+}
+
+impl From<DiskError> for std::io::Error {
+    fn from(e: DiskError) -> Self {
+        match e {
+            DiskError::Io(e) => e,
+            e => std::io::Error::other(e),
+        }
+    }
+}
+
+/// Tracks which pages are in use with an on-disk bitmap. The file is divided into groups of `BITS_PER_BITMAP + 1` pages
+/// following the header, where the first page of every group is a bitmap with one bit for each of the other pages in the
+/// group. Bitmap pages are created as the file grows into their group. A cached copy of each bitmap is kept for
+/// allocation. Changes are written through to disk, except those made through the `_logged` methods, which reach the disk
+/// when a checkpoint writes the bitmaps
+struct FreeSpaceMap {
+    page_count: PageId,
+    bitmaps: Vec<Box<[u8; PAGE_SIZE]>>,
+    /// No page below this one is free
+    first_free: PageId,
+}
+
+impl FreeSpaceMap {
+    const GROUP_SIZE: PageId = BITS_PER_BITMAP + 1;
+
+    fn new() -> Self {
+        Self {
+            page_count: HEADER_ID as PageId + 1,
+            bitmaps: Vec::new(),
+            first_free: HEADER_ID as PageId + 1,
+        }
+    }
+
+    /// Id of the `idx`th bitmap page
+    #[inline]
+    fn bitmap_page_id(idx: usize) -> PageId {
+        HEADER_ID as PageId + 1 + idx as PageId * Self::GROUP_SIZE
+    }
+
+    /// The bitmap and bit that track `page_id`, or None for the header and bitmap pages, which are always in use
+    fn locate(page_id: PageId) -> Option<(usize, usize)> {
+        if page_id <= HEADER_ID as PageId {
+            return None;
+        }
+        let offset = page_id - HEADER_ID as PageId - 1;
+        match offset % Self::GROUP_SIZE {
+            0 => None,
+            bit => Some(((offset / Self::GROUP_SIZE) as usize, bit as usize - 1)),
+        }
+    }
+
+    fn is_allocated(&self, page_id: PageId) -> bool {
+        match Self::locate(page_id) {
+            Some((idx, bit)) => self.bitmaps[idx][bit / 8] & (1 << (bit % 8)) != 0,
+            None => true,
+        }
+    }
+
+    /// Refuse ids that no bitmap tracks: the header, bitmap pages and pages past the end of the file
+    fn check(&self, page_id: PageId) -> std::io::Result<()> {
+        if Self::locate(page_id).is_none() || page_id >= self.page_count {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("page {} was never allocated", page_id),
+            ));
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, page_id: PageId, allocated: bool) -> usize {
+        let (idx, bit) = Self::locate(page_id).expect("header and bitmap pages cannot change");
+        if allocated {
+            self.bitmaps[idx][bit / 8] |= 1 << (bit % 8);
+        } else {
+            self.bitmaps[idx][bit / 8] &= !(1 << (bit % 8));
+        }
+        idx
+    }
+}
+
+/// What happens to a write when faults are injected
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum WriteFate {
+    Complete,
+    /// The process dies during this write, so only part of it may reach the file
+    Torn,
+    /// The process is already dead
+    Lost,
+}
+
+/// Simulates the process being killed after a given number of writes, for testing crash recovery. The write that crosses
+/// the limit and every write after it fail without reaching the file, so whatever the files hold at that point is what a
+/// restarted process finds. One injector is shared by the disk manager and the write-ahead log, so the crash point counts
+/// writes to both
+#[derive(Debug)]
+pub struct FaultInjector {
+    /// Writes left before the crash
+    remaining: AtomicUsize,
+    crashed: AtomicBool,
+}
+
+impl Default for FaultInjector {
+    fn default() -> Self {
+        Self {
+            remaining: AtomicUsize::new(usize::MAX),
+            crashed: AtomicBool::new(false),
+        }
+    }
+}
+
+impl FaultInjector {
+    /// Let `writes` more writes complete, then kill the process during the next one
+    pub fn crash_after(&self, writes: usize) {
+        self.remaining.store(writes, Ordering::Release);
+    }
+
+    pub fn has_crashed(&self) -> bool {
+        self.crashed.load(Ordering::Acquire)
+    }
+
+    /// Decide the fate of the next write
+    pub fn next_write(&self) -> WriteFate {
+        if self.has_crashed() {
+            return WriteFate::Lost;
+        }
+        let admitted =
+            self.remaining
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |remaining| {
+                    remaining.checked_sub(1)
+                });
+        match admitted {
+            Ok(remaining) if remaining > 0 => WriteFate::Complete,
+            _ => {
+                self.crashed.store(true, Ordering::Release);
+                WriteFate::Torn
+            }
+        }
+    }
+
+    /// The error returned by writes that did not survive the crash
+    pub fn crash_error() -> std::io::Error {
+        std::io::Error::other("process killed by fault injection")
+    }
+}
+
+/// The bitmap pages as they were when a checkpoint began
+pub struct BitmapSnapshot(Vec<Box<[u8; PAGE_SIZE]>>);
+
+/// What the disk manager did since it was opened or its counters were last reset
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DiskStats {
+    pub pages_read: u64,
+    /// Pages written, including the header and the free space bitmaps
+    pub pages_written: u64,
+    pub fsyncs: u64,
+}
 
 pub struct DiskMgrInternal {
     file_handle: Synchronized<File>,
     file_path: String,
-    num_flushes: usize,
-    num_writes: usize,
+    space: Synchronized<FreeSpaceMap>,
+    checkpoint_lsn: AtomicU64,
+    catalog_page_id: AtomicIsize,
+    num_reads: AtomicU64,
+    num_writes: AtomicU64,
+    num_flushes: AtomicU64,
+    faults: Option<Arc<FaultInjector>>,
+    /// The file was formatted by `open` rather than loaded
+    created: bool,
 }
 
 impl DiskMgrInternal {
-    pub fn new(file_path: &str) -> Self {
-        let file_handle: Synchronized<File> = Arc::new(parking_lot::Mutex::new(
-            OpenOptions::new()
-                .create(true)
-                .read(true)
-                .write(true)
-                .truncate(true)
-                .open(std::path::Path::new(file_path))
-                .unwrap(),
-        ));
+    /// Open or create the database file at `file_path`. A new file gets an empty header; an existing one has its header
+    /// checked and its free space map loaded
+    pub fn open(file_path: &str, mode: OpenMode) -> DiskResult<Self> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        match mode {
+            OpenMode::CreateNew => options.create_new(true),
+            OpenMode::OpenExisting => &mut options,
+            OpenMode::OpenOrCreate => options.create(true),
+        };
+        let file = options
+            .open(std::path::Path::new(file_path))
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::AlreadyExists => DiskError::AlreadyExists(file_path.into()),
+                std::io::ErrorKind::NotFound => DiskError::NotFound(file_path.into()),
+                _ => DiskError::Io(e),
+            })?;
+        let file_len = file.metadata()?.len();
 
-        Self {
-            file_handle,
+        let mut diskmgr = Self {
+            file_handle: Arc::new(parking_lot::Mutex::new(file)),
             file_path: String::from(file_path),
-            num_flushes: 0,
-            num_writes: 0,
+            space: Arc::new(parking_lot::Mutex::new(FreeSpaceMap::new())),
+            checkpoint_lsn: AtomicU64::new(INVALID_LSN),
+            catalog_page_id: AtomicIsize::new(INVALID_PAGE_ID),
+            num_reads: AtomicU64::new(0),
+            num_writes: AtomicU64::new(0),
+            num_flushes: AtomicU64::new(0),
+            faults: None,
+            created: false,
+        };
+        // an empty file is one whose creation was interrupted, so there is nothing to lose by formatting it
+        if file_len == 0 && mode != OpenMode::OpenExisting {
+            diskmgr.format()?;
+            diskmgr.created = true;
+        } else {
+            diskmgr.load(file_len)?;
         }
+        Ok(diskmgr)
     }
 
-    /// Shutdown DiskMgr and close all open file handles
-    pub fn close(&self) {
-        drop(self);
+    /// Route every write through `faults`, which decides when the process dies
+    pub fn with_faults(mut self, faults: Arc<FaultInjector>) -> Self {
+        self.faults = Some(faults);
+        self
     }
 
+    #[inline]
+    pub fn get_file_path(&self) -> &str {
+        &self.file_path
+    }
+
+    /// Whether `open` created the database rather than opening an existing one
+    #[inline]
+    pub fn was_created(&self) -> bool {
+        self.created
+    }
+
+    pub fn stats(&self) -> DiskStats {
+        DiskStats {
+            pages_read: self.num_reads.load(Ordering::Relaxed),
+            pages_written: self.num_writes.load(Ordering::Relaxed),
+            fsyncs: self.num_flushes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Start counting from zero again
+    pub fn reset_stats(&self) {
+        self.num_reads.store(0, Ordering::Relaxed);
+        self.num_writes.store(0, Ordering::Relaxed);
+        self.num_flushes.store(0, Ordering::Relaxed);
+    }
+
+    /// Shutdown DiskMgr, syncing the underlying file. The handle itself is closed when the DiskMgr is dropped
+    pub fn close(&self) -> std::io::Result<()> {
+        self.sync()
+    }
+
+    /// Write a page without syncing it. Durability comes from the write-ahead log, so pages only need to reach the disk
+    /// before a checkpoint or shutdown, which call `sync`
     pub fn write_page(&self, id: PageId, page_buf: &[u8; PAGE_SIZE]) -> std::io::Result<()> {
-        let file = self.file_handle.lock();
+        self.check_faults()?;
+        let file = acquire(&self.file_handle);
         write_bytes(&file, page_buf, PAGE_SIZE as u64 * id as u64)?;
-        // sync filesystem
-        file.sync_all()?;
+        self.num_writes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Fail a write that an injected crash prevents. Page writes are assumed to be atomic, so a torn one is lost entirely
+    fn check_faults(&self) -> std::io::Result<()> {
+        match self.faults.as_ref().map(|faults| faults.next_write()) {
+            None | Some(WriteFate::Complete) => Ok(()),
+            Some(_) => Err(FaultInjector::crash_error()),
+        }
+    }
+
+    /// Force every page written so far to disk
+    pub fn sync(&self) -> std::io::Result<()> {
+        acquire(&self.file_handle).sync_all()?;
+        self.num_flushes.fetch_add(1, Ordering::Relaxed);
         Ok(())
     }
 
     pub fn read_page(&self, id: PageId, page_buf: &mut [u8; PAGE_SIZE]) -> std::io::Result<()> {
-        let file = self.file_handle.lock();
+        let file = acquire(&self.file_handle);
         read_bytes(&file, page_buf, PAGE_SIZE as u64 * id as u64)?;
+        self.num_reads.fetch_add(1, Ordering::Relaxed);
         Ok(())
     }
 
-    pub fn clear(&self) -> std::io::Result<()> {
-        unsafe {
-            (*self.file_handle.data_ptr()).set_len(0)?;
+    /// Write one of the pages the disk manager keeps for itself. These are not synced until the next `sync` or `close`
+    fn write_meta_page(&self, id: PageId, page_buf: &[u8; PAGE_SIZE]) -> std::io::Result<()> {
+        self.check_faults()?;
+        write_bytes(
+            &acquire(&self.file_handle),
+            page_buf,
+            PAGE_SIZE as u64 * id as u64,
+        )?;
+        self.num_writes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn write_header(&self, space: &FreeSpaceMap) -> std::io::Result<()> {
+        let header = FileHeader {
+            magic: MAGIC,
+            version: FORMAT_VERSION,
+            page_size: PAGE_SIZE as u32,
+            page_count: space.page_count as u64,
+            checkpoint_lsn: self.checkpoint_lsn.load(Ordering::Acquire),
+            catalog_page_id: self.catalog_page_id.load(Ordering::Acquire),
+        };
+        self.write_meta_page(HEADER_ID as PageId, &ioutil::to_buffer(header).unwrap())
+    }
+
+    /// Write an empty header and forget every allocation
+    fn format(&self) -> std::io::Result<()> {
+        let mut space = acquire(&self.space);
+        *space = FreeSpaceMap::new();
+        self.checkpoint_lsn.store(INVALID_LSN, Ordering::Release);
+        self.catalog_page_id
+            .store(INVALID_PAGE_ID, Ordering::Release);
+        self.write_header(&space)
+    }
+
+    /// Check the header of an existing file and read its bitmap pages
+    fn load(&self, file_len: u64) -> DiskResult<()> {
+        let mut buf = [0u8; PAGE_SIZE];
+        self.read_page(HEADER_ID as PageId, &mut buf)?;
+        let header = match ioutil::from_buffer::<FileHeader>(&buf) {
+            Some(header) if header.magic == MAGIC => header,
+            _ => return Err(DiskError::NotADatabase(self.file_path.clone())),
+        };
+        if header.version != FORMAT_VERSION {
+            return Err(DiskError::UnsupportedVersion {
+                path: self.file_path.clone(),
+                version: header.version,
+            });
+        }
+        if header.page_size as usize != PAGE_SIZE {
+            return Err(DiskError::PageSizeMismatch {
+                path: self.file_path.clone(),
+                page_size: header.page_size,
+            });
+        }
+        if header.page_count == 0 {
+            return Err(DiskError::SizeMismatch {
+                path: self.file_path.clone(),
+                page_count: header.page_count,
+                file_len,
+            });
+        }
+        // pages that were allocated but never written do not extend the file, so it may be shorter than the page count.
+        // The header is synced before the file grows, so anything past the page count was written by a process that died
+        // before the header recording it reached the disk, and no page in there was ever handed out
+        let len = header.page_count * PAGE_SIZE as u64;
+        if file_len > len {
+            let file = acquire(&self.file_handle);
+            file.set_len(len)?;
+            file.sync_all()?;
+        }
+
+        self.checkpoint_lsn
+            .store(header.checkpoint_lsn, Ordering::Release);
+        self.catalog_page_id
+            .store(header.catalog_page_id, Ordering::Release);
+        let mut space = acquire(&self.space);
+        *space = FreeSpaceMap::new();
+        space.page_count = header.page_count as PageId;
+        while FreeSpaceMap::bitmap_page_id(space.bitmaps.len()) < space.page_count {
+            let mut bitmap = Box::new([0u8; PAGE_SIZE]);
+            self.read_page(
+                FreeSpaceMap::bitmap_page_id(space.bitmaps.len()),
+                &mut bitmap,
+            )?;
+            space.bitmaps.push(bitmap);
+        }
+        Ok(())
+    }
+
+    /// Hand out a page id that is not in use, reusing the lowest free page before growing the file. The header and
+    /// bitmap pages are never handed out
+    pub fn allocate_page(&self) -> std::io::Result<PageId> {
+        let mut space = acquire(&self.space);
+        let page_id = self.find_free(&mut space)?;
+        self.write_bit(&mut space, page_id, true)?;
+        space.first_free = page_id + 1;
+        Ok(page_id)
+    }
+
+    /// Like `allocate_page`, but for a database with a write-ahead log: `log` is called with the page id to log the
+    /// allocation, before any other page is allocated or freed, and the bitmap is left to be written by the next
+    /// checkpoint. The bitmap on disk then never has an allocation whose log record may not be durable, which recovery
+    /// could not undo
+    pub fn allocate_page_logged(&self, log: impl FnOnce(PageId)) -> std::io::Result<PageId> {
+        let mut space = acquire(&self.space);
+        let page_id = self.find_free(&mut space)?;
+        space.set(page_id, true);
+        space.first_free = page_id + 1;
+        log(page_id);
+        Ok(page_id)
+    }
+
+    /// The lowest page that is not in use, growing the file if there is none
+    fn find_free(&self, space: &mut FreeSpaceMap) -> std::io::Result<PageId> {
+        let mut page_id = space.first_free;
+        while page_id < space.page_count && space.is_allocated(page_id) {
+            page_id += 1;
+        }
+        if page_id == space.page_count {
+            // grow the file by an extent, with a bitmap page for every group it reaches into. The header has to be durable
+            // before anything is written past the old page count, or a crash in between leaves pages that it does not
+            // account for
+            let page_count = space.page_count;
+            space.page_count += EXTENT_SIZE;
+            if let Err(e) = self.write_header(space).and_then(|_| self.sync()) {
+                space.page_count = page_count;
+                return Err(e);
+            }
+            let bitmaps = space.bitmaps.len();
+            while FreeSpaceMap::bitmap_page_id(space.bitmaps.len()) < space.page_count {
+                space.bitmaps.push(Box::new([0u8; PAGE_SIZE]));
+            }
+            // a bitmap page that never makes it to disk reads as empty, which is what it is
+            for idx in bitmaps..space.bitmaps.len() {
+                self.write_meta_page(FreeSpaceMap::bitmap_page_id(idx), &space.bitmaps[idx])?;
+            }
+            if FreeSpaceMap::locate(page_id).is_none() {
+                page_id += 1;
+            }
+        }
+        Ok(page_id)
+    }
+
+    /// Mark a page as free so a later `allocate_page` can reuse it. Fails with `InvalidInput` if the page is not in use
+    pub fn deallocate_page(&self, id: PageId) -> std::io::Result<()> {
+        let mut space = acquire(&self.space);
+        Self::check_allocated(&space, id)?;
+        self.write_bit(&mut space, id, false)?;
+        space.first_free = space.first_free.min(id);
+        Ok(())
+    }
+
+    /// Like `deallocate_page`, for a database with a write-ahead log. See `allocate_page_logged`
+    pub fn deallocate_page_logged(&self, id: PageId, log: impl FnOnce()) -> std::io::Result<()> {
+        let mut space = acquire(&self.space);
+        Self::check_allocated(&space, id)?;
+        space.set(id, false);
+        space.first_free = space.first_free.min(id);
+        log();
+        Ok(())
+    }
+
+    /// Mark a page as in use or free, whatever the bitmap says now. Recovery uses this to bring the bitmap in line with
+    /// the allocations and frees in the log, which may not have reached the disk before a crash
+    pub fn set_allocated(&self, id: PageId, allocated: bool) -> std::io::Result<()> {
+        let mut space = acquire(&self.space);
+        space.check(id)?;
+        if space.is_allocated(id) == allocated {
+            return Ok(());
+        }
+        self.write_bit(&mut space, id, allocated)?;
+        if !allocated {
+            space.first_free = space.first_free.min(id);
+        }
+        Ok(())
+    }
+
+    /// Set the bit of a page and write its bitmap through, leaving the bit as it was if the write fails
+    fn write_bit(
+        &self,
+        space: &mut FreeSpaceMap,
+        id: PageId,
+        allocated: bool,
+    ) -> std::io::Result<()> {
+        let idx = space.set(id, allocated);
+        if let Err(e) = self.write_meta_page(FreeSpaceMap::bitmap_page_id(idx), &space.bitmaps[idx])
+        {
+            space.set(id, !allocated);
+            return Err(e);
         }
         Ok(())
     }
+
+    /// Copy every bitmap page, calling `begin` to log the begin record of a checkpoint while no page can be allocated or
+    /// freed. Every logged allocation and free the copy has is then logged before the checkpoint, and the others after
+    /// it, where recovery finds them
+    pub fn snapshot_bitmaps(&self, begin: impl FnOnce() -> Lsn) -> (Lsn, BitmapSnapshot) {
+        let space = acquire(&self.space);
+        let lsn = begin();
+        (lsn, BitmapSnapshot(space.bitmaps.clone()))
+    }
+
+    /// Write bitmap pages copied by `snapshot_bitmaps`. They are durable after the next sync
+    pub fn write_bitmaps(&self, snapshot: &BitmapSnapshot) -> std::io::Result<()> {
+        for (idx, bitmap) in snapshot.0.iter().enumerate() {
+            self.write_meta_page(FreeSpaceMap::bitmap_page_id(idx), bitmap)?;
+        }
+        Ok(())
+    }
+
+    /// Whether a page is in use. The header and bitmap pages always are
+    pub fn is_allocated(&self, id: PageId) -> bool {
+        let space = acquire(&self.space);
+        id < space.page_count && space.is_allocated(id)
+    }
+
+    /// Fail with `InvalidInput` unless `id` is a page handed out by `allocate_page` and not freed since
+    pub fn check_page_id(&self, id: PageId) -> std::io::Result<()> {
+        Self::check_allocated(&acquire(&self.space), id)
+    }
+
+    fn check_allocated(space: &FreeSpaceMap, id: PageId) -> std::io::Result<()> {
+        space.check(id)?;
+        if !space.is_allocated(id) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("page {} is not in use", id),
+            ));
+        }
+        Ok(())
+    }
+
+    /// LSN of the last complete checkpoint, or INVALID_LSN if there has been none
+    pub fn get_checkpoint_lsn(&self) -> Lsn {
+        self.checkpoint_lsn.load(Ordering::Acquire)
+    }
+
+    /// Record a completed checkpoint in the header. Every page written so far is synced first, since recovery will not
+    /// look at changes made before the checkpoint to pages that were clean at the time
+    pub fn set_checkpoint_lsn(&self, lsn: Lsn) -> std::io::Result<()> {
+        self.sync()?;
+        let space = acquire(&self.space);
+        self.checkpoint_lsn.store(lsn, Ordering::Release);
+        self.write_header(&space)?;
+        drop(space);
+        self.sync()
+    }
+
+    /// First page of the catalog, or INVALID_PAGE_ID if the database has none yet
+    pub fn get_catalog_page_id(&self) -> PageId {
+        self.catalog_page_id.load(Ordering::Acquire)
+    }
+
+    /// Record where the catalog starts. The header is synced, but the catalog page itself has to be made durable by the
+    /// caller, through the log or by writing it back
+    pub fn set_catalog_page_id(&self, page_id: PageId) -> std::io::Result<()> {
+        let space = acquire(&self.space);
+        self.catalog_page_id.store(page_id, Ordering::Release);
+        self.write_header(&space)?;
+        drop(space);
+        self.sync()
+    }
+
+    /// Number of pages in the file, including the header and bitmap pages
+    pub fn get_page_count(&self) -> PageId {
+        acquire(&self.space).page_count
+    }
+
+    /// Truncate the file, leaving an empty header
+    pub fn clear(&self) -> std::io::Result<()> {
+        acquire(&self.file_handle).set_len(0)?;
+        self.format()
+    }
 }
 
 pub type DiskMgr = Arc<parking_lot::RwLock<DiskMgrInternal>>;
 
+#[cfg(test)]
+impl DiskMgrInternal {
+    /// Create an empty database file for a test, removing whatever an earlier run left behind
+    pub(crate) fn recreate(file_path: &str) -> Self {
+        match std::fs::remove_file(file_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => panic!("{}", e),
+            _ => {}
+        }
+        Self::open(file_path, OpenMode::CreateNew).unwrap()
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    #![allow(clippy::nonminimal_bool)]
     use super::*;
 
     use lazy_static::lazy_static;
     use rayon::ThreadPoolBuilder;
     use std::sync::Arc;
 
-    use crate::concurrency::{
-        acquire, rw_acquire_excl, rw_acquire_shared, rw_release_excl, rw_release_shared,
-    };
     use crate::shared::Song;
     use crate::storage::ioutil;
 
-    const DISKMGR_TEST_PATH: &'static str = "/Users/anishsinha/Home/personal/research/symmetric-concurrent/symmetric-concurrent-v3/data/test/__diskmgr__/diskmgr.bin";
-
     lazy_static! {
-        static ref DISKMGR: DiskMgr = Arc::new(parking_lot::RwLock::new(DiskMgrInternal::new(
-            DISKMGR_TEST_PATH
+        static ref DISKMGR_TEST_PATH: String =
+            crate::shared::cwd() + "/data/test/__diskmgr__/diskmgr.bin";
+        static ref DISKMGR: DiskMgr = Arc::new(parking_lot::RwLock::new(DiskMgrInternal::recreate(
+            &DISKMGR_TEST_PATH
         )));
         /// Used in test threaded_rw
         static ref DONE_STATE: Arc<(parking_lot::Mutex<bool>, parking_lot::Condvar)> =
@@ -93,17 +727,259 @@ mod tests {
         let helium = Song::new(1, "Helium", "Glass Animals");
         let helium_buf = ioutil::to_buffer(helium).unwrap();
         assert!(!internal.clear().is_err());
-        assert!(!internal
-            .write_page(helium.id as isize, &helium_buf)
-            .is_err());
+        let page_id = internal.allocate_page().unwrap();
+        assert!(!internal.write_page(page_id, &helium_buf).is_err());
         let mut helium_disk_buf = [0u8; PAGE_SIZE];
-        assert!(!internal
-            .read_page(helium.id as isize, &mut helium_disk_buf)
-            .is_err());
+        assert!(!internal.read_page(page_id, &mut helium_disk_buf).is_err());
         let helium_from_buf = ioutil::from_buffer::<Song>(&helium_disk_buf).unwrap();
 
         assert_eq!(helium.id, helium_from_buf.id);
         assert_eq!(helium.title, helium_from_buf.title);
         assert_eq!(helium.artist, helium_from_buf.artist);
     }
+
+    #[test]
+    fn allocate_deallocate() {
+        let diskmgr =
+            DiskMgrInternal::recreate(&(crate::shared::cwd() + "/data/test/__diskmgr__/alloc.bin"));
+        let first = diskmgr.allocate_page().unwrap();
+        let second = diskmgr.allocate_page().unwrap();
+        assert_ne!(first, HEADER_ID as PageId);
+        assert_ne!(first, FreeSpaceMap::bitmap_page_id(0));
+        assert_ne!(first, second);
+
+        diskmgr.deallocate_page(first).unwrap();
+        // a double free, a page past the end and the header and bitmap pages are refused
+        for id in [
+            first,
+            diskmgr.get_page_count(),
+            HEADER_ID as PageId,
+            FreeSpaceMap::bitmap_page_id(0),
+        ] {
+            let e = diskmgr.deallocate_page(id).unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+        }
+        assert_eq!(diskmgr.allocate_page().unwrap(), first);
+        assert!(diskmgr.allocate_page().unwrap() > second);
+
+        // the header and bitmap are on disk
+        let mut buf = [0u8; PAGE_SIZE];
+        diskmgr.read_page(HEADER_ID as PageId, &mut buf).unwrap();
+        let header = ioutil::from_buffer::<FileHeader>(&buf).unwrap();
+        assert_eq!(header.page_count as PageId, diskmgr.get_page_count());
+        diskmgr
+            .read_page(FreeSpaceMap::bitmap_page_id(0), &mut buf)
+            .unwrap();
+        assert_eq!(buf[0], 0b111);
+    }
+
+    #[test]
+    fn churn_reuses_space() {
+        let diskmgr =
+            DiskMgrInternal::recreate(&(crate::shared::cwd() + "/data/test/__diskmgr__/churn.bin"));
+        let mut live: Vec<PageId> = (0..64).map(|_| diskmgr.allocate_page().unwrap()).collect();
+        let page_count = diskmgr.get_page_count();
+        for round in 0..20 {
+            // free every other page, then allocate as many again
+            let (freed, kept): (Vec<PageId>, Vec<PageId>) =
+                live.iter().partition(|&&id| (id + round) % 2 == 0);
+            for &id in &freed {
+                diskmgr.deallocate_page(id).unwrap();
+            }
+            live = kept;
+            for _ in 0..freed.len() {
+                live.push(diskmgr.allocate_page().unwrap());
+            }
+            assert_eq!(diskmgr.get_page_count(), page_count);
+        }
+        live.sort();
+        live.dedup();
+        assert_eq!(live.len(), 64);
+    }
+
+    #[test]
+    fn growth_syncs_once_per_extent() {
+        let diskmgr = DiskMgrInternal::recreate(
+            &(crate::shared::cwd() + "/data/test/__diskmgr__/extents.bin"),
+        );
+        diskmgr.reset_stats();
+        let count = 10 * EXTENT_SIZE as usize;
+        let ids: Vec<PageId> = (0..count)
+            .map(|_| diskmgr.allocate_page().unwrap())
+            .collect();
+        assert!(diskmgr.stats().fsyncs <= 11);
+        // the pages of an extent are handed out in order, and the header counts every one of them
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        let mut buf = [0u8; PAGE_SIZE];
+        diskmgr.read_page(HEADER_ID as PageId, &mut buf).unwrap();
+        let header = ioutil::from_buffer::<FileHeader>(&buf).unwrap();
+        assert_eq!(header.page_count as PageId, diskmgr.get_page_count());
+        assert!(ids[count - 1] < diskmgr.get_page_count());
+    }
+
+    #[test]
+    fn bitmap_groups() {
+        let diskmgr = DiskMgrInternal::recreate(
+            &(crate::shared::cwd() + "/data/test/__diskmgr__/groups.bin"),
+        );
+        let count = BITS_PER_BITMAP as usize + 10;
+        let ids: Vec<PageId> = (0..count)
+            .map(|_| diskmgr.allocate_page().unwrap())
+            .collect();
+        // the second bitmap page sits between the two groups and is skipped
+        let second_bitmap = FreeSpaceMap::bitmap_page_id(1);
+        assert!(!ids.contains(&second_bitmap));
+        assert!(ids.contains(&(second_bitmap - 1)));
+        assert!(ids.contains(&(second_bitmap + 1)));
+        assert!(diskmgr.get_page_count() - ids[count - 1] <= EXTENT_SIZE);
+
+        diskmgr.deallocate_page(second_bitmap + 1).unwrap();
+        assert_eq!(diskmgr.allocate_page().unwrap(), second_bitmap + 1);
+    }
+
+    #[test]
+    fn open_modes() {
+        let path = crate::shared::cwd() + "/data/test/__diskmgr__/modes.bin";
+        let _ = std::fs::remove_file(&path);
+        assert!(matches!(
+            DiskMgrInternal::open(&path, OpenMode::OpenExisting),
+            Err(DiskError::NotFound(_))
+        ));
+
+        let song = Song::new(7, "Daddy Issues", "The Neighbourhood");
+        let (kept, freed, catalog) = {
+            let diskmgr = DiskMgrInternal::open(&path, OpenMode::CreateNew).unwrap();
+            let ids: Vec<PageId> = (0..10).map(|_| diskmgr.allocate_page().unwrap()).collect();
+            diskmgr
+                .write_page(ids[9], &ioutil::to_buffer(song).unwrap())
+                .unwrap();
+            diskmgr.deallocate_page(ids[3]).unwrap();
+            diskmgr.set_checkpoint_lsn(42).unwrap();
+            diskmgr.set_catalog_page_id(ids[0]).unwrap();
+            diskmgr.close().unwrap();
+            (ids[9], ids[3], ids[0])
+        };
+        assert!(matches!(
+            DiskMgrInternal::open(&path, OpenMode::CreateNew),
+            Err(DiskError::AlreadyExists(_))
+        ));
+
+        // reopening keeps the pages and the free space map
+        for mode in [OpenMode::OpenExisting, OpenMode::OpenOrCreate] {
+            let diskmgr = DiskMgrInternal::open(&path, mode).unwrap();
+            let mut buf = [0u8; PAGE_SIZE];
+            diskmgr.read_page(kept, &mut buf).unwrap();
+            assert_eq!(ioutil::from_buffer::<Song>(&buf).unwrap().id, song.id);
+            assert_eq!(diskmgr.get_checkpoint_lsn(), 42);
+            assert_eq!(diskmgr.get_catalog_page_id(), catalog);
+            assert_eq!(diskmgr.allocate_page().unwrap(), freed);
+            diskmgr.deallocate_page(freed).unwrap();
+        }
+    }
+
+    #[test]
+    fn refuse_foreign_files() {
+        let dir = crate::shared::cwd() + "/data/test/__diskmgr__/";
+        let foreign = dir.clone() + "foreign.bin";
+        let text = "not a database, just some notes ".repeat(200);
+        std::fs::write(&foreign, &text).unwrap();
+        for mode in [OpenMode::OpenExisting, OpenMode::OpenOrCreate] {
+            let err = DiskMgrInternal::open(&foreign, mode).err().unwrap();
+            assert!(matches!(err, DiskError::NotADatabase(_)));
+            assert!(err.to_string().contains("foreign.bin"));
+        }
+        // the file was left alone
+        assert_eq!(std::fs::read_to_string(&foreign).unwrap(), text);
+
+        let path = dir + "mismatch.bin";
+        let diskmgr = DiskMgrInternal::recreate(&path);
+        let header = FileHeader {
+            magic: MAGIC,
+            version: FORMAT_VERSION,
+            page_size: PAGE_SIZE as u32,
+            page_count: 1,
+            checkpoint_lsn: INVALID_LSN,
+            catalog_page_id: INVALID_PAGE_ID,
+        };
+        let cases = [
+            (
+                FileHeader {
+                    version: 99,
+                    ..header
+                },
+                "format version 99",
+            ),
+            (
+                FileHeader {
+                    page_size: 8192,
+                    ..header
+                },
+                "8192 byte pages",
+            ),
+        ];
+        for (bad, message) in cases {
+            diskmgr
+                .write_page(HEADER_ID as PageId, &ioutil::to_buffer(bad).unwrap())
+                .unwrap();
+            let err = DiskMgrInternal::open(&path, OpenMode::OpenExisting)
+                .err()
+                .unwrap();
+            assert!(err.to_string().contains(message), "{}", err);
+        }
+        diskmgr
+            .write_page(
+                HEADER_ID as PageId,
+                &ioutil::to_buffer(FileHeader {
+                    page_count: 0,
+                    ..header
+                })
+                .unwrap(),
+            )
+            .unwrap();
+        assert!(matches!(
+            DiskMgrInternal::open(&path, OpenMode::OpenExisting),
+            Err(DiskError::SizeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn trailing_pages_after_crash() {
+        let path = crate::shared::cwd() + "/data/test/__diskmgr__/trailing.bin";
+        let page_count = {
+            let diskmgr = DiskMgrInternal::recreate(&path);
+            for _ in 0..3 {
+                diskmgr.allocate_page().unwrap();
+            }
+            diskmgr.close().unwrap();
+            diskmgr.get_page_count()
+        };
+        // a process that died while growing the file left pages behind that the header does not count
+        {
+            let diskmgr = DiskMgrInternal::open(&path, OpenMode::OpenExisting).unwrap();
+            diskmgr
+                .write_page(page_count + 1, &[1u8; PAGE_SIZE])
+                .unwrap();
+        }
+        let diskmgr = DiskMgrInternal::open(&path, OpenMode::OpenExisting).unwrap();
+        assert_eq!(diskmgr.get_page_count(), page_count);
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            page_count as u64 * PAGE_SIZE as u64
+        );
+        assert!(diskmgr.allocate_page().unwrap() < page_count);
+
+        // dying while the file grows leaves a header that covers every page written
+        while diskmgr.allocate_page().unwrap() + 1 < diskmgr.get_page_count() {}
+        let faults = Arc::new(FaultInjector::default());
+        let diskmgr = diskmgr.with_faults(faults.clone());
+        faults.crash_after(1);
+        assert!(diskmgr.allocate_page().is_err());
+        drop(diskmgr);
+        let diskmgr = DiskMgrInternal::open(&path, OpenMode::OpenExisting).unwrap();
+        assert!(
+            std::fs::metadata(&path).unwrap().len()
+                <= diskmgr.get_page_count() as u64 * PAGE_SIZE as u64
+        );
+        diskmgr.allocate_page().unwrap();
+    }
 }
@@ -1,4 +1,215 @@
-use crate::concurrency::RwSynchronized;
-use std::collections::LinkedList;
+// SOURCES + USEFUL LINKS
+// https://www.1024cores.net/home/lock-free-algorithms/queues/bounded-mpmc-queue
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 
-pub type FreeList<T> = RwSynchronized<LinkedList<T>>;
+use crate::shared::FrameId;
+
+/// One slot of the ring
+struct Slot {
+    /// Equal to a position when the slot is free for the push at that position, and one past it once the frame pushed
+    /// there can be popped
+    seq: AtomicUsize,
+    frame: AtomicU32,
+}
+
+/// The frames of the buffer pool that hold no page, as a lock-free bounded ring. Frames are popped in the order they were
+/// pushed, like the locked list this replaced.
+///
+/// Pushes and pops each claim a position by advancing their own counter, and the sequence number of the slot at that
+/// position tells them whether it is ready: a push waits for the pop of the frame a lap earlier to leave the slot, and a
+/// pop for the push at its position to fill it. A frame id can only be on the list once, so the ring has room for every
+/// frame and a push never finds it full. It can still find a slot whose pop has claimed it but not yet left it, and
+/// waits for that pop to finish
+pub struct FreeList {
+    slots: Vec<Slot>,
+    /// `slots.len() - 1`, with `slots.len()` a power of two
+    mask: usize,
+    /// Position of the next push
+    tail: AtomicUsize,
+    /// Position of the next pop
+    head: AtomicUsize,
+    /// Frames the list is for, `0..frames`
+    frames: usize,
+}
+
+impl FreeList {
+    /// An empty list for the frames `0..capacity`
+    pub fn new(capacity: usize) -> Self {
+        assert!(
+            capacity < u32::MAX as usize,
+            "too many frames for a free list"
+        );
+        let len = capacity.max(1).next_power_of_two();
+        Self {
+            slots: (0..len)
+                .map(|pos| Slot {
+                    seq: AtomicUsize::new(pos),
+                    frame: AtomicU32::new(0),
+                })
+                .collect(),
+            mask: len - 1,
+            tail: AtomicUsize::new(0),
+            head: AtomicUsize::new(0),
+            frames: capacity,
+        }
+    }
+
+    /// A list holding every frame in `0..capacity`, which are popped in ascending order
+    pub fn full(capacity: usize) -> Self {
+        let list = Self::new(capacity);
+        for frame_id in 0..capacity {
+            list.push(frame_id as FrameId);
+        }
+        list
+    }
+
+    /// Put a frame at the back of the list. The frame must not already be on it
+    pub fn push(&self, frame_id: FrameId) {
+        assert!(
+            (frame_id as usize) < self.frames,
+            "frame {} is out of range",
+            frame_id
+        );
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        let slot = loop {
+            let slot = &self.slots[pos & self.mask];
+            let seq = slot.seq.load(Ordering::Acquire);
+            match (seq as isize).wrapping_sub(pos as isize) {
+                0 => match self.tail.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break slot,
+                    Err(current) => pos = current,
+                },
+                // the pop of the frame a lap ago has claimed the slot but not left it yet
+                lag if lag < 0 => std::thread::yield_now(),
+                _ => pos = self.tail.load(Ordering::Relaxed),
+            }
+        };
+        slot.frame.store(frame_id as u32, Ordering::Relaxed);
+        // release: whoever pops the frame sees it stored above
+        slot.seq.store(pos.wrapping_add(1), Ordering::Release);
+    }
+
+    /// Take the frame at the front of the list, or return None if it is empty
+    pub fn pop(&self) -> Option<FrameId> {
+        let mut pos = self.head.load(Ordering::Relaxed);
+        let slot = loop {
+            let slot = &self.slots[pos & self.mask];
+            let seq = slot.seq.load(Ordering::Acquire);
+            match (seq as isize).wrapping_sub(pos.wrapping_add(1) as isize) {
+                0 => match self.head.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break slot,
+                    Err(current) => pos = current,
+                },
+                // nothing has been pushed at this position yet, or the push has not finished
+                lag if lag < 0 => return None,
+                _ => pos = self.head.load(Ordering::Relaxed),
+            }
+        };
+        let frame_id = slot.frame.load(Ordering::Relaxed) as FrameId;
+        // release: the push a lap later sees the slot empty only after the frame was read
+        slot.seq
+            .store(pos.wrapping_add(self.mask + 1), Ordering::Release);
+        Some(frame_id)
+    }
+
+    /// Whether the list was empty when it was looked at
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        let head = self.head.load(Ordering::Acquire);
+        let seq = self.slots[head & self.mask].seq.load(Ordering::Acquire);
+        seq != head.wrapping_add(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use rand::Rng;
+
+    use super::FreeList;
+    use crate::shared::FrameId;
+
+    #[test]
+    fn push_pop() {
+        let list = FreeList::full(4);
+        assert_eq!(
+            (0..4).map(|_| list.pop().unwrap()).collect::<Vec<_>>(),
+            [0, 1, 2, 3]
+        );
+        assert!(list.is_empty());
+        assert_eq!(list.pop(), None);
+        list.push(2);
+        list.push(0);
+        assert!(!list.is_empty());
+        assert_eq!(
+            (list.pop(), list.pop(), list.pop()),
+            (Some(2), Some(0), None)
+        );
+        // frames come back in the order they were pushed, across many laps of the ring
+        for lap in 0..10 {
+            let order = [3, 1, 0, 2].map(|i| (i + lap) % 4);
+            for frame_id in order {
+                list.push(frame_id);
+            }
+            assert_eq!(order.map(|_| list.pop().unwrap()), order);
+        }
+
+        let empty = FreeList::new(8);
+        assert_eq!(empty.pop(), None);
+        empty.push(7);
+        assert_eq!(empty.pop(), Some(7));
+    }
+
+    /// Threads take frames off the list and put them back in random order, checking that no frame is ever handed to two
+    /// threads at once. The list is small, so pushes keep catching up with pops a lap ahead of them
+    #[test]
+    fn concurrent_allocate_release() {
+        const FRAMES: usize = 16;
+        const THREADS: usize = 8;
+        let list = FreeList::full(FRAMES);
+        let taken: Vec<AtomicBool> = (0..FRAMES).map(|_| AtomicBool::new(false)).collect();
+        std::thread::scope(|scope| {
+            for _ in 0..THREADS {
+                scope.spawn(|| {
+                    let mut rng = rand::thread_rng();
+                    let mut held: Vec<FrameId> = Vec::new();
+                    for _ in 0..100_000 {
+                        if held.len() < 4 && rng.gen_bool(0.5) {
+                            if let Some(frame_id) = list.pop() {
+                                let twice = taken[frame_id as usize].swap(true, Ordering::AcqRel);
+                                assert!(!twice, "frame {} was handed out twice", frame_id);
+                                held.push(frame_id);
+                            }
+                        } else if !held.is_empty() {
+                            let frame_id = held.swap_remove(rng.gen_range(0..held.len()));
+                            taken[frame_id as usize].store(false, Ordering::Release);
+                            list.push(frame_id);
+                        }
+                    }
+                    for frame_id in held {
+                        taken[frame_id as usize].store(false, Ordering::Release);
+                        list.push(frame_id);
+                    }
+                });
+            }
+        });
+        // every frame made it back exactly once
+        let mut frames = HashSet::new();
+        while let Some(frame_id) = list.pop() {
+            assert!(frames.insert(frame_id));
+        }
+        assert_eq!(frames.len(), FRAMES);
+    }
+}
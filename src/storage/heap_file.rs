@@ -0,0 +1,663 @@
+// SOURCES + USEFUL LINKS
+// https://github.com/cmu-db/bustub/blob/master/src/include/storage/table/table_heap.h
+// https://github.com/postgres/postgres/blob/master/src/backend/access/heap/README.HOT
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::ops::Deref;
+
+use serde::{Deserialize, Serialize};
+
+use crate::concurrency::rw_acquire_shared;
+use crate::shared::{PageId, INVALID_PAGE_ID, PAGE_SIZE};
+use crate::storage::bufmgr::{BufferPool, BufferPoolError, BufferPoolInternal};
+use crate::storage::ioutil;
+use crate::storage::objptr::ObjectPtr;
+use crate::storage::page::{Page, PageType, SlotId, SlottedPage, PAGE_LSN_SIZE};
+use crate::storage::page_guard::{ReadPageGuard, WritePageGuard};
+
+/// Records stored in a heap file are tagged so that a record moved off its home page can be found again
+const TAG_RECORD: u8 = 0;
+/// A record shorter than a forwarding pointer, padded so that it can later be replaced by one in place
+const TAG_SHORT: u8 = 1;
+/// Left in the home slot of a record that no longer fits its page
+const TAG_FORWARD: u8 = 2;
+/// A record living away from its home slot, prefixed with the home's `ObjectPtr`
+const TAG_MOVED: u8 = 3;
+
+const FORWARD_SIZE: usize = 1 + ObjectPtr::SIZE;
+
+/// Largest record that fits in an empty page once moved there with its home pointer
+pub const MAX_RECORD_SIZE: usize = PAGE_SIZE - Page::PAGE_HEADER_SIZE - 4 - FORWARD_SIZE;
+
+/// Errors returned by heap file operations
+#[derive(Debug)]
+pub enum HeapError {
+    /// The record is longer than `MAX_RECORD_SIZE`
+    RecordTooLarge(usize),
+    /// The pointer does not refer to a page of this heap file
+    InvalidPtr(ObjectPtr),
+    /// A page did not decode as the kind of heap page the file expected
+    Corrupt(PageId),
+    BufferPool(BufferPoolError),
+}
+
+pub type HeapResult<T> = Result<T, HeapError>;
+
+impl Display for HeapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeapError::RecordTooLarge(len) => write!(
+                f,
+                "record of {} bytes exceeds the maximum of {} bytes",
+                len, MAX_RECORD_SIZE
+            ),
+            HeapError::InvalidPtr(ptr) => write!(f, "{} is not a record of this heap file", ptr),
+            HeapError::Corrupt(page_id) => write!(f, "page {} is not a valid heap page", page_id),
+            HeapError::BufferPool(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for HeapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HeapError::BufferPool(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<BufferPoolError> for HeapError {
+    fn from(e: BufferPoolError) -> Self {
+        HeapError::BufferPool(e)
+    }
+}
+
+impl From<std::io::Error> for HeapError {
+    fn from(e: std::io::Error) -> Self {
+        HeapError::BufferPool(BufferPoolError::Io(e))
+    }
+}
+
+impl From<HeapError> for std::io::Error {
+    fn from(e: HeapError) -> Self {
+        match e {
+            HeapError::BufferPool(e) => e.into(),
+            e => std::io::Error::other(e),
+        }
+    }
+}
+
+/// A record as it is stored in a slot
+enum Stored<'a> {
+    Record(&'a [u8]),
+    Forward(ObjectPtr),
+    Moved(ObjectPtr, &'a [u8]),
+}
+
+impl<'a> Stored<'a> {
+    fn decode(bytes: &'a [u8]) -> Option<Self> {
+        let (&tag, rest) = bytes.split_first()?;
+        match tag {
+            TAG_RECORD => Some(Stored::Record(rest)),
+            TAG_SHORT => {
+                let (&len, rest) = rest.split_first()?;
+                rest.get(..len as usize).map(Stored::Record)
+            }
+            TAG_FORWARD if rest.len() >= ObjectPtr::SIZE => {
+                Some(Stored::Forward(ObjectPtr::from_bytes(rest)))
+            }
+            TAG_MOVED if rest.len() >= ObjectPtr::SIZE => Some(Stored::Moved(
+                ObjectPtr::from_bytes(rest),
+                &rest[ObjectPtr::SIZE..],
+            )),
+            _ => None,
+        }
+    }
+
+    /// Encode a record for its home slot. Every home slot is at least as long as a forwarding pointer
+    fn encode_record(record: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(FORWARD_SIZE.max(record.len() + 1));
+        if record.len() + 1 >= FORWARD_SIZE {
+            bytes.push(TAG_RECORD);
+            bytes.extend_from_slice(record);
+        } else {
+            bytes.push(TAG_SHORT);
+            bytes.push(record.len() as u8);
+            bytes.extend_from_slice(record);
+            bytes.resize(FORWARD_SIZE, 0);
+        }
+        bytes
+    }
+
+    fn encode_forward(target: ObjectPtr) -> Vec<u8> {
+        let mut bytes = vec![TAG_FORWARD];
+        bytes.extend_from_slice(&target.to_bytes());
+        bytes
+    }
+
+    fn encode_moved(home: ObjectPtr, record: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![TAG_MOVED];
+        bytes.extend_from_slice(&home.to_bytes());
+        bytes.extend_from_slice(record);
+        bytes
+    }
+}
+
+/// One page of the list of pages that make up a heap file, with a hint of how much space each page has left
+#[derive(Serialize, Deserialize)]
+struct HeapDirectoryPage {
+    next: PageId,
+    entries: Vec<HeapDirectoryEntry>,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone)]
+struct HeapDirectoryEntry {
+    page_id: PageId,
+    free_space: u16,
+}
+
+impl HeapDirectoryPage {
+    /// Entries that fit next to the page LSN, the link and the vector length
+    const CAPACITY: usize = (PAGE_SIZE - PAGE_LSN_SIZE - 16) / 10;
+
+    fn new() -> Self {
+        HeapDirectoryPage {
+            next: INVALID_PAGE_ID,
+            entries: Vec::new(),
+        }
+    }
+}
+
+/// An unordered collection of variable-length records spread over slotted pages. Records are addressed by `ObjectPtr`,
+/// which stays the same for the lifetime of the record: a record that grows too large for its page is moved and a
+/// forwarding pointer is left in its home slot.
+///
+/// The pages of the file are listed in a chain of directory pages starting at `directory_page_id`. Operations latch
+/// at most one heap page at a time, so they cannot deadlock with each other; an operation that has to touch two pages
+/// re-checks the first one after latching it again
+pub struct HeapFile {
+    bufmgr: BufferPool,
+    directory_page_id: PageId,
+}
+
+impl HeapFile {
+    /// Create an empty heap file, consisting of a single empty directory page
+    pub fn create(bufmgr: BufferPool) -> HeapResult<Self> {
+        let directory_page_id = {
+            let pool = rw_acquire_shared(&bufmgr);
+            let mut guard = pool.new_page_write()?;
+            ioutil::to_page(&HeapDirectoryPage::new(), &mut guard).unwrap();
+            guard.get_page_id()
+        };
+        Ok(Self::open(bufmgr, directory_page_id))
+    }
+
+    /// Open an existing heap file through its first directory page
+    pub fn open(bufmgr: BufferPool, directory_page_id: PageId) -> Self {
+        Self {
+            bufmgr,
+            directory_page_id,
+        }
+    }
+
+    #[inline]
+    pub fn get_directory_page_id(&self) -> PageId {
+        self.directory_page_id
+    }
+
+    pub fn insert(&self, record: &[u8]) -> HeapResult<ObjectPtr> {
+        if record.len() > MAX_RECORD_SIZE {
+            return Err(HeapError::RecordTooLarge(record.len()));
+        }
+        let pool = rw_acquire_shared(&self.bufmgr);
+        self.insert_stored(&pool, &Stored::encode_record(record))
+    }
+
+    pub fn get(&self, ptr: ObjectPtr) -> HeapResult<Option<Vec<u8>>> {
+        let pool = rw_acquire_shared(&self.bufmgr);
+        loop {
+            let target = {
+                let guard = self.fetch_read(&pool, ptr)?;
+                let page = SlottedPage::new(&*guard);
+                match Self::decode(&page, ptr)? {
+                    None | Some(Stored::Moved(..)) => return Ok(None),
+                    Some(Stored::Record(record)) => return Ok(Some(record.to_vec())),
+                    Some(Stored::Forward(target)) => target,
+                }
+            };
+            let guard = self.fetch_read(&pool, target)?;
+            let page = SlottedPage::new(&*guard);
+            if let Some(Stored::Moved(home, record)) = Self::decode(&page, target)? {
+                if home == ptr {
+                    return Ok(Some(record.to_vec()));
+                }
+            }
+            // the record moved again after its home slot was read
+        }
+    }
+
+    /// Replace a record, moving it to another page if it no longer fits. Returns false if there is no record at `ptr`
+    pub fn update(&self, ptr: ObjectPtr, record: &[u8]) -> HeapResult<bool> {
+        if record.len() > MAX_RECORD_SIZE {
+            return Err(HeapError::RecordTooLarge(record.len()));
+        }
+        let pool = rw_acquire_shared(&self.bufmgr);
+        loop {
+            // first try to update the record where it is
+            let (home, old_target) = {
+                let mut guard = self.fetch_write(&pool, ptr)?;
+                let mut page = SlottedPage::new(&mut *guard);
+                let home = match Self::decode(&page, ptr)? {
+                    None | Some(Stored::Moved(..)) => return Ok(false),
+                    Some(Stored::Record(_)) => {
+                        if page.update(ptr.get_slot(), &Stored::encode_record(record)) {
+                            return Ok(true);
+                        }
+                        None
+                    }
+                    Some(Stored::Forward(target)) => Some(target),
+                };
+                (page.get(ptr.get_slot()).unwrap().to_vec(), home)
+            };
+            if let Some(target) = old_target {
+                let mut guard = self.fetch_write(&pool, target)?;
+                let mut page = SlottedPage::new(&mut *guard);
+                if let Some(Stored::Moved(owner, _)) = Self::decode(&page, target)? {
+                    if owner == ptr
+                        && page.update(target.get_slot(), &Stored::encode_moved(ptr, record))
+                    {
+                        return Ok(true);
+                    }
+                }
+            }
+
+            // then move it: write the new copy, point the home slot at it, and drop the previous copy
+            let target = self.insert_stored(&pool, &Stored::encode_moved(ptr, record))?;
+            let installed = {
+                let mut guard = self.fetch_write(&pool, ptr)?;
+                let mut page = SlottedPage::new(&mut *guard);
+                page.get(ptr.get_slot()) == Some(home.as_slice())
+                    && page.update(ptr.get_slot(), &Stored::encode_forward(target))
+            };
+            if !installed {
+                // a concurrent update or delete got to the home slot first
+                self.delete_stored(&pool, target)?;
+                continue;
+            }
+            if let Some(old_target) = old_target {
+                self.delete_stored(&pool, old_target)?;
+            }
+            return Ok(true);
+        }
+    }
+
+    /// Remove a record. Returns false if there is no record at `ptr`
+    pub fn delete(&self, ptr: ObjectPtr) -> HeapResult<bool> {
+        let pool = rw_acquire_shared(&self.bufmgr);
+        let target = {
+            let mut guard = self.fetch_write(&pool, ptr)?;
+            let mut page = SlottedPage::new(&mut *guard);
+            let target = match Self::decode(&page, ptr)? {
+                None | Some(Stored::Moved(..)) => return Ok(false),
+                Some(Stored::Record(_)) => None,
+                Some(Stored::Forward(target)) => Some(target),
+            };
+            page.delete(ptr.get_slot());
+            target
+        };
+        self.note_free_space(&pool, ptr.get_page_id())?;
+        if let Some(target) = target {
+            self.delete_stored(&pool, target)?;
+        }
+        Ok(true)
+    }
+
+    /// Iterate over every record in the file, in no particular order. The scan reads one page at a time, so records
+    /// inserted, moved or deleted while it runs may or may not be seen
+    pub fn scan(&self) -> HeapScan<'_> {
+        HeapScan {
+            file: self,
+            directory_page_id: self.directory_page_id,
+            pages: VecDeque::new(),
+            records: VecDeque::new(),
+        }
+    }
+
+    fn fetch_read<'a>(
+        &self,
+        pool: &'a BufferPoolInternal,
+        ptr: ObjectPtr,
+    ) -> HeapResult<ReadPageGuard<'a>> {
+        if !ptr.is_valid() {
+            return Err(HeapError::InvalidPtr(ptr));
+        }
+        let guard = pool.fetch_page_read(ptr.get_page_id())?;
+        if SlottedPage::new(&*guard).get_page_type() != PageType::Heap {
+            return Err(HeapError::InvalidPtr(ptr));
+        }
+        Ok(guard)
+    }
+
+    fn fetch_write<'a>(
+        &self,
+        pool: &'a BufferPoolInternal,
+        ptr: ObjectPtr,
+    ) -> HeapResult<WritePageGuard<'a>> {
+        if !ptr.is_valid() {
+            return Err(HeapError::InvalidPtr(ptr));
+        }
+        let guard = pool.fetch_page_write(ptr.get_page_id())?;
+        if SlottedPage::new(&*guard).get_page_type() != PageType::Heap {
+            return Err(HeapError::InvalidPtr(ptr));
+        }
+        Ok(guard)
+    }
+
+    /// Decode the slot `ptr` refers to. None if the slot is empty
+    fn decode<'b, B: Deref<Target = [u8; PAGE_SIZE]>>(
+        page: &'b SlottedPage<B>,
+        ptr: ObjectPtr,
+    ) -> HeapResult<Option<Stored<'b>>> {
+        match page.get(ptr.get_slot()) {
+            None => Ok(None),
+            Some(bytes) => Stored::decode(bytes)
+                .map(Some)
+                .ok_or(HeapError::Corrupt(ptr.get_page_id())),
+        }
+    }
+
+    fn read_directory(pool: &BufferPoolInternal, page_id: PageId) -> HeapResult<HeapDirectoryPage> {
+        let guard = pool.fetch_page_read(page_id)?;
+        ioutil::from_page(&guard).ok_or(HeapError::Corrupt(page_id))
+    }
+
+    /// Store already encoded bytes on the first page with room for them, adding a page if there is none
+    fn insert_stored(&self, pool: &BufferPoolInternal, bytes: &[u8]) -> HeapResult<ObjectPtr> {
+        let needed = bytes.len() + 4;
+        let mut directory_page_id = self.directory_page_id;
+        loop {
+            let directory = Self::read_directory(pool, directory_page_id)?;
+            for entry in &directory.entries {
+                if (entry.free_space as usize) < needed {
+                    continue;
+                }
+                let slot = {
+                    let mut guard = pool.fetch_page_write(entry.page_id)?;
+                    SlottedPage::new(&mut *guard).insert(bytes)
+                };
+                // the hint is refreshed whether or not it was accurate
+                self.set_free_space(pool, directory_page_id, entry.page_id)?;
+                if let Some(slot) = slot {
+                    return Ok(ObjectPtr::new(entry.page_id, slot));
+                }
+            }
+            if directory.next == INVALID_PAGE_ID {
+                return self.append_page(pool, directory_page_id, bytes);
+            }
+            directory_page_id = directory.next;
+        }
+    }
+
+    /// Add a heap page holding `bytes` to the last directory page, moving right first if another insert got there before
+    fn append_page(
+        &self,
+        pool: &BufferPoolInternal,
+        mut directory_page_id: PageId,
+        bytes: &[u8],
+    ) -> HeapResult<ObjectPtr> {
+        let mut directory_guard = pool.fetch_page_write(directory_page_id)?;
+        let mut directory: HeapDirectoryPage =
+            ioutil::from_page(&directory_guard).ok_or(HeapError::Corrupt(directory_page_id))?;
+        while directory.next != INVALID_PAGE_ID {
+            directory_page_id = directory.next;
+            directory_guard = pool.fetch_page_write(directory_page_id)?;
+            directory =
+                ioutil::from_page(&directory_guard).ok_or(HeapError::Corrupt(directory_page_id))?;
+        }
+        if directory.entries.len() == HeapDirectoryPage::CAPACITY {
+            let mut next_guard = pool.new_page_write()?;
+            ioutil::to_page(&HeapDirectoryPage::new(), &mut next_guard).unwrap();
+            directory.next = next_guard.get_page_id();
+            ioutil::to_page(&directory, &mut directory_guard).unwrap();
+            directory_guard = next_guard;
+            directory = HeapDirectoryPage::new();
+        }
+
+        let mut guard = pool.new_page_write()?;
+        let page_id = guard.get_page_id();
+        let mut page = SlottedPage::init(&mut *guard, PageType::Heap);
+        let slot = page
+            .insert(bytes)
+            .expect("record does not fit in an empty page");
+        directory.entries.push(HeapDirectoryEntry {
+            page_id,
+            free_space: page.free_space() as u16,
+        });
+        ioutil::to_page(&directory, &mut directory_guard).unwrap();
+        Ok(ObjectPtr::new(page_id, slot))
+    }
+
+    /// Remove the copy of a moved record
+    fn delete_stored(&self, pool: &BufferPoolInternal, ptr: ObjectPtr) -> HeapResult<()> {
+        SlottedPage::new(&mut *self.fetch_write(pool, ptr)?).delete(ptr.get_slot());
+        self.note_free_space(pool, ptr.get_page_id())
+    }
+
+    /// Refresh the free space hint of a page whose directory page is not known
+    fn note_free_space(&self, pool: &BufferPoolInternal, page_id: PageId) -> HeapResult<()> {
+        let mut directory_page_id = self.directory_page_id;
+        while directory_page_id != INVALID_PAGE_ID {
+            let directory = Self::read_directory(pool, directory_page_id)?;
+            if directory
+                .entries
+                .iter()
+                .any(|entry| entry.page_id == page_id)
+            {
+                return self.set_free_space(pool, directory_page_id, page_id);
+            }
+            directory_page_id = directory.next;
+        }
+        Ok(())
+    }
+
+    /// Record how much space `page_id` has left. The page is read before the directory page is latched, so the hint
+    /// may be slightly stale by the time it is written
+    fn set_free_space(
+        &self,
+        pool: &BufferPoolInternal,
+        directory_page_id: PageId,
+        page_id: PageId,
+    ) -> HeapResult<()> {
+        let free_space = {
+            let guard = pool.fetch_page_read(page_id)?;
+            SlottedPage::new(&*guard).free_space() as u16
+        };
+        let mut guard = pool.fetch_page_write(directory_page_id)?;
+        let mut directory: HeapDirectoryPage =
+            ioutil::from_page(&guard).ok_or(HeapError::Corrupt(directory_page_id))?;
+        if let Some(entry) = directory
+            .entries
+            .iter_mut()
+            .find(|entry| entry.page_id == page_id)
+        {
+            entry.free_space = free_space;
+            ioutil::to_page(&directory, &mut guard).unwrap();
+        }
+        Ok(())
+    }
+}
+
+/// Iterator over the records of a heap file, created by `HeapFile::scan`. A moved record is returned under its home
+/// `ObjectPtr` when the scan reaches the page it was moved to
+pub struct HeapScan<'a> {
+    file: &'a HeapFile,
+    directory_page_id: PageId,
+    pages: VecDeque<PageId>,
+    records: VecDeque<(ObjectPtr, Vec<u8>)>,
+}
+
+impl HeapScan<'_> {
+    /// Buffer the records of the next page, or the page ids of the next directory page. Returns false at the end
+    fn advance(&mut self) -> HeapResult<bool> {
+        let pool = rw_acquire_shared(&self.file.bufmgr);
+        if let Some(page_id) = self.pages.pop_front() {
+            let guard = pool.fetch_page_read(page_id)?;
+            let page = SlottedPage::new(&*guard);
+            for (slot, bytes) in page.iter() {
+                match Stored::decode(bytes).ok_or(HeapError::Corrupt(page_id))? {
+                    Stored::Record(record) => self
+                        .records
+                        .push_back((ObjectPtr::new(page_id, slot as SlotId), record.to_vec())),
+                    Stored::Moved(home, record) => self.records.push_back((home, record.to_vec())),
+                    Stored::Forward(_) => {}
+                }
+            }
+            return Ok(true);
+        }
+        if self.directory_page_id == INVALID_PAGE_ID {
+            return Ok(false);
+        }
+        let directory = HeapFile::read_directory(&pool, self.directory_page_id)?;
+        self.pages
+            .extend(directory.entries.iter().map(|entry| entry.page_id));
+        self.directory_page_id = directory.next;
+        Ok(true)
+    }
+}
+
+impl Iterator for HeapScan<'_> {
+    type Item = HeapResult<(ObjectPtr, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.records.is_empty() {
+            match self.advance() {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        self.records.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::storage::bufmgr::BufferPoolInternal;
+    use crate::storage::diskmgr::DiskMgrInternal;
+
+    fn make_heap(name: &str, pool_size: usize) -> HeapFile {
+        let diskmgr = Arc::new(parking_lot::RwLock::new(DiskMgrInternal::recreate(
+            &(crate::shared::cwd() + "/data/test/__heap_file__/" + name),
+        )));
+        let bufmgr = Arc::new(parking_lot::RwLock::new(BufferPoolInternal::new(
+            pool_size, 2, diskmgr,
+        )));
+        HeapFile::create(bufmgr).unwrap()
+    }
+
+    fn record(i: usize) -> Vec<u8> {
+        format!("row-{}-{}", i, "x".repeat(i % 97)).into_bytes()
+    }
+
+    #[test]
+    fn insert_get_delete() {
+        let heap = make_heap("insert_get_delete.bin", 8);
+        let ptrs: Vec<ObjectPtr> = (0..2000)
+            .map(|i| heap.insert(&record(i)).unwrap())
+            .collect();
+        // many small records share a page
+        assert!(
+            ptrs.iter()
+                .filter(|ptr| ptr.get_page_id() == ptrs[0].get_page_id())
+                .count()
+                > 10
+        );
+        for (i, &ptr) in ptrs.iter().enumerate() {
+            assert_eq!(heap.get(ptr).unwrap(), Some(record(i)));
+        }
+        for &ptr in ptrs.iter().step_by(2) {
+            assert!(heap.delete(ptr).unwrap());
+            assert!(!heap.delete(ptr).unwrap());
+            assert_eq!(heap.get(ptr).unwrap(), None);
+        }
+        // the space freed by deletes is reused before the file grows
+        let reused = heap.insert(b"reused").unwrap();
+        assert!(ptrs
+            .iter()
+            .any(|ptr| ptr.get_page_id() == reused.get_page_id()));
+
+        assert!(heap.insert(b"").is_ok());
+        assert!(matches!(
+            heap.insert(&vec![0u8; MAX_RECORD_SIZE + 1]),
+            Err(HeapError::RecordTooLarge(_))
+        ));
+        assert!(heap.insert(&vec![0u8; MAX_RECORD_SIZE]).is_ok());
+        assert!(matches!(
+            heap.get(ObjectPtr::default()),
+            Err(HeapError::InvalidPtr(_))
+        ));
+        assert!(matches!(
+            heap.get(ObjectPtr::new(heap.get_directory_page_id(), 0)),
+            Err(HeapError::InvalidPtr(_))
+        ));
+    }
+
+    #[test]
+    fn update_moves_records() {
+        let heap = make_heap("update_moves_records.bin", 8);
+        let ptrs: Vec<ObjectPtr> = (0..200).map(|i| heap.insert(&record(i)).unwrap()).collect();
+        // shrinking and growing within the page keep the record in place
+        assert!(heap.update(ptrs[0], b"tiny").unwrap());
+        assert_eq!(heap.get(ptrs[0]).unwrap(), Some(b"tiny".to_vec()));
+        assert!(heap.update(ptrs[0], &record(0)).unwrap());
+
+        // records too large for their page move, but keep their pointer
+        let big = vec![7u8; 3000];
+        for &ptr in ptrs.iter().take(20) {
+            assert!(heap.update(ptr, &big).unwrap());
+            assert_eq!(heap.get(ptr).unwrap(), Some(big.clone()));
+        }
+        // and can be updated again, shrunk, or deleted through the same pointer
+        assert!(heap.update(ptrs[1], &vec![8u8; 3500]).unwrap());
+        assert_eq!(heap.get(ptrs[1]).unwrap(), Some(vec![8u8; 3500]));
+        assert!(heap.update(ptrs[2], b"small again").unwrap());
+        assert_eq!(heap.get(ptrs[2]).unwrap(), Some(b"small again".to_vec()));
+        assert!(heap.delete(ptrs[3]).unwrap());
+        assert_eq!(heap.get(ptrs[3]).unwrap(), None);
+        assert!(!heap.update(ptrs[3], b"gone").unwrap());
+
+        // a scan sees every record exactly once, under its original pointer
+        let scanned: HashMap<ObjectPtr, Vec<u8>> = heap.scan().map(|item| item.unwrap()).collect();
+        assert_eq!(scanned.len(), 199);
+        for (i, &ptr) in ptrs.iter().enumerate() {
+            assert_eq!(
+                scanned.get(&ptr).cloned(),
+                heap.get(ptr).unwrap(),
+                "record {}",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn scan_many_pages() {
+        let heap = make_heap("scan_many_pages.bin", 16);
+        // enough pages to chain a second directory page
+        let count = HeapDirectoryPage::CAPACITY + 10;
+        let ptrs: Vec<ObjectPtr> = (0..count)
+            .map(|i| heap.insert(&vec![(i % 251) as u8; 3000]).unwrap())
+            .collect();
+        let directory = {
+            let pool = rw_acquire_shared(&heap.bufmgr);
+            HeapFile::read_directory(&pool, heap.get_directory_page_id()).unwrap()
+        };
+        assert_ne!(directory.next, INVALID_PAGE_ID);
+        let scanned: Vec<ObjectPtr> = heap.scan().map(|item| item.unwrap().0).collect();
+        assert_eq!(scanned, ptrs);
+    }
+}
@@ -1,8 +1,373 @@
 // SOURCES + USEFUL LINKS
 // https://github.com/cmu-db/bustub/blob/master/src/include/storage/page/b_plus_tree_page.h
+// https://www.csd.uoc.gr/~hy460/pdf/p650-lehman.pdf (Efficient Locking for Concurrent Operations on B-Trees)
+// https://dl.acm.org/doi/pdf/10.5555/324493.324589 (A Symmetric Concurrent B-Tree Algorithm)
 #![allow(dead_code, unused_imports)]
 
-use crate::shared::PageId;
+use serde::{Deserialize, Serialize};
+
+use crate::shared::{PageId, INVALID_PAGE_ID, PAGE_SIZE};
+use crate::storage::ioutil;
 use crate::storage::objptr::ObjectPtr;
+use crate::storage::page::PAGE_LSN_SIZE;
+
+/// Keys larger than this are refused so that any split of a full node produces two halves that fit in a page
+pub const MAX_KEY_SIZE: usize = PAGE_SIZE / 8;
+
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+pub enum IndexPageType {
+    Meta,
+    Internal,
+    Leaf,
+}
+
+/// The anchor of a B-link tree. It never moves, so the tree is identified by the id of its meta page while the root is
+/// free to change when it splits
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IndexMetaPage {
+    pub page_type: IndexPageType,
+    pub root: PageId,
+    /// Number of levels in the tree. A tree whose root is a leaf has height 1
+    pub height: u32,
+}
+
+impl IndexMetaPage {
+    pub fn new(root: PageId) -> Self {
+        Self {
+            page_type: IndexPageType::Meta,
+            root,
+            height: 1,
+        }
+    }
+
+    pub fn from_bytes(buf: &[u8; PAGE_SIZE]) -> Option<Self> {
+        ioutil::from_page::<Self>(buf).filter(|meta| meta.page_type == IndexPageType::Meta)
+    }
+
+    /// Serialize the meta page into a page buffer, leaving the page LSN alone
+    pub fn write_to(&self, buf: &mut [u8; PAGE_SIZE]) {
+        ioutil::to_page(self, buf).unwrap();
+    }
+}
+
+/// A B-link tree node. Every node covers the key range `(low_key, high_key]` and carries a link to its right sibling. A key
+/// larger than the high key means the node was split after the caller read its parent, and the key now lives somewhere to
+/// the right. A key at or below the low key means entries were moved out of this node to its left neighbour, and the
+/// caller has to search again from the root.
+///
+/// When a node is merged into its left neighbour it is marked deleted and its outlink points at that neighbour, which now
+/// covers the deleted node's range. The page is only reclaimed once no operation can still be on its way to it.
+///
+/// Leaves hold sorted `keys` with matching `values`. Internal nodes hold `children`, one more than `keys`: child `i`
+/// covers keys in `(keys[i - 1], keys[i]]` and the last child is bounded by the high key
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IndexPage {
+    pub page_type: IndexPageType,
+    /// Distance from the leaves. Leaves are level 0
+    pub level: u32,
+    /// None for the leftmost node of a level, which is unbounded
+    pub low_key: Option<Vec<u8>>,
+    /// None for the rightmost node of a level, which is unbounded
+    pub high_key: Option<Vec<u8>>,
+    pub right_link: PageId,
+    pub deleted: bool,
+    /// For a deleted node, the node that absorbed its entries
+    pub outlink: PageId,
+    pub keys: Vec<Vec<u8>>,
+    pub values: Vec<ObjectPtr>,
+    pub children: Vec<PageId>,
+}
+
+impl IndexPage {
+    pub fn new_leaf() -> Self {
+        Self {
+            page_type: IndexPageType::Leaf,
+            level: 0,
+            low_key: None,
+            high_key: None,
+            right_link: INVALID_PAGE_ID,
+            deleted: false,
+            outlink: INVALID_PAGE_ID,
+            keys: Vec::new(),
+            values: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    /// An internal node without children, to be filled by the caller
+    pub fn new_internal(level: u32) -> Self {
+        Self {
+            page_type: IndexPageType::Internal,
+            level,
+            ..Self::new_leaf()
+        }
+    }
+
+    /// A root over two children separated by `separator`, the high key of `left`
+    pub fn new_root(level: u32, left: PageId, separator: Vec<u8>, right: PageId) -> Self {
+        Self {
+            page_type: IndexPageType::Internal,
+            level,
+            low_key: None,
+            high_key: None,
+            right_link: INVALID_PAGE_ID,
+            deleted: false,
+            outlink: INVALID_PAGE_ID,
+            keys: vec![separator],
+            values: Vec::new(),
+            children: vec![left, right],
+        }
+    }
+
+    pub fn from_bytes(buf: &[u8; PAGE_SIZE]) -> Option<Self> {
+        ioutil::from_page::<Self>(buf).filter(|page| page.page_type != IndexPageType::Meta)
+    }
+
+    /// Serialize the node into a page buffer, leaving the page LSN alone. The caller must have checked `fits`
+    pub fn write_to(&self, buf: &mut [u8; PAGE_SIZE]) {
+        ioutil::to_page(self, buf).expect("index page does not fit in a page");
+    }
+
+    #[inline]
+    pub fn is_leaf(&self) -> bool {
+        self.page_type == IndexPageType::Leaf
+    }
+
+    pub fn encoded_size(&self) -> usize {
+        bincode::serialized_size(self).unwrap() as usize
+    }
+
+    #[inline]
+    pub fn fits(&self) -> bool {
+        self.encoded_size() <= PAGE_SIZE - PAGE_LSN_SIZE
+    }
+
+    /// A node below a quarter of a page is a candidate for merging or redistribution
+    #[inline]
+    pub fn is_underfull(&self) -> bool {
+        self.encoded_size() < PAGE_SIZE / 4
+    }
+
+    /// True if entries covering `key` were moved to the left of this node, where a search cannot follow them
+    #[inline]
+    pub fn is_left_of(&self, key: &[u8]) -> bool {
+        match &self.low_key {
+            Some(low_key) => key <= low_key.as_slice(),
+            None => false,
+        }
+    }
+
+    /// True if `key` is beyond this node's range, i.e. the search has to continue at the right sibling
+    #[inline]
+    pub fn must_move_right(&self, key: &[u8]) -> bool {
+        match &self.high_key {
+            Some(high_key) => key > high_key.as_slice() && self.right_link != INVALID_PAGE_ID,
+            None => false,
+        }
+    }
+
+    /// The child of an internal node whose subtree covers `key`
+    pub fn child_for(&self, key: &[u8]) -> PageId {
+        debug_assert!(!self.is_leaf());
+        let idx = self.keys.partition_point(|k| k.as_slice() < key);
+        self.children[idx]
+    }
+
+    /// Position of `key` in a leaf, or where it would be inserted
+    #[inline]
+    pub fn search(&self, key: &[u8]) -> Result<usize, usize> {
+        self.keys.binary_search_by(|k| k.as_slice().cmp(key))
+    }
+
+    pub fn lookup(&self, key: &[u8]) -> Option<ObjectPtr> {
+        debug_assert!(self.is_leaf());
+        self.search(key).ok().map(|idx| self.values[idx])
+    }
+
+    /// Insert into a leaf. Returns false if the key is already present
+    pub fn insert_value(&mut self, key: &[u8], value: ObjectPtr) -> bool {
+        debug_assert!(self.is_leaf());
+        match self.search(key) {
+            Ok(_) => false,
+            Err(idx) => {
+                self.keys.insert(idx, key.to_vec());
+                self.values.insert(idx, value);
+                true
+            }
+        }
+    }
+
+    /// Remove a key from a leaf, returning its value if it was present
+    pub fn remove_value(&mut self, key: &[u8]) -> Option<ObjectPtr> {
+        debug_assert!(self.is_leaf());
+        let idx = self.search(key).ok()?;
+        self.keys.remove(idx);
+        Some(self.values.remove(idx))
+    }
+
+    /// Forget the child at `idx + 1`, whose entries were merged into the child at `idx`. The separator between the two
+    /// goes with it
+    pub fn remove_child(&mut self, idx: usize) {
+        debug_assert!(!self.is_leaf());
+        self.keys.remove(idx);
+        self.children.remove(idx + 1);
+    }
+
+    /// Take over every entry of the right sibling, which is separated from this node by `separator`, along with its high
+    /// key and right link. The mirror image of `split`
+    pub fn absorb(&mut self, right: &IndexPage, separator: Vec<u8>) {
+        debug_assert_eq!(self.high_key.as_ref(), Some(&separator));
+        if self.is_leaf() {
+            self.keys.extend(right.keys.iter().cloned());
+            self.values.extend(right.values.iter().copied());
+        } else {
+            self.keys.push(separator);
+            self.keys.extend(right.keys.iter().cloned());
+            self.children.extend(right.children.iter().copied());
+        }
+        self.high_key = right.high_key.clone();
+        self.right_link = right.right_link;
+    }
+
+    /// Mark a node that was merged into `outlink` as deleted
+    pub fn mark_deleted(&mut self, outlink: PageId) {
+        self.deleted = true;
+        self.outlink = outlink;
+        self.keys.clear();
+        self.values.clear();
+        self.children.clear();
+    }
+
+    /// Record in an internal node that the child covering `separator` was split, and that `right` now holds the keys
+    /// above `separator`
+    pub fn insert_child(&mut self, separator: Vec<u8>, right: PageId) {
+        debug_assert!(!self.is_leaf());
+        let idx = self.keys.partition_point(|k| *k < separator);
+        self.keys.insert(idx, separator);
+        self.children.insert(idx + 1, right);
+    }
+
+    /// Move the upper half of the node, by encoded size, into a new right sibling. Returns the sibling and the separator,
+    /// which becomes this node's new high key. The caller links the two nodes together
+    pub fn split(&mut self) -> (IndexPage, Vec<u8>) {
+        let mut right = IndexPage {
+            page_type: self.page_type,
+            level: self.level,
+            low_key: None,
+            high_key: self.high_key.take(),
+            right_link: self.right_link,
+            deleted: false,
+            outlink: INVALID_PAGE_ID,
+            keys: Vec::new(),
+            values: Vec::new(),
+            children: Vec::new(),
+        };
+        let separator = if self.is_leaf() {
+            let mid = self.split_point(self.keys.len());
+            right.keys = self.keys.split_off(mid);
+            right.values = self.values.split_off(mid);
+            self.keys.last().unwrap().clone()
+        } else {
+            // the left node keeps `mid` children and the key between the halves moves up as the separator
+            let mid = self.split_point(self.children.len());
+            right.keys = self.keys.split_off(mid);
+            right.children = self.children.split_off(mid);
+            self.keys.pop().unwrap()
+        };
+        self.high_key = Some(separator.clone());
+        right.low_key = Some(separator.clone());
+        (right, separator)
+    }
+
+    /// Number of entries to leave in the left half so both halves hold about the same number of bytes. Always leaves at
+    /// least one entry on either side
+    fn split_point(&self, entries: usize) -> usize {
+        debug_assert!(entries >= 2);
+        let total: usize = self.keys.iter().map(|k| k.len() + 16).sum();
+        let mut acc = 0;
+        for (idx, key) in self.keys.iter().enumerate() {
+            acc += key.len() + 16;
+            if acc * 2 >= total {
+                return (idx + 1).clamp(1, entries - 1);
+            }
+        }
+        entries - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::page::SlotId;
+
+    fn ptr(i: usize) -> ObjectPtr {
+        ObjectPtr::new(i as PageId, (i % 64) as SlotId)
+    }
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key-{:06}", i).into_bytes()
+    }
+
+    #[test]
+    fn leaf_split() {
+        let mut leaf = IndexPage::new_leaf();
+        leaf.high_key = Some(key(1000));
+        leaf.right_link = 42;
+        for i in (0..100).rev() {
+            assert!(leaf.insert_value(&key(i), ptr(i)));
+        }
+        assert!(!leaf.insert_value(&key(7), ptr(7)));
+        assert_eq!(leaf.lookup(&key(7)), Some(ptr(7)));
+
+        let (right, separator) = leaf.split();
+        assert_eq!(leaf.high_key.as_ref(), Some(&separator));
+        assert_eq!(leaf.keys.last(), Some(&separator));
+        assert_eq!(right.high_key, Some(key(1000)));
+        assert_eq!(right.right_link, 42);
+        assert_eq!(leaf.keys.len() + right.keys.len(), 100);
+        assert!(right.keys[0] > separator);
+        assert!(leaf.must_move_right(&right.keys[0]));
+        assert!(right.is_left_of(&separator));
+
+        // absorbing the right half restores the original node
+        let mut merged = leaf.clone();
+        merged.absorb(&right, separator.clone());
+        assert_eq!(merged.keys.len(), 100);
+        assert_eq!(merged.high_key, Some(key(1000)));
+        assert_eq!(merged.right_link, 42);
+        assert_eq!(merged.remove_value(&key(99)), Some(ptr(99)));
+        assert_eq!(merged.remove_value(&key(99)), None);
+    }
+
+    #[test]
+    fn internal_split() {
+        let mut node = IndexPage::new_root(1, 0, key(0), 1);
+        for i in 1..50 {
+            node.insert_child(key(i * 10), i as PageId + 1);
+        }
+        assert_eq!(node.children.len(), node.keys.len() + 1);
+        assert_eq!(node.child_for(&key(5)), 1);
+        assert_eq!(node.child_for(&key(10)), 1);
+        assert_eq!(node.child_for(&key(11)), 2);
+
+        let (right, separator) = node.split();
+        let mut merged = node.clone();
+        merged.absorb(&right, separator.clone());
+        assert_eq!(merged.children.len(), 51);
+        assert_eq!(merged.keys.len(), 50);
+        merged.remove_child(0);
+        assert_eq!(merged.child_for(&key(5)), 0);
+        assert_eq!(node.children.len(), node.keys.len() + 1);
+        assert_eq!(right.children.len(), right.keys.len() + 1);
+        assert_eq!(node.children.len() + right.children.len(), 51);
+        assert!(node.keys.iter().all(|k| *k < separator));
+        assert!(right.keys.iter().all(|k| *k > separator));
 
-pub struct IndexPage {}
+        let mut buf = [0u8; PAGE_SIZE];
+        right.write_to(&mut buf);
+        let decoded = IndexPage::from_bytes(&buf).unwrap();
+        assert_eq!(decoded.keys, right.keys);
+        assert_eq!(decoded.children, right.children);
+        assert!(IndexMetaPage::from_bytes(&buf).is_none());
+    }
+}
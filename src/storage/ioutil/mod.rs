@@ -4,6 +4,7 @@ use serde::de::DeserializeOwned;
 use serde::Serialize;
 
 use crate::shared::PAGE_SIZE;
+use crate::storage::page::PAGE_LSN_SIZE;
 
 /// Used to encode a generic item to a vector of u8s as long as it implements the Sized and Serialize traits
 pub fn encode<T>(item: T) -> Option<Vec<u8>>
@@ -34,7 +35,7 @@ where
 {
     if let Some(encoded) = encode(item) {
         let mut buf = [0u8; PAGE_SIZE];
-        buf[..std::mem::size_of_val(&*encoded)].copy_from_slice(&*encoded);
+        buf[..std::mem::size_of_val(&*encoded)].copy_from_slice(&encoded);
         return Some(buf);
     }
     None
@@ -48,6 +49,30 @@ where
     decode::<T>(buf.to_vec())
 }
 
+/// Encode an item into a page after the page LSN, which occupies the first `PAGE_LSN_SIZE` bytes and is left untouched.
+/// The rest of the page is zeroed. Returns None if the item does not fit
+pub fn to_page<T>(item: &T, buf: &mut [u8; PAGE_SIZE]) -> Option<()>
+where
+    T: Sized + Serialize,
+{
+    let encoded = bincode::serialize(item).ok()?;
+    let body = &mut buf[PAGE_LSN_SIZE..];
+    if encoded.len() > body.len() {
+        return None;
+    }
+    body[..encoded.len()].copy_from_slice(&encoded);
+    body[encoded.len()..].fill(0);
+    Some(())
+}
+
+/// Decode an item written by `to_page`
+pub fn from_page<T>(buf: &[u8; PAGE_SIZE]) -> Option<T>
+where
+    T: Sized + Serialize + DeserializeOwned,
+{
+    bincode::deserialize(&buf[PAGE_LSN_SIZE..]).ok()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,253 @@
+// SOURCES + USEFUL LINKS
+// https://github.com/facebook/mysql-5.6/wiki/MyRocks-record-format#memcomparable-format
+// https://github.com/cockroachdb/cockroach/blob/master/pkg/util/encoding/encoding.go
+use crate::storage::catalog::KeyType;
+
+/// Ends an encoded byte string. A zero byte inside the string is written as `ESCAPE_ZERO`
+const TERMINATOR: [u8; 2] = [0x00, 0x01];
+const ESCAPE_ZERO: [u8; 2] = [0x00, 0xff];
+
+/// A key type with an order-preserving ("memcomparable") encoding: comparing two encoded keys byte by byte gives the
+/// same result as comparing the keys themselves. Encodings are self-delimiting, so the encodings of the components of a
+/// tuple can simply be concatenated
+pub trait KeyCodec: Sized {
+    /// How the catalog describes this key type
+    fn key_type() -> KeyType;
+
+    /// Append the encoding of the key to `buf`
+    fn encode_to(&self, buf: &mut Vec<u8>);
+
+    /// Decode a key from the start of `input`, and advance `input` past it
+    fn decode_from(input: &mut &[u8]) -> Option<Self>;
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_to(&mut buf);
+        buf
+    }
+
+    /// Decode a key that takes up all of `bytes`
+    fn decode(mut bytes: &[u8]) -> Option<Self> {
+        let key = Self::decode_from(&mut bytes)?;
+        bytes.is_empty().then_some(key)
+    }
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if input.len() < len {
+        return None;
+    }
+    let (taken, rest) = input.split_at(len);
+    *input = rest;
+    Some(taken)
+}
+
+/// Unsigned integers are stored big-endian, so the most significant byte is compared first
+macro_rules! unsigned_codec {
+    ($t:ty, $key_type:expr) => {
+        impl KeyCodec for $t {
+            fn key_type() -> KeyType {
+                $key_type
+            }
+
+            fn encode_to(&self, buf: &mut Vec<u8>) {
+                buf.extend_from_slice(&self.to_be_bytes());
+            }
+
+            fn decode_from(input: &mut &[u8]) -> Option<Self> {
+                let bytes = take(input, std::mem::size_of::<$t>())?;
+                Some(<$t>::from_be_bytes(bytes.try_into().unwrap()))
+            }
+        }
+    };
+}
+
+/// Signed integers have their sign bit flipped, which moves the negative numbers below the positive ones, and are then
+/// stored like unsigned integers
+macro_rules! signed_codec {
+    ($t:ty, $unsigned:ty, $key_type:expr) => {
+        impl KeyCodec for $t {
+            fn key_type() -> KeyType {
+                $key_type
+            }
+
+            fn encode_to(&self, buf: &mut Vec<u8>) {
+                let flipped = (*self as $unsigned) ^ (1 << (<$unsigned>::BITS - 1));
+                buf.extend_from_slice(&flipped.to_be_bytes());
+            }
+
+            fn decode_from(input: &mut &[u8]) -> Option<Self> {
+                let bytes = take(input, std::mem::size_of::<$t>())?;
+                let flipped = <$unsigned>::from_be_bytes(bytes.try_into().unwrap());
+                Some((flipped ^ (1 << (<$unsigned>::BITS - 1))) as $t)
+            }
+        }
+    };
+}
+
+unsigned_codec!(u32, KeyType::UInt32);
+unsigned_codec!(u64, KeyType::UInt64);
+signed_codec!(i32, u32, KeyType::Int32);
+signed_codec!(i64, u64, KeyType::Int64);
+
+fn encode_bytes(bytes: &[u8], buf: &mut Vec<u8>) {
+    for &byte in bytes {
+        match byte {
+            0 => buf.extend_from_slice(&ESCAPE_ZERO),
+            byte => buf.push(byte),
+        }
+    }
+    buf.extend_from_slice(&TERMINATOR);
+}
+
+/// Byte strings are terminated by `00 01`, with every zero byte inside them escaped as `00 ff`. A string that is a prefix
+/// of another ends with `00 01` where the longer one continues with a byte that is either non-zero or `00 ff`, so it
+/// sorts first, as it should
+impl KeyCodec for Vec<u8> {
+    fn key_type() -> KeyType {
+        KeyType::Bytes
+    }
+
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        encode_bytes(self, buf);
+    }
+
+    fn decode_from(input: &mut &[u8]) -> Option<Self> {
+        let mut bytes = Vec::new();
+        loop {
+            match *take(input, 1)? {
+                [0] => match *take(input, 1)? {
+                    [0x01] => return Some(bytes),
+                    [0xff] => bytes.push(0),
+                    _ => return None,
+                },
+                [byte] => bytes.push(byte),
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+/// Strings are encoded as their UTF-8 bytes, whose byte order is the order of their code points
+impl KeyCodec for String {
+    fn key_type() -> KeyType {
+        KeyType::String
+    }
+
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        encode_bytes(self.as_bytes(), buf);
+    }
+
+    fn decode_from(input: &mut &[u8]) -> Option<Self> {
+        String::from_utf8(Vec::<u8>::decode_from(input)?).ok()
+    }
+}
+
+/// Tuples are ordered by their first component, then their second and so on, which is what concatenating the encodings
+/// of the components gives
+macro_rules! tuple_codec {
+    ($($name:ident),+) => {
+        impl<$($name: KeyCodec),+> KeyCodec for ($($name,)+) {
+            fn key_type() -> KeyType {
+                KeyType::Tuple(vec![$($name::key_type()),+])
+            }
+
+            #[allow(non_snake_case)]
+            fn encode_to(&self, buf: &mut Vec<u8>) {
+                let ($($name,)+) = self;
+                $($name.encode_to(buf);)+
+            }
+
+            fn decode_from(input: &mut &[u8]) -> Option<Self> {
+                Some(($($name::decode_from(input)?,)+))
+            }
+        }
+    };
+}
+
+tuple_codec!(A, B);
+tuple_codec!(A, B, C);
+tuple_codec!(A, B, C, D);
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::KeyCodec;
+    use crate::storage::catalog::KeyType;
+
+    /// Every pair of keys compares the same way before and after encoding, and every key decodes to itself
+    fn check_order<K: KeyCodec + Ord + Clone + std::fmt::Debug>(mut keys: Vec<K>) {
+        keys.sort();
+        keys.dedup();
+        let encoded: Vec<Vec<u8>> = keys.iter().map(|key| key.encode()).collect();
+        for (key, bytes) in keys.iter().zip(&encoded) {
+            assert_eq!(K::decode(bytes).as_ref(), Some(key));
+        }
+        for (i, a) in encoded.iter().enumerate() {
+            for (j, b) in encoded.iter().enumerate() {
+                assert_eq!(a.cmp(b), i.cmp(&j), "{:?} vs {:?}", keys[i], keys[j]);
+            }
+        }
+    }
+
+    #[test]
+    fn integers() {
+        let mut rng = rand::thread_rng();
+        let mut signed: Vec<i64> = vec![i64::MIN, -256, -1, 0, 1, 255, 256, i64::MAX];
+        signed.extend((0..200).map(|_| rng.gen::<i64>() >> rng.gen_range(0..63)));
+        check_order(signed.clone());
+        check_order(signed.iter().map(|&i| i as i32).collect());
+        let mut unsigned: Vec<u64> = vec![0, 1, 255, 256, u64::MAX];
+        unsigned.extend((0..200).map(|_| rng.gen::<u64>() >> rng.gen_range(0..64)));
+        check_order(unsigned.clone());
+        check_order(unsigned.iter().map(|&i| i as u32).collect());
+        // little-endian bincode gets this wrong
+        assert!(256u32.encode() > 1u32.encode());
+        assert!((-1i32).encode() < 0i32.encode());
+    }
+
+    #[test]
+    fn strings() {
+        let bytes: Vec<Vec<u8>> = vec![
+            vec![],
+            vec![0],
+            vec![0, 0],
+            vec![0, 1],
+            vec![0, 0xff],
+            vec![1],
+            vec![0xff],
+            vec![0xff, 0],
+            b"abc".to_vec(),
+            b"ab".to_vec(),
+            b"ab\0c".to_vec(),
+        ];
+        check_order(bytes);
+        let strings: Vec<String> = ["", "a", "ab", "abc", "b", "a\0", "é", "z", "ÿ"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        check_order(strings);
+        assert_eq!(Vec::<u8>::decode(&[1, 0]), None);
+        assert_eq!(Vec::<u8>::decode(&[1, 0, 1, 7]), None);
+    }
+
+    #[test]
+    fn tuples() {
+        let mut keys = Vec::new();
+        for artist in ["", "Lorde", "Lorde Jr", "The Neighbourhood"] {
+            for id in [-3i32, 0, 7, 1000] {
+                keys.push((artist.to_string(), id));
+            }
+        }
+        check_order(keys);
+        let nested: Vec<(u64, Vec<u8>, i64)> = (0..50)
+            .map(|i| (i % 3, vec![i as u8 % 2; (i % 4) as usize], -(i as i64)))
+            .collect();
+        check_order(nested);
+        assert_eq!(
+            <(String, i32)>::key_type(),
+            KeyType::Tuple(vec![KeyType::String, KeyType::Int32])
+        );
+    }
+}
@@ -1,11 +1,21 @@
 #![allow(dead_code)]
+pub mod bgwriter;
+pub mod blink_tree;
 pub mod bufmgr;
-mod diskmgr;
+pub mod catalog;
+pub mod checkpoint;
+pub mod diskmgr;
 mod free_list;
 mod fsutil;
+mod heap_file;
 mod index_page;
 mod ioutil;
-mod objptr;
+pub mod key_codec;
+pub mod objptr;
 mod page;
+mod page_guard;
 mod page_table;
+pub mod recovery;
 mod replacer;
+mod txn;
+pub mod wal;
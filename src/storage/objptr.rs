@@ -2,19 +2,82 @@
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 
-#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+use crate::shared::{PageId, INVALID_PAGE_ID};
+use crate::storage::page::SlotId;
+
+/// A record id: the page a record lives on and its slot in that page's slot directory
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct ObjectPtr {
-    loc: usize,
+    page_id: PageId,
+    slot: SlotId,
+}
+
+impl Default for ObjectPtr {
+    fn default() -> Self {
+        ObjectPtr::new(INVALID_PAGE_ID, 0)
+    }
 }
 
 impl ObjectPtr {
-    pub fn new() -> Self {
-        ObjectPtr { loc: 0 }
+    /// Width of the encoded form, which is also the width bincode gives it
+    pub const SIZE: usize = 10;
+
+    pub fn new(page_id: PageId, slot: SlotId) -> Self {
+        ObjectPtr { page_id, slot }
+    }
+
+    #[inline]
+    pub fn get_page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    #[inline]
+    pub fn get_slot(&self) -> SlotId {
+        self.slot
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.page_id != INVALID_PAGE_ID
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut buf = [0u8; Self::SIZE];
+        buf[..8].copy_from_slice(&(self.page_id as i64).to_le_bytes());
+        buf[8..].copy_from_slice(&self.slot.to_le_bytes());
+        buf
+    }
+
+    /// Decode the first `SIZE` bytes of `buf`
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        let mut page_id = [0u8; 8];
+        page_id.copy_from_slice(&buf[..8]);
+        ObjectPtr {
+            page_id: i64::from_le_bytes(page_id) as PageId,
+            slot: SlotId::from_le_bytes([buf[8], buf[9]]),
+        }
     }
 }
 
 impl Display for ObjectPtr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "\nObjectPtr [loc={}]", self.loc)
+        write!(
+            f,
+            "\nObjectPtr [page_id={}, slot={}]",
+            self.page_id, self.slot
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_width_encoding() {
+        let ptr = ObjectPtr::new(123456789, 513);
+        assert_eq!(ObjectPtr::from_bytes(&ptr.to_bytes()), ptr);
+        assert_eq!(bincode::serialize(&ptr).unwrap().len(), ObjectPtr::SIZE);
+        assert_eq!(bincode::serialize(&ptr).unwrap(), ptr.to_bytes());
+        assert!(!ObjectPtr::default().is_valid());
     }
 }
@@ -1,116 +1,565 @@
 // SOURCES + USEFUL LINKS
 // https://github.com/cmu-db/bustub/blob/master/src/include/storage/page/page.h
+// https://github.com/cmu-db/bustub/blob/master/src/include/storage/page/table_page.h
+// https://www.postgresql.org/docs/current/storage-page-layout.html
 
 #![allow(dead_code, unused_imports)]
-use derivative::Derivative;
-use serde::{Deserialize, Serialize};
-use serde_with::serde_as;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{fence, AtomicBool, AtomicIsize, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
 
 use crate::concurrency::{
-    rw_acquire_excl, rw_acquire_shared, rw_acquire_upgradable, rw_release_excl, rw_release_shared,
-    rw_release_upgradable, rw_upgrade_shared, RwSynchronized,
+    rw_acquire_excl, rw_acquire_excl_owned, rw_acquire_shared, rw_acquire_shared_owned,
+    rw_try_acquire_excl_owned, rw_try_acquire_shared_owned, OwnedExclusiveLatch, OwnedSharedLatch,
+    RwSynchronized,
 };
-use crate::shared::{PageId, PAGE_SIZE};
+use crate::shared::{Lsn, PageId, INVALID_LSN, INVALID_PAGE_ID, PAGE_SIZE};
 
-#[serde_as]
-#[derive(Derivative, Deserialize, Serialize)]
-#[derivative(Default)]
+/// Every page that goes through the buffer pool starts with the LSN of the last log record that changed it
+pub const PAGE_LSN_SIZE: usize = 8;
+
+#[inline]
+pub fn get_page_lsn(data: &[u8; PAGE_SIZE]) -> Lsn {
+    let mut bytes = [0u8; PAGE_LSN_SIZE];
+    bytes.copy_from_slice(&data[..PAGE_LSN_SIZE]);
+    Lsn::from_le_bytes(bytes)
+}
+
+#[inline]
+pub fn set_page_lsn(data: &mut [u8; PAGE_SIZE], lsn: Lsn) {
+    data[..PAGE_LSN_SIZE].copy_from_slice(&lsn.to_le_bytes());
+}
+
+/// Shared latch on the bytes of a page
+pub type PageReadLatch = OwnedSharedLatch<[u8; PAGE_SIZE]>;
+/// Exclusive latch on the bytes of a page. The page version is odd while the latch is held, and moves on to the next even
+/// number when it is released, so an optimistic reader can tell that the page changed under it
+pub struct PageWriteLatch {
+    latch: OwnedExclusiveLatch<[u8; PAGE_SIZE]>,
+    version: Arc<AtomicU64>,
+}
+
+impl PageWriteLatch {
+    fn new(latch: OwnedExclusiveLatch<[u8; PAGE_SIZE]>, version: Arc<AtomicU64>) -> Self {
+        version.fetch_add(1, Ordering::Relaxed);
+        // the odd version is visible before any of the writes to the page
+        fence(Ordering::Release);
+        Self { latch, version }
+    }
+}
+
+impl Deref for PageWriteLatch {
+    type Target = [u8; PAGE_SIZE];
+
+    fn deref(&self) -> &Self::Target {
+        &self.latch
+    }
+}
+
+impl DerefMut for PageWriteLatch {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.latch
+    }
+}
+
+impl Drop for PageWriteLatch {
+    fn drop(&mut self) {
+        // runs before the latch itself is released
+        self.version.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// An in-memory page. The bytes are protected by the page latch, while the id, pin count and dirty flag are atomics so the
+/// buffer pool can pin and unpin a page without waiting for whoever holds its latch
 pub struct Page {
-    #[serde_as(as = "[_; PAGE_SIZE]")]
-    #[derivative(Default(value = "[0u8; PAGE_SIZE]"))]
-    data: [u8; PAGE_SIZE],
-    #[derivative(Default(value = "-1"))]
-    id: PageId,
-    #[derivative(Default(value = "0"))]
-    pin_count: usize,
-    #[derivative(Default(value = "false"))]
-    dirty: bool,
+    data: RwSynchronized<[u8; PAGE_SIZE]>,
+    id: AtomicIsize,
+    pin_count: AtomicUsize,
+    dirty: AtomicBool,
+    /// LSN of the first logged change since the page was last written back (the recLSN), or INVALID_LSN if none
+    rec_lsn: AtomicU64,
+    /// Bumped whenever the exclusive latch is taken or released. Odd while a writer holds the latch
+    version: Arc<AtomicU64>,
+}
+
+impl Default for Page {
+    fn default() -> Self {
+        Page::new(INVALID_PAGE_ID, &[0u8; PAGE_SIZE])
+    }
 }
 
 impl Page {
-    const PAGE_HEADER_SIZE: usize = 8;
+    /// Size of the header at the start of every slotted page
+    pub const PAGE_HEADER_SIZE: usize = 16;
 
     pub fn new(id: PageId, data: &[u8; PAGE_SIZE]) -> Self {
         Page {
-            data: *data,
-            id,
-            pin_count: 0,
-            dirty: false,
+            data: Arc::new(parking_lot::RwLock::new(*data)),
+            id: AtomicIsize::new(id),
+            pin_count: AtomicUsize::new(0),
+            dirty: AtomicBool::new(false),
+            rec_lsn: AtomicU64::new(INVALID_LSN),
+            version: Arc::new(AtomicU64::new(0)),
         }
     }
 
+    /// Copy the page bytes out under a shared latch. Must not be called while holding this page's write latch
     #[inline]
     pub fn get_data(&self) -> [u8; PAGE_SIZE] {
-        self.data
+        *rw_acquire_shared(&self.data)
     }
 
     #[inline]
     pub fn get_id(&self) -> PageId {
-        self.id
+        self.id.load(Ordering::Acquire)
     }
 
     #[inline]
     pub fn get_pin_count(&self) -> usize {
-        self.pin_count
+        self.pin_count.load(Ordering::Acquire)
     }
 
     #[inline]
     pub fn is_dirty(&self) -> bool {
-        self.dirty
-    }
-
-    pub fn set_data(mut self, data: &[u8; PAGE_SIZE]) {
-        self.data = *data;
-    }
-}
-
-// pub type Page = RwSynchronized<PageInternal>;
-
-// #[inline]
-// pub fn w_latch(page: &Page) {
-//     unsafe {
-//         rw_acquire_excl(&page);
-//     }
-// }
-
-// #[inline]
-// pub fn w_unlatch(page: &Page) {
-//     unsafe {
-//         rw_release_excl(&page);
-//     }
-// }
-
-// #[inline]
-// pub fn r_latch(page: &Page) {
-//     unsafe {
-//         rw_acquire_shared(&page);
-//     }
-// }
-
-// #[inline]
-// pub fn r_unlatch(page: &Page) {
-//     unsafe {
-//         rw_release_shared(&page);
-//     }
-// }
-
-// #[inline]
-// pub fn u_latch(page: &Page) {
-//     unsafe {
-//         rw_acquire_upgradable(&page);
-//     }
-// }
-
-// #[inline]
-// pub fn u_unlatch(page: &Page) {
-//     unsafe {
-//         rw_release_upgradable(&page);
-//     }
-// }
-
-// #[inline]
-// pub fn u_upgrade_latch(page: &Page) {
-//     unsafe {
-//         rw_upgrade_shared(&page);
-//     }
-// }
+        self.dirty.load(Ordering::Acquire)
+    }
+
+    /// Copy the bytes of page `id` out without latching or pinning the page, as an optimistic reader: the version is read
+    /// before and after the copy, and the copy is only returned if no writer held the latch at any point in between.
+    /// Returns None if one did, or if the page is no longer `id`, in which case the caller restarts the read. Resetting
+    /// the page takes the latch, so the id cannot change without the version changing too
+    pub fn read_optimistic(&self, id: PageId) -> Option<[u8; PAGE_SIZE]> {
+        let version = self.version.load(Ordering::Acquire);
+        if version % 2 == 1 || self.get_id() != id {
+            return None;
+        }
+        let mut data = [0u8; PAGE_SIZE];
+        // SAFETY: the pointer is valid for as long as the page lives. A writer may change the bytes during the copy, in
+        // which case the version check below throws the torn copy away before anyone looks at it. A volatile read of the
+        // whole array would be copied a byte at a time, an order of magnitude slower than the latched copy
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                self.data.data_ptr() as *const u8,
+                data.as_mut_ptr(),
+                PAGE_SIZE,
+            )
+        };
+        // the copy is finished before the version is read again
+        fence(Ordering::Acquire);
+        (self.version.load(Ordering::Relaxed) == version).then_some(data)
+    }
+
+    #[inline]
+    pub fn get_version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+
+    /// Overwrite the page bytes under an exclusive latch. Must not be called while holding this page's latch
+    pub fn set_data(&self, data: &[u8; PAGE_SIZE]) {
+        *self.w_latch() = *data;
+    }
+
+    /// Give the page a new identity and contents. Used by the buffer pool when it reuses a frame, at which point the page
+    /// must be unpinned
+    pub fn reset(&self, id: PageId, data: &[u8; PAGE_SIZE]) {
+        let mut latch = self.w_latch();
+        debug_assert_eq!(self.get_pin_count(), 0, "resetting a pinned page");
+        *latch = *data;
+        self.id.store(id, Ordering::Release);
+        self.set_dirty(false);
+    }
+
+    #[inline]
+    pub fn pin(&self) {
+        self.pin_count.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Release one pin and return the remaining pin count
+    #[inline]
+    pub fn unpin(&self) -> usize {
+        let prev = self.pin_count.fetch_sub(1, Ordering::AcqRel);
+        assert!(prev > 0, "page {} is not pinned", self.get_id());
+        prev - 1
+    }
+
+    /// Marking a page clean also forgets its recLSN, since the disk now holds all of its changes
+    #[inline]
+    pub fn set_dirty(&self, dirty: bool) {
+        if !dirty {
+            self.rec_lsn.store(INVALID_LSN, Ordering::Release);
+        }
+        self.dirty.store(dirty, Ordering::Release);
+    }
+
+    #[inline]
+    pub fn get_rec_lsn(&self) -> Lsn {
+        self.rec_lsn.load(Ordering::Acquire)
+    }
+
+    /// Mark the page dirty by the change logged at `lsn`. Unless an earlier change is still waiting to be written back,
+    /// `lsn` becomes the page's recLSN. Must be called with the page latched exclusively
+    #[inline]
+    pub fn mark_dirty_at(&self, lsn: Lsn) {
+        let _ =
+            self.rec_lsn
+                .compare_exchange(INVALID_LSN, lsn, Ordering::AcqRel, Ordering::Acquire);
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    /// Take the page latch in shared mode
+    #[inline]
+    pub fn r_latch(&self) -> PageReadLatch {
+        rw_acquire_shared_owned(&self.data)
+    }
+
+    /// Take the page latch in exclusive mode
+    #[inline]
+    pub fn w_latch(&self) -> PageWriteLatch {
+        PageWriteLatch::new(rw_acquire_excl_owned(&self.data), self.version.clone())
+    }
+
+    /// Take the page latch in shared mode if that does not mean waiting
+    #[inline]
+    pub fn try_r_latch(&self) -> Option<PageReadLatch> {
+        rw_try_acquire_shared_owned(&self.data)
+    }
+
+    /// Take the page latch in exclusive mode if that does not mean waiting
+    #[inline]
+    pub fn try_w_latch(&self) -> Option<PageWriteLatch> {
+        rw_try_acquire_excl_owned(&self.data)
+            .map(|latch| PageWriteLatch::new(latch, self.version.clone()))
+    }
+}
+
+/// Index of a record in a slotted page's slot directory. Slots are never renumbered, so a slot id stays valid for as long
+/// as its record lives
+pub type SlotId = u16;
+
+/// What a page holds, stored in its header
+#[repr(u16)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PageType {
+    Invalid = 0,
+    Heap = 1,
+}
+
+impl PageType {
+    fn from_u16(value: u16) -> Self {
+        match value {
+            1 => PageType::Heap,
+            _ => PageType::Invalid,
+        }
+    }
+}
+
+/// A view of a page as a slotted page holding variable-length records.
+///
+/// ```text
+/// +--------+---------+---------+-----+------------+----------+----------+
+/// | header | slot 0  | slot 1  | ... | free space | record 1 | record 0 |
+/// +--------+---------+---------+-----+------------+----------+----------+
+///          ^ slot directory grows ->              ^ free_ptr  <- records grow
+/// ```
+///
+/// The header holds the page LSN (bytes 0..8), the page type (8..10), the number of slots (10..12) and the free space
+/// pointer (12..14), the offset of the lowest record. Every slot is an (offset, length) pair of u16s. A deleted record
+/// leaves an empty slot with offset 0 behind, which a later insert reuses. Deleting and shrinking records fragments the
+/// record area, and `compact` moves the live records back together
+pub struct SlottedPage<B> {
+    data: B,
+}
+
+impl<B: Deref<Target = [u8; PAGE_SIZE]>> SlottedPage<B> {
+    const PAGE_TYPE_OFFSET: usize = 8;
+    const SLOT_COUNT_OFFSET: usize = 10;
+    const FREE_PTR_OFFSET: usize = 12;
+    const SLOT_SIZE: usize = 4;
+
+    /// View an already initialized page
+    pub fn new(data: B) -> Self {
+        SlottedPage { data }
+    }
+
+    pub fn into_inner(self) -> B {
+        self.data
+    }
+
+    #[inline]
+    fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.data[offset], self.data[offset + 1]])
+    }
+
+    pub fn get_lsn(&self) -> Lsn {
+        get_page_lsn(&self.data)
+    }
+
+    pub fn get_page_type(&self) -> PageType {
+        PageType::from_u16(self.read_u16(Self::PAGE_TYPE_OFFSET))
+    }
+
+    pub fn get_slot_count(&self) -> u16 {
+        self.read_u16(Self::SLOT_COUNT_OFFSET)
+    }
+
+    /// Offset of the lowest record, PAGE_SIZE while the page is empty
+    pub fn get_free_ptr(&self) -> usize {
+        match self.read_u16(Self::FREE_PTR_OFFSET) as usize {
+            0 => PAGE_SIZE,
+            free_ptr => free_ptr,
+        }
+    }
+
+    #[inline]
+    fn slot_offset(slot: SlotId) -> usize {
+        Page::PAGE_HEADER_SIZE + slot as usize * Self::SLOT_SIZE
+    }
+
+    /// The (offset, length) pair of a slot. Offset 0 marks an empty slot
+    fn read_slot(&self, slot: SlotId) -> (usize, usize) {
+        let offset = Self::slot_offset(slot);
+        (
+            self.read_u16(offset) as usize,
+            self.read_u16(offset + 2) as usize,
+        )
+    }
+
+    /// Bytes between the end of the slot directory and the lowest record
+    pub fn contiguous_free_space(&self) -> usize {
+        self.get_free_ptr() - Self::slot_offset(self.get_slot_count())
+    }
+
+    /// Bytes that would be free after compaction
+    pub fn free_space(&self) -> usize {
+        let used: usize = self.iter().map(|(_, record)| record.len().max(1)).sum();
+        PAGE_SIZE - Self::slot_offset(self.get_slot_count()) - used
+    }
+
+    /// True if a record of `len` bytes can be inserted, possibly after compaction
+    pub fn can_insert(&self, len: usize) -> bool {
+        let new_slot = match self.empty_slot() {
+            Some(_) => 0,
+            None => Self::SLOT_SIZE,
+        };
+        len + new_slot <= self.free_space()
+    }
+
+    pub fn get(&self, slot: SlotId) -> Option<&[u8]> {
+        if slot >= self.get_slot_count() {
+            return None;
+        }
+        match self.read_slot(slot) {
+            (0, _) => None,
+            (offset, len) => Some(&self.data[offset..offset + len]),
+        }
+    }
+
+    /// Live records in slot order
+    pub fn iter(&self) -> impl Iterator<Item = (SlotId, &[u8])> + '_ {
+        (0..self.get_slot_count())
+            .filter_map(move |slot| self.get(slot).map(|record| (slot, record)))
+    }
+
+    fn empty_slot(&self) -> Option<SlotId> {
+        (0..self.get_slot_count()).find(|&slot| self.read_slot(slot).0 == 0)
+    }
+}
+
+impl<B: DerefMut<Target = [u8; PAGE_SIZE]>> SlottedPage<B> {
+    /// Format the page as an empty slotted page of the given type
+    pub fn init(mut data: B, page_type: PageType) -> Self {
+        data.fill(0);
+        let mut page = SlottedPage { data };
+        page.write_u16(Self::PAGE_TYPE_OFFSET, page_type as u16);
+        page.set_free_ptr(PAGE_SIZE);
+        page
+    }
+
+    #[inline]
+    fn write_u16(&mut self, offset: usize, value: u16) {
+        self.data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn set_lsn(&mut self, lsn: Lsn) {
+        set_page_lsn(&mut self.data, lsn);
+    }
+
+    fn set_slot_count(&mut self, count: u16) {
+        self.write_u16(Self::SLOT_COUNT_OFFSET, count);
+    }
+
+    /// The free space pointer is stored as a u16, in which PAGE_SIZE itself wraps to 0
+    fn set_free_ptr(&mut self, free_ptr: usize) {
+        self.write_u16(Self::FREE_PTR_OFFSET, (free_ptr % PAGE_SIZE) as u16);
+    }
+
+    fn write_slot(&mut self, slot: SlotId, offset: usize, len: usize) {
+        let slot_offset = Self::slot_offset(slot);
+        self.write_u16(slot_offset, offset as u16);
+        self.write_u16(slot_offset + 2, len as u16);
+    }
+
+    /// Copy a record below the free space pointer, which the caller has checked leaves room for it. An empty record still
+    /// takes up a byte so that its offset tells it apart from an empty slot
+    fn place(&mut self, record: &[u8]) -> usize {
+        let offset = self.get_free_ptr() - record.len().max(1);
+        self.data[offset..offset + record.len()].copy_from_slice(record);
+        self.set_free_ptr(offset);
+        offset
+    }
+
+    /// Store a record and return its slot, or None if the page is too full even after compaction
+    pub fn insert(&mut self, record: &[u8]) -> Option<SlotId> {
+        if !self.can_insert(record.len().max(1)) {
+            return None;
+        }
+        let slot = match self.empty_slot() {
+            Some(slot) => slot,
+            None => {
+                let slot = self.get_slot_count();
+                self.set_slot_count(slot + 1);
+                slot
+            }
+        };
+        if self.contiguous_free_space() < record.len().max(1) {
+            self.compact();
+        }
+        let offset = self.place(record);
+        self.write_slot(slot, offset, record.len());
+        Some(slot)
+    }
+
+    /// Replace a record. Returns false, leaving the page unchanged, if the slot is empty or the new record does not fit
+    pub fn update(&mut self, slot: SlotId, record: &[u8]) -> bool {
+        let Some(old) = self.get(slot) else {
+            return false;
+        };
+        let old_len = old.len();
+        if record.len() <= old_len {
+            let (offset, _) = self.read_slot(slot);
+            self.data[offset..offset + record.len()].copy_from_slice(record);
+            self.write_slot(slot, offset, record.len());
+            return true;
+        }
+        if record.len() > self.free_space() + old_len {
+            return false;
+        }
+        // give up the old bytes so that compaction can reclaim them
+        self.write_slot(slot, 0, 0);
+        if self.contiguous_free_space() < record.len() {
+            self.compact();
+        }
+        let offset = self.place(record);
+        self.write_slot(slot, offset, record.len());
+        true
+    }
+
+    /// Remove a record. Returns false if the slot was already empty
+    pub fn delete(&mut self, slot: SlotId) -> bool {
+        if self.get(slot).is_none() {
+            return false;
+        }
+        self.write_slot(slot, 0, 0);
+        // trailing empty slots can go, since no live record refers to them
+        let mut count = self.get_slot_count();
+        while count > 0 && self.read_slot(count - 1).0 == 0 {
+            count -= 1;
+        }
+        self.set_slot_count(count);
+        true
+    }
+
+    /// Move the live records to the end of the page, leaving all free space between the slot directory and the records
+    pub fn compact(&mut self) {
+        let records: Vec<(SlotId, Vec<u8>)> = self
+            .iter()
+            .map(|(slot, record)| (slot, record.to_vec()))
+            .collect();
+        self.set_free_ptr(PAGE_SIZE);
+        for (slot, record) in records {
+            let offset = self.place(&record);
+            self.write_slot(slot, offset, record.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(i: usize, len: usize) -> Vec<u8> {
+        format!("{:0>width$}", i, width = len).into_bytes()
+    }
+
+    #[test]
+    fn slotted_insert_get_delete() {
+        let mut page = SlottedPage::init(Box::new([0u8; PAGE_SIZE]), PageType::Heap);
+        assert_eq!(page.get_page_type(), PageType::Heap);
+        assert_eq!(page.get_free_ptr(), PAGE_SIZE);
+        page.set_lsn(42);
+        assert_eq!(page.get_lsn(), 42);
+
+        let mut slots = Vec::new();
+        while let Some(slot) = page.insert(&record(slots.len(), 50)) {
+            slots.push(slot);
+        }
+        // 16 byte header, then 54 bytes per record including its slot
+        assert_eq!(slots.len(), (PAGE_SIZE - Page::PAGE_HEADER_SIZE) / 54);
+        for (i, &slot) in slots.iter().enumerate() {
+            assert_eq!(slot as usize, i);
+            assert_eq!(page.get(slot), Some(record(i, 50).as_slice()));
+        }
+
+        assert!(page.delete(3));
+        assert!(!page.delete(3));
+        assert_eq!(page.get(3), None);
+        assert_eq!(page.iter().count(), slots.len() - 1);
+        // the empty slot is reused
+        assert_eq!(page.insert(b"again"), Some(3));
+        assert_eq!(page.get(3), Some(&b"again"[..]));
+
+        let last = *slots.last().unwrap();
+        assert!(page.delete(last));
+        assert_eq!(page.get_slot_count(), last);
+        assert_eq!(page.get(last), None);
+
+        // the same bytes read back through a fresh view
+        let page = SlottedPage::new(page.into_inner());
+        assert_eq!(page.get(0), Some(record(0, 50).as_slice()));
+        assert_eq!(page.get_lsn(), 42);
+    }
+
+    #[test]
+    fn slotted_update_compact() {
+        let mut page = SlottedPage::init(Box::new([0u8; PAGE_SIZE]), PageType::Heap);
+        for i in 0..30 {
+            assert_eq!(page.insert(&record(i, 100)), Some(i as SlotId));
+        }
+        // shrink in place, then free every other record so the free space is fragmented
+        assert!(page.update(0, b"short"));
+        assert_eq!(page.get(0), Some(&b"short"[..]));
+        for i in (1..30).step_by(2) {
+            assert!(page.delete(i));
+        }
+        let free = page.free_space();
+        assert!(page.contiguous_free_space() < free);
+
+        // a record larger than the contiguous space forces a compaction
+        let big = record(7, page.contiguous_free_space() + 200);
+        assert!(page.update(2, &big));
+        assert_eq!(page.get(2), Some(big.as_slice()));
+        assert_eq!(page.contiguous_free_space(), page.free_space());
+        for i in (4..30).step_by(2) {
+            assert_eq!(page.get(i as SlotId), Some(record(i, 100).as_slice()));
+        }
+
+        assert!(!page.update(4, &vec![0u8; PAGE_SIZE]));
+        assert_eq!(page.get(4), Some(record(4, 100).as_slice()));
+        assert!(!page.update(5, b"deleted"));
+        assert!(page.insert(&vec![1u8; page.free_space() + 1]).is_none());
+        let empty = page.insert(b"").unwrap();
+        assert_eq!(page.get(empty), Some(&b""[..]));
+        page.compact();
+        assert_eq!(page.get(0), Some(&b"short"[..]));
+    }
+}
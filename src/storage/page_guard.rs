@@ -0,0 +1,197 @@
+// SOURCES + USEFUL LINKS
+// https://github.com/cmu-db/bustub/blob/master/src/include/storage/page/page_guard.h
+use std::ops::{Deref, DerefMut, Range};
+
+use crate::concurrency::rw_acquire_shared;
+use crate::shared::{FrameId, PageId, INVALID_LSN, INVALID_TXN_ID, PAGE_SIZE};
+use crate::storage::bufmgr::{BufferPoolFrame, BufferPoolInternal};
+use crate::storage::page::{set_page_lsn, PageReadLatch, PageWriteLatch, PAGE_LSN_SIZE};
+use crate::storage::txn::Transaction;
+use crate::storage::wal::{LogBody, LogRecord};
+
+/// A pinned page latched in shared mode. Dropping the guard releases the latch and then the pin
+pub struct ReadPageGuard<'a> {
+    bufmgr: &'a BufferPoolInternal,
+    frame: BufferPoolFrame,
+    latch: Option<PageReadLatch>,
+}
+
+impl<'a> ReadPageGuard<'a> {
+    /// Latch a frame that the caller has already pinned. The guard takes over the pin
+    pub(crate) fn new(bufmgr: &'a BufferPoolInternal, frame: BufferPoolFrame) -> Self {
+        let latch = Some(bufmgr.latch_shared(frame.get_page()));
+        Self {
+            bufmgr,
+            frame,
+            latch,
+        }
+    }
+
+    #[inline]
+    pub fn get_page_id(&self) -> PageId {
+        self.frame.get_page().get_id()
+    }
+
+    #[inline]
+    pub fn get_frame_id(&self) -> FrameId {
+        self.frame.get_frame_id()
+    }
+}
+
+impl Deref for ReadPageGuard<'_> {
+    type Target = [u8; PAGE_SIZE];
+
+    fn deref(&self) -> &Self::Target {
+        self.latch.as_ref().unwrap()
+    }
+}
+
+impl Drop for ReadPageGuard<'_> {
+    fn drop(&mut self) {
+        // unlatch before unpinning so the frame is never evictable while latched
+        self.latch.take();
+        self.bufmgr.unpin_frame(self.frame.get_frame_id(), false);
+    }
+}
+
+/// A pinned page latched in exclusive mode. Dropping the guard marks the page dirty if it changed, releases the latch and
+/// then the pin.
+///
+/// If the buffer pool has a write-ahead log, the guard keeps a copy of the page as it was latched and logs the bytes that
+/// changed when it is dropped, stamping the page with the LSN of the record. A guard obtained through a transaction hands
+/// a changed page over to the transaction instead of releasing it, so that it stays latched until the transaction ends
+pub struct WritePageGuard<'a> {
+    bufmgr: &'a BufferPoolInternal,
+    frame: BufferPoolFrame,
+    latch: Option<PageWriteLatch>,
+    txn: Option<&'a Transaction<'a>>,
+    /// The page as it was latched, if changes to it are logged or may have to be undone
+    before: Option<Box<[u8; PAGE_SIZE]>>,
+    /// A new page. Whatever the disk holds for it is garbage, so the whole page is logged
+    fresh: bool,
+    /// The page was changed earlier in the transaction, which keeps holding it whether or not this guard changes it
+    held: bool,
+}
+
+impl<'a> WritePageGuard<'a> {
+    /// Latch a frame that the caller has already pinned. The guard takes over the pin
+    pub(crate) fn new(
+        bufmgr: &'a BufferPoolInternal,
+        frame: BufferPoolFrame,
+        txn: Option<&'a Transaction<'a>>,
+        fresh: bool,
+    ) -> Self {
+        let latch = bufmgr.latch_exclusive(frame.get_page());
+        let before = (bufmgr.get_wal().is_some() || txn.is_some()).then(|| Box::new(*latch));
+        Self {
+            bufmgr,
+            frame,
+            latch: Some(latch),
+            txn,
+            before,
+            fresh,
+            held: false,
+        }
+    }
+
+    /// Hand a page that a transaction changed and still holds back to it. The guard takes over the latch and the pin and
+    /// returns them to the transaction when dropped
+    pub(crate) fn resume(
+        bufmgr: &'a BufferPoolInternal,
+        frame: BufferPoolFrame,
+        latch: PageWriteLatch,
+        txn: &'a Transaction<'a>,
+    ) -> Self {
+        let before = Some(Box::new(*latch));
+        Self {
+            bufmgr,
+            frame,
+            latch: Some(latch),
+            txn: Some(txn),
+            before,
+            fresh: false,
+            held: true,
+        }
+    }
+
+    #[inline]
+    pub fn get_page_id(&self) -> PageId {
+        self.frame.get_page().get_id()
+    }
+
+    #[inline]
+    pub fn get_frame_id(&self) -> FrameId {
+        self.frame.get_frame_id()
+    }
+
+    /// The smallest range of bytes after the page LSN that differs from the before-image, or None if nothing changed
+    fn changed_range(&self, before: &[u8; PAGE_SIZE]) -> Option<Range<usize>> {
+        let after = self.latch.as_ref().unwrap();
+        if self.fresh {
+            return Some(PAGE_LSN_SIZE..PAGE_SIZE);
+        }
+        let start = (PAGE_LSN_SIZE..PAGE_SIZE).find(|&i| before[i] != after[i])?;
+        let end = (start..PAGE_SIZE).rev().find(|&i| before[i] != after[i])? + 1;
+        Some(start..end)
+    }
+}
+
+impl Deref for WritePageGuard<'_> {
+    type Target = [u8; PAGE_SIZE];
+
+    fn deref(&self) -> &Self::Target {
+        self.latch.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for WritePageGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.latch.as_mut().unwrap()
+    }
+}
+
+impl Drop for WritePageGuard<'_> {
+    fn drop(&mut self) {
+        // without a before-image there is no telling whether the page changed, so it has to be assumed that it did
+        let compared = self.before.is_some();
+        let change = self
+            .before
+            .take()
+            .and_then(|before| self.changed_range(&before).map(|range| (before, range)));
+        let changed = change.is_some() || !compared;
+        // with a log, the page is marked dirty together with its recLSN when the change is logged below
+        if changed && self.bufmgr.get_wal().is_none() {
+            self.frame.get_page().set_dirty(true);
+        }
+        let mut latch = self.latch.take().unwrap();
+        if let Some(txn) = self.txn {
+            if let Some((before, range)) = change {
+                txn.log_update(self.frame.get_page(), &mut latch, range, &before);
+            }
+            // the transaction releases the pages it changed when it ends. One it only looked at is released now
+            if changed || self.held {
+                txn.retain(self.frame.clone(), latch);
+                return;
+            }
+        } else if let (Some(wal), Some((before, range))) = (self.bufmgr.get_wal(), change) {
+            let record = LogRecord {
+                txn_id: INVALID_TXN_ID,
+                prev_lsn: INVALID_LSN,
+                body: LogBody::Update {
+                    page_id: self.get_page_id(),
+                    offset: range.start as u16,
+                    before: before[range.clone()].to_vec(),
+                    after: latch[range].to_vec(),
+                },
+            };
+            let wal = rw_acquire_shared(wal);
+            // a checkpoint that begins after the record must find the page in the dirty page table, so its recLSN is
+            // set first, to a lower bound of the record's LSN
+            self.frame.get_page().mark_dirty_at(wal.get_next_lsn());
+            let lsn = wal.append(&record);
+            set_page_lsn(&mut latch, lsn);
+        }
+        drop(latch);
+        self.bufmgr.unpin_frame(self.frame.get_frame_id(), changed);
+    }
+}
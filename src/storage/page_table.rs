@@ -1,9 +1,172 @@
 use crate::{
-    concurrency::RwSynchronized,
+    concurrency::{rw_acquire_shared, RwSynchronized},
     shared::{FrameId, PageId},
 };
 use std::collections::HashMap;
+use std::sync::Arc;
 
-/// Thread safe HashMap (protected by a parking_lot::RwLock)
-/// Just lock it and then perform operations normally.
-pub type PageTable = RwSynchronized<HashMap<PageId, FrameId>>;
+/// Shards a page table is split into unless the buffer pool asks for another number
+pub const DEFAULT_SHARDS: usize = 16;
+
+/// One shard of the page table. Just lock it and then perform operations normally
+pub type PageTableShard = RwSynchronized<HashMap<PageId, FrameId>>;
+
+/// Maps resident pages to their frames. The table is split into shards, each a HashMap behind its own latch, and a page id
+/// always hashes to the same shard, so looking up pages in different shards never contends. A table with a single shard
+/// is a plain HashMap behind one global latch
+pub struct PageTable {
+    shards: Vec<PageTableShard>,
+}
+
+impl PageTable {
+    pub fn new(shard_count: usize) -> Self {
+        assert!(shard_count > 0, "a page table needs at least one shard");
+        Self {
+            shards: (0..shard_count)
+                .map(|_| Arc::new(parking_lot::RwLock::new(HashMap::new())))
+                .collect(),
+        }
+    }
+
+    #[inline]
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// The shard `page_id` belongs to
+    #[inline]
+    pub fn shard(&self, page_id: PageId) -> &PageTableShard {
+        &self.shards[Self::shard_index(page_id, self.shards.len())]
+    }
+
+    #[inline]
+    pub fn shards(&self) -> &[PageTableShard] {
+        &self.shards
+    }
+
+    /// The frame holding `page_id`. The answer may be out of date as soon as the shard is unlatched
+    pub fn get(&self, page_id: PageId) -> Option<FrameId> {
+        rw_acquire_shared(self.shard(page_id))
+            .get(&page_id)
+            .copied()
+    }
+
+    /// Fibonacci hashing: multiplying by 2^64 / φ spreads neighbouring page ids, which tend to be fetched together, over
+    /// different shards
+    #[inline]
+    fn shard_index(page_id: PageId, shard_count: usize) -> usize {
+        let hash = (page_id as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        ((hash >> 32) % shard_count as u64) as usize
+    }
+}
+
+impl Default for PageTable {
+    fn default() -> Self {
+        Self::new(DEFAULT_SHARDS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use rand::Rng;
+
+    use super::{PageTable, DEFAULT_SHARDS};
+    use crate::concurrency::{rw_acquire_excl, rw_acquire_shared};
+    use crate::shared::PageId;
+    use crate::storage::bufmgr::BufferPoolInternal;
+    use crate::storage::diskmgr::DiskMgrInternal;
+
+    #[test]
+    fn shards() {
+        let table = PageTable::default();
+        assert_eq!(table.shard_count(), DEFAULT_SHARDS);
+        for page_id in 0..1000 {
+            rw_acquire_excl(table.shard(page_id)).insert(page_id, page_id * 2);
+        }
+        for page_id in 0..1000 {
+            assert_eq!(table.get(page_id), Some(page_id * 2));
+            assert!(std::ptr::eq(table.shard(page_id), table.shard(page_id)));
+        }
+        assert_eq!(table.get(1000), None);
+        // consecutive page ids are spread evenly
+        for shard in table.shards() {
+            let len = rw_acquire_shared(shard).len();
+            assert!((1000 / DEFAULT_SHARDS / 2..1000 / DEFAULT_SHARDS * 2).contains(&len));
+        }
+        let single = PageTable::new(1);
+        rw_acquire_excl(single.shard(-1)).insert(-1, 0);
+        assert!(std::ptr::eq(single.shard(-1), single.shard(7)));
+    }
+
+    /// Run `fetch` on `threads` threads for a fixed time and return the number of fetches per second
+    fn throughput(threads: usize, pages: PageId, fetch: impl Fn(PageId) + Sync) -> f64 {
+        const RUN: Duration = Duration::from_millis(500);
+        let start = Instant::now();
+        let fetches: usize = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut rng = rand::thread_rng();
+                        let mut fetches = 0;
+                        while start.elapsed() < RUN {
+                            for _ in 0..256 {
+                                fetch(rng.gen_range(0..pages));
+                            }
+                            fetches += 256;
+                        }
+                        fetches
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+        fetches as f64 / start.elapsed().as_secs_f64()
+    }
+
+    /// Compares `fetch_page` on resident pages with a page table of a single shard, which is one global latch, and with
+    /// `DEFAULT_SHARDS` shards. A hit latches its shard in shared mode, but pinning the frame and the last unpin also
+    /// take the replacer's mutex, so every fetch still serialises on one lock whatever the number of shards, and
+    /// sharding alone buys little on the hit path. What sharding does is keep a miss, which latches its shard
+    /// exclusively while it reads the page from disk, from blocking hits on the other shards. Readers that only need a
+    /// copy avoid the replacer with optimistic reads, see `optimistic_read_scaling` in the buffer pool. Run with
+    /// `cargo test --release page_table_contention -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn page_table_contention() {
+        const PAGES: usize = 1024;
+        let pools: Vec<_> = [1, DEFAULT_SHARDS]
+            .into_iter()
+            .map(|shards| {
+                let diskmgr = Arc::new(parking_lot::RwLock::new(DiskMgrInternal::recreate(
+                    &(crate::shared::cwd() + &format!("/data/test/__page_table__/{}.bin", shards)),
+                )));
+                let bufmgr =
+                    BufferPoolInternal::new(PAGES, 2, diskmgr).with_page_table_shards(shards);
+                let first = bufmgr.new_page_write().unwrap().get_page_id();
+                for _ in 1..PAGES {
+                    bufmgr.new_page_write().unwrap();
+                }
+                (bufmgr, first)
+            })
+            .collect();
+        for threads in [1, 2, 4, 8, 16] {
+            let [global, sharded] = [&pools[0], &pools[1]].map(|(bufmgr, first)| {
+                throughput(threads, PAGES as PageId, |page| {
+                    let frame_id = bufmgr.fetch_page(first + page).unwrap();
+                    bufmgr.unpin_frame(frame_id, false);
+                })
+            });
+            println!(
+                "{:>2} threads: global {:>12.0} fetches/s, {} shards {:>12.0} fetches/s ({:.1}x)",
+                threads,
+                global,
+                DEFAULT_SHARDS,
+                sharded,
+                sharded / global
+            );
+        }
+    }
+}
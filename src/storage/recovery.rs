@@ -0,0 +1,536 @@
+// SOURCES + USEFUL LINKS
+// https://web.stanford.edu/class/cs345d-01/rl/aries.pdf (ARIES)
+// https://github.com/cmu-db/bustub/blob/master/src/include/recovery/log_recovery.h
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::concurrency::rw_acquire_shared;
+use crate::shared::{Lsn, PageId, TxnId, INVALID_LSN, INVALID_TXN_ID};
+use crate::storage::bufmgr::BufferPoolInternal;
+use crate::storage::page::{get_page_lsn, set_page_lsn};
+use crate::storage::wal::{LogBody, LogRecord, WalInternal};
+
+/// What a recovery run found and did
+#[derive(Default, Debug)]
+pub struct RecoveryStats {
+    /// Log records whose change was missing from their page and was applied again
+    pub redone: usize,
+    /// Changes of unfinished transactions that were rolled back
+    pub undone: usize,
+    /// Transactions that were still running at the crash
+    pub losers: usize,
+    /// Log records read by the analysis, which starts at the last checkpoint
+    pub analyzed: usize,
+}
+
+/// The state of the system at the end of the log, as far as the log can tell
+#[derive(Default)]
+struct Analysis {
+    /// Unfinished transactions with the LSN of their last record
+    active: HashMap<TxnId, Lsn>,
+    /// Pages that may have changes missing on disk, with the LSN of the first such change (the recLSN)
+    dirty: HashMap<PageId, Lsn>,
+    /// Pages allocated, freed or changed since the checkpoint, and whether they were in use at the end of the log
+    allocated: HashMap<PageId, bool>,
+}
+
+/// Bring the pages of a database back to a consistent state after a crash, following ARIES:
+/// - analysis scans the log from the last checkpoint to find the transactions that never finished and the pages that may
+///   be out of date
+/// - redo repeats history, reapplying every logged change a page is missing, including those of unfinished transactions,
+///   and marks the pages allocated or freed since the checkpoint in the bitmap, which may not have reached the disk
+/// - undo rolls the unfinished transactions back, logging a compensation record for every change it reverts so that a
+///   crash during recovery never undoes anything twice, and frees the pages they allocated
+///
+/// Must run before anything else uses the buffer pool, which must have a write-ahead log. Recovered pages are left dirty
+/// in the buffer pool
+pub fn recover(bufmgr: &BufferPoolInternal) -> std::io::Result<RecoveryStats> {
+    let wal = rw_acquire_shared(bufmgr.get_wal().expect("recovery needs a write-ahead log"));
+    let mut stats = RecoveryStats::default();
+    let checkpoint_lsn = rw_acquire_shared(bufmgr.get_diskmgr()).get_checkpoint_lsn();
+    let analysis = match analyze(&wal, checkpoint_lsn, &mut stats)? {
+        Some(analysis) => analysis,
+        // the checkpoint in the header is not in the log, so fall back to reading all of it
+        None => analyze(&wal, INVALID_LSN, &mut stats)?.unwrap(),
+    };
+    redo(bufmgr, &wal, &analysis, &mut stats)?;
+    let allocated = undo(bufmgr, &wal, analysis.active, &mut stats)?;
+    wal.flush_all()?;
+    drop(wal);
+    bufmgr
+        .discard_pages(&allocated)
+        .map_err(std::io::Error::from)?;
+    Ok(stats)
+}
+
+/// Rebuild the dirty page table and the active transactions from the checkpoint at `start`, or from the beginning of
+/// the log if `start` is INVALID_LSN. Returns None if the checkpoint is incomplete
+fn analyze(
+    wal: &WalInternal,
+    start: Lsn,
+    stats: &mut RecoveryStats,
+) -> std::io::Result<Option<Analysis>> {
+    let mut analysis = Analysis::default();
+    // transactions with records after `start`, whose state the log knows better than the checkpoint does
+    let mut seen = HashSet::new();
+    let mut checkpointed = start == INVALID_LSN;
+    stats.analyzed = 0;
+    for (lsn, record) in wal.iter_from(start)? {
+        stats.analyzed += 1;
+        if record.txn_id != INVALID_TXN_ID {
+            seen.insert(record.txn_id);
+            match record.body {
+                LogBody::Commit | LogBody::Abort => analysis.active.remove(&record.txn_id),
+                _ => analysis.active.insert(record.txn_id, lsn),
+            };
+        }
+        match record.body {
+            LogBody::Update { page_id, .. } | LogBody::Compensation { page_id, .. } => {
+                analysis.dirty.entry(page_id).or_insert(lsn);
+                analysis.allocated.insert(page_id, true);
+            }
+            LogBody::Commit | LogBody::Abort => {}
+            LogBody::AllocatePage { page_id } => {
+                analysis.allocated.insert(page_id, true);
+            }
+            LogBody::FreePage { page_id } | LogBody::UndoAllocate { page_id, .. } => {
+                analysis.allocated.insert(page_id, false);
+            }
+            LogBody::BeginCheckpoint => {}
+            LogBody::EndCheckpoint {
+                dirty_pages,
+                active_txns,
+            } => {
+                for (page_id, rec_lsn) in dirty_pages {
+                    let entry = analysis.dirty.entry(page_id).or_insert(rec_lsn);
+                    *entry = (*entry).min(rec_lsn);
+                }
+                for (txn_id, last_lsn) in active_txns {
+                    if !seen.contains(&txn_id) {
+                        analysis.active.insert(txn_id, last_lsn);
+                    }
+                }
+                checkpointed = true;
+            }
+        }
+    }
+    Ok(checkpointed.then_some(analysis))
+}
+
+fn redo(
+    bufmgr: &BufferPoolInternal,
+    wal: &WalInternal,
+    analysis: &Analysis,
+    stats: &mut RecoveryStats,
+) -> std::io::Result<()> {
+    // the checkpoint synced the bitmap, so only the allocations and frees logged after it can be missing
+    let diskmgr = rw_acquire_shared(bufmgr.get_diskmgr());
+    for (&page_id, &allocated) in &analysis.allocated {
+        diskmgr.set_allocated(page_id, allocated)?;
+    }
+    drop(diskmgr);
+    let start = match analysis.dirty.values().min() {
+        Some(&lsn) => lsn,
+        None => return Ok(()),
+    };
+    for (lsn, record) in wal.iter_from(start)? {
+        let (page_id, offset, after) = match &record.body {
+            LogBody::Update {
+                page_id,
+                offset,
+                after,
+                ..
+            }
+            | LogBody::Compensation {
+                page_id,
+                offset,
+                after,
+                ..
+            } => (*page_id, *offset as usize, after),
+            _ => continue,
+        };
+        // a page that was written back after this change already has it
+        if analysis
+            .dirty
+            .get(&page_id)
+            .is_none_or(|&rec_lsn| lsn < rec_lsn)
+        {
+            continue;
+        }
+        if apply(bufmgr, page_id, offset, after, lsn)? {
+            stats.redone += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Roll the losers back. Returns the pages they allocated, to be freed once the rollback is durable
+fn undo(
+    bufmgr: &BufferPoolInternal,
+    wal: &WalInternal,
+    active: HashMap<TxnId, Lsn>,
+    stats: &mut RecoveryStats,
+) -> std::io::Result<Vec<PageId>> {
+    stats.losers = active.len();
+    // the next record to roll back for every loser, undone newest first across all of them
+    let mut to_undo: BTreeSet<(Lsn, TxnId)> = active.iter().map(|(&t, &lsn)| (lsn, t)).collect();
+    let mut last_lsn = active;
+    let mut allocated = Vec::new();
+    while let Some((lsn, txn_id)) = to_undo.pop_last() {
+        let record = wal.read_record(lsn)?;
+        let undo_next = match record.body {
+            LogBody::Update {
+                page_id,
+                offset,
+                before,
+                ..
+            } => {
+                let clr = LogRecord {
+                    txn_id,
+                    prev_lsn: last_lsn[&txn_id],
+                    body: LogBody::Compensation {
+                        page_id,
+                        offset,
+                        after: before.clone(),
+                        undo_next: record.prev_lsn,
+                    },
+                };
+                let clr_lsn = wal.append(&clr);
+                last_lsn.insert(txn_id, clr_lsn);
+                apply(bufmgr, page_id, offset as usize, &before, clr_lsn)?;
+                stats.undone += 1;
+                record.prev_lsn
+            }
+            LogBody::AllocatePage { page_id } => {
+                let clr = LogRecord {
+                    txn_id,
+                    prev_lsn: last_lsn[&txn_id],
+                    body: LogBody::UndoAllocate {
+                        page_id,
+                        undo_next: record.prev_lsn,
+                    },
+                };
+                last_lsn.insert(txn_id, wal.append(&clr));
+                allocated.push(page_id);
+                stats.undone += 1;
+                record.prev_lsn
+            }
+            // everything a compensation record's change was preceded by is still to be undone
+            LogBody::Compensation { undo_next, .. } | LogBody::UndoAllocate { undo_next, .. } => {
+                undo_next
+            }
+            LogBody::Commit
+            | LogBody::Abort
+            | LogBody::BeginCheckpoint
+            | LogBody::EndCheckpoint { .. }
+            | LogBody::FreePage { .. } => record.prev_lsn,
+        };
+        if undo_next != INVALID_LSN {
+            to_undo.insert((undo_next, txn_id));
+        } else {
+            wal.append(&LogRecord {
+                txn_id,
+                prev_lsn: last_lsn[&txn_id],
+                body: LogBody::Abort,
+            });
+        }
+    }
+    Ok(allocated)
+}
+
+/// Write `bytes` at `offset` of a page and stamp it with `lsn`, unless the page already reflects a record at least that
+/// new. Returns whether the page changed. The change is not logged: redo replays the log, and undo logs its own
+/// compensation records
+fn apply(
+    bufmgr: &BufferPoolInternal,
+    page_id: PageId,
+    offset: usize,
+    bytes: &[u8],
+    lsn: Lsn,
+) -> std::io::Result<bool> {
+    let frame_id = bufmgr.fetch_page(page_id)?;
+    let frame = bufmgr.frame(frame_id);
+    let mut latch = frame.get_page().w_latch();
+    let stale = get_page_lsn(&latch) < lsn;
+    if stale {
+        latch[offset..offset + bytes.len()].copy_from_slice(bytes);
+        set_page_lsn(&mut latch, lsn);
+        frame.get_page().mark_dirty_at(lsn);
+    }
+    drop(latch);
+    bufmgr.unpin_frame(frame_id, stale);
+    Ok(stale)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, SeekFrom, Write};
+    use std::sync::Arc;
+
+    use crate::bootstrap::{DbContext, Options};
+    use crate::concurrency::rw_acquire_shared;
+    use crate::shared::{PageId, PAGE_SIZE};
+    use crate::storage::blink_tree::BLinkTree;
+    use crate::storage::bufmgr::BufferPoolInternal;
+    use crate::storage::diskmgr::{DiskMgrInternal, FaultInjector, OpenMode};
+    use crate::storage::objptr::ObjectPtr;
+    use crate::storage::wal::WalInternal;
+
+    /// A database path with nothing left behind by an earlier run
+    fn db_path(name: &str) -> String {
+        let path = crate::shared::cwd() + "/data/test/__recovery__/" + name;
+        for file in [path.clone(), DbContext::wal_path(&path)] {
+            let _ = std::fs::remove_file(file);
+        }
+        path
+    }
+
+    fn read_byte(ctx: &DbContext, page_id: PageId, offset: usize) -> u8 {
+        rw_acquire_shared(ctx.get_bufmgr())
+            .fetch_page_read(page_id)
+            .unwrap()[offset]
+    }
+
+    #[test]
+    fn losers_are_rolled_back() {
+        let path = db_path("losers.bin");
+        let page_ids: Vec<PageId> = {
+            let ctx = DbContext::open(&path, Options::default()).unwrap();
+            let pool = rw_acquire_shared(ctx.get_bufmgr());
+            let page_ids: Vec<PageId> = (0..3)
+                .map(|_| pool.new_page_write().unwrap().get_page_id())
+                .collect();
+
+            let winner = pool.begin();
+            winner.fetch_page_write(page_ids[0]).unwrap()[PAGE_SIZE - 1] = 1;
+            winner.commit().unwrap();
+
+            let loser = pool.begin();
+            loser.fetch_page_write(page_ids[1]).unwrap()[100] = 2;
+            loser.fetch_page_write(page_ids[2]).unwrap()[200] = 3;
+            // the loser's records reach the log, its pages never reach the disk, and it dies before committing
+            rw_acquire_shared(ctx.get_wal()).flush_all().unwrap();
+            std::mem::forget(loser);
+            page_ids
+        };
+
+        let ctx = DbContext::open(&path, Options::default()).unwrap();
+        let stats = ctx.get_recovery_stats();
+        assert_eq!((stats.losers, stats.undone), (1, 2));
+        assert!(stats.redone >= 3);
+        assert_eq!(read_byte(&ctx, page_ids[0], PAGE_SIZE - 1), 1);
+        assert_eq!(read_byte(&ctx, page_ids[1], 100), 0);
+        assert_eq!(read_byte(&ctx, page_ids[2], 200), 0);
+        drop(ctx);
+
+        // the rollback was logged, so a second recovery has nothing left to undo
+        let ctx = DbContext::open(&path, Options::default()).unwrap();
+        assert_eq!(ctx.get_recovery_stats().losers, 0);
+        assert_eq!(read_byte(&ctx, page_ids[0], PAGE_SIZE - 1), 1);
+        assert_eq!(read_byte(&ctx, page_ids[1], 100), 0);
+    }
+
+    #[test]
+    fn lost_bitmap_is_rebuilt() {
+        let path = db_path("lost_bitmap.bin");
+        let (kept, freed) = {
+            let ctx = DbContext::open(&path, Options::default()).unwrap();
+            let pool = rw_acquire_shared(ctx.get_bufmgr());
+            let txn = pool.begin();
+            let kept: Vec<PageId> = (0..3)
+                .map(|_| {
+                    let mut page = txn.new_page_write().unwrap();
+                    page[100] = 1;
+                    page.get_page_id()
+                })
+                .collect();
+            txn.commit().unwrap();
+            let freed = pool.new_page_write().unwrap().get_page_id();
+            assert!(pool.delete_page(freed).unwrap());
+            (kept, freed)
+        };
+        // the process dies, and the disk ends up with a bitmap that has none of the allocations but still has the page
+        // that was freed
+        let mut bitmap = [0u8; PAGE_SIZE];
+        let bit = freed as usize - 2;
+        bitmap[bit / 8] |= 1 << (bit % 8);
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(PAGE_SIZE as u64)).unwrap();
+        file.write_all(&bitmap).unwrap();
+        drop(file);
+
+        let ctx = DbContext::open(&path, Options::default()).unwrap();
+        let diskmgr = rw_acquire_shared(ctx.get_diskmgr());
+        for &page_id in &kept {
+            assert!(diskmgr.is_allocated(page_id), "page {} was lost", page_id);
+        }
+        assert!(!diskmgr.is_allocated(freed));
+        let page_id = diskmgr.allocate_page().unwrap();
+        assert!(!kept.contains(&page_id));
+        drop(diskmgr);
+        for &page_id in &kept {
+            assert_eq!(read_byte(&ctx, page_id, 100), 1);
+        }
+    }
+
+    #[derive(Copy, Clone, Debug)]
+    enum Op {
+        Insert(usize),
+        Delete(usize),
+    }
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("recovered-{:06}-{}", i, "x".repeat(80)).into_bytes()
+    }
+
+    fn ptr(i: usize) -> ObjectPtr {
+        ObjectPtr::new(i as PageId, i as u16)
+    }
+
+    /// Build a tree, then run inserts and deletes against it until the process is killed after `crash_after` writes.
+    /// Returns the tree's meta page, the operations that completed and the one that was interrupted
+    fn run_until_crash(path: &str, crash_after: usize) -> (PageId, Vec<Op>, Option<Op>) {
+        let faults = Arc::new(FaultInjector::default());
+        let diskmgr = DiskMgrInternal::open(path, OpenMode::CreateNew)
+            .unwrap()
+            .with_faults(faults.clone());
+        let wal = WalInternal::open(&DbContext::wal_path(path), OpenMode::CreateNew)
+            .unwrap()
+            .with_faults(faults.clone());
+        let bufmgr = Arc::new(parking_lot::RwLock::new(BufferPoolInternal::with_wal(
+            8,
+            2,
+            Arc::new(parking_lot::RwLock::new(diskmgr)),
+            Arc::new(parking_lot::RwLock::new(wal)),
+        )));
+        let tree = BLinkTree::create(bufmgr).unwrap();
+
+        faults.crash_after(crash_after);
+        let ops = (0..400)
+            .map(Op::Insert)
+            .chain((0..400).filter(|i| i % 3 != 0).map(Op::Delete));
+        let mut done = Vec::new();
+        for op in ops {
+            let result = match op {
+                Op::Insert(i) => tree.insert(&key(i), ptr(i)),
+                Op::Delete(i) => tree.delete(&key(i)).map(|deleted| assert!(deleted)),
+            };
+            if result.is_err() {
+                assert!(faults.has_crashed());
+                return (tree.get_meta_page_id(), done, Some(op));
+            }
+            done.push(op);
+        }
+        (tree.get_meta_page_id(), done, None)
+    }
+
+    #[test]
+    fn crash_points() {
+        for crash_after in [0, 1, 7, 40, 150, 333, 500, 700, 5000] {
+            let path = db_path("crash_points.bin");
+            let (meta_page_id, done, interrupted) = run_until_crash(&path, crash_after);
+
+            let ctx = DbContext::open(&path, Options::default()).unwrap();
+            let tree = BLinkTree::open(ctx.get_bufmgr().clone(), meta_page_id);
+            let mut expected = vec![false; 400];
+            for op in &done {
+                match *op {
+                    Op::Insert(i) => expected[i] = true,
+                    Op::Delete(i) => expected[i] = false,
+                }
+            }
+            // every completed operation survives, and the interrupted one happened entirely or not at all
+            let uncertain = match interrupted {
+                Some(Op::Insert(i)) | Some(Op::Delete(i)) => Some(i),
+                None => None,
+            };
+            for (i, &present) in expected.iter().enumerate() {
+                let found = tree.get(&key(i)).unwrap();
+                if Some(i) == uncertain {
+                    assert!(found.is_none() || found == Some(ptr(i)));
+                } else {
+                    assert_eq!(
+                        found,
+                        present.then(|| ptr(i)),
+                        "key {} after crash at {}",
+                        i,
+                        crash_after
+                    );
+                }
+            }
+
+            // the recovered tree is sound enough to keep working with
+            for i in 0..400 {
+                if tree.get(&key(i)).unwrap().is_none() {
+                    tree.insert(&key(i), ptr(i)).unwrap();
+                }
+            }
+            for i in 0..400 {
+                assert!(tree.delete(&key(i)).unwrap());
+            }
+            assert_eq!(tree.get(&key(0)).unwrap(), None);
+        }
+    }
+
+    /// The pages in use, other than the header and the bitmaps
+    fn pages_in_use(ctx: &DbContext) -> usize {
+        let diskmgr = rw_acquire_shared(ctx.get_diskmgr());
+        (0..diskmgr.get_page_count())
+            .filter(|&page_id| diskmgr.check_page_id(page_id).is_ok())
+            .count()
+    }
+
+    #[test]
+    fn crash_during_bulk_load_frees_its_pages() {
+        let baseline = {
+            let ctx = DbContext::open(&db_path("bulk_baseline.bin"), Options::default()).unwrap();
+            pages_in_use(&ctx)
+        };
+        for crash_after in [0, 3, 50, 200, 450, 5000] {
+            let path = db_path("bulk_crash_points.bin");
+            let faults = Arc::new(FaultInjector::default());
+            let diskmgr = DiskMgrInternal::open(&path, OpenMode::CreateNew)
+                .unwrap()
+                .with_faults(faults.clone());
+            let wal = Arc::new(parking_lot::RwLock::new(
+                WalInternal::open(&DbContext::wal_path(&path), OpenMode::CreateNew)
+                    .unwrap()
+                    .with_faults(faults.clone()),
+            ));
+            let bufmgr = Arc::new(parking_lot::RwLock::new(BufferPoolInternal::with_wal(
+                8,
+                2,
+                Arc::new(parking_lot::RwLock::new(diskmgr)),
+                wal.clone(),
+            )));
+
+            faults.crash_after(crash_after);
+            // other transactions commit while the load runs, so its allocations reach the log long before it ends
+            let entries = (0..20000).map(|i| {
+                if i % 1000 == 0 {
+                    let _ = rw_acquire_shared(&wal).flush_all();
+                }
+                (key(i), ptr(i))
+            });
+            let loaded = BLinkTree::bulk_load(bufmgr, entries, 100);
+            assert_eq!(loaded.is_err(), faults.has_crashed());
+            let meta_page_id = loaded.ok().map(|tree| tree.get_meta_page_id());
+
+            let ctx = DbContext::open(&path, Options::default()).unwrap();
+            match meta_page_id {
+                Some(meta_page_id) => {
+                    let tree = BLinkTree::open(ctx.get_bufmgr().clone(), meta_page_id);
+                    assert_eq!(tree.range(..).count(), 20000);
+                    assert_eq!(tree.get(&key(12345)).unwrap(), Some(ptr(12345)));
+                }
+                None => {
+                    assert!(ctx.get_recovery_stats().losers <= 1);
+                    assert_eq!(pages_in_use(&ctx), baseline, "crash at {}", crash_after);
+                    // the freed pages stay free once the bitmap is written back
+                    drop(ctx);
+                    let ctx = DbContext::open(&path, Options::default()).unwrap();
+                    assert_eq!(pages_in_use(&ctx), baseline, "crash at {}", crash_after);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,231 @@
+// SOURCES + USEFUL LINKS
+// https://www.cs.cmu.edu/~christos/courses/721-resources/p297-o_neil.pdf (The LRU-K Page Replacement Algorithm)
+use std::collections::{HashMap, VecDeque};
+
+use super::Replacer;
+use crate::concurrency::Synchronized;
+use crate::shared::FrameId;
+
+struct LruKNode {
+    /// Timestamps of the last K accesses to the frame, oldest first
+    history: VecDeque<u64>,
+    evictable: bool,
+}
+
+struct LruKReplacerInternal {
+    clock: u64,
+    nodes: HashMap<FrameId, LruKNode>,
+    num_evictable: usize,
+}
+
+/// Evicts the frame whose backward K-distance (the time since its K-th most recent access) is largest. Frames with fewer
+/// than K recorded accesses have an infinite backward K-distance and are evicted first, least recently used first. Every
+/// `pin` counts as an access
+pub struct LruKReplacer {
+    capacity: usize,
+    k: usize,
+    internal: Synchronized<LruKReplacerInternal>,
+}
+
+impl LruKReplacer {
+    pub fn new(capacity: usize, k: usize) -> Self {
+        assert!(k > 0, "LRU-K requires k > 0");
+        Self {
+            capacity,
+            k,
+            internal: std::sync::Arc::new(parking_lot::Mutex::new(LruKReplacerInternal {
+                clock: 0,
+                nodes: HashMap::with_capacity(capacity),
+                num_evictable: 0,
+            })),
+        }
+    }
+
+    /// Orders frames for eviction: (has K accesses, timestamp to compare). Frames with fewer than K accesses sort first
+    /// and among them the least recently used wins. Among the rest the oldest K-th most recent access wins
+    fn eviction_key(&self, node: &LruKNode) -> (bool, u64) {
+        if node.history.len() < self.k {
+            (false, node.history.back().copied().unwrap_or(0))
+        } else {
+            (true, node.history[0])
+        }
+    }
+
+    fn check_frame(&self, frame_id: FrameId) {
+        assert!(
+            frame_id >= 0 && (frame_id as usize) < self.capacity,
+            "frame {} is out of range for a replacer of capacity {}",
+            frame_id,
+            self.capacity
+        );
+    }
+}
+
+impl Replacer for LruKReplacer {
+    fn victim(&self) -> Option<FrameId> {
+        let mut internal = self.internal.lock();
+        let (_, frame_id) = internal
+            .nodes
+            .iter()
+            .filter(|(_, node)| node.evictable)
+            .map(|(frame_id, node)| (self.eviction_key(node), *frame_id))
+            .min()?;
+        internal.nodes.remove(&frame_id);
+        internal.num_evictable -= 1;
+        Some(frame_id)
+    }
+
+    fn pin(&self, frame_id: FrameId) {
+        self.check_frame(frame_id);
+        let mut internal = self.internal.lock();
+        let stamp = internal.clock;
+        internal.clock += 1;
+        let node = internal.nodes.entry(frame_id).or_insert_with(|| LruKNode {
+            history: VecDeque::with_capacity(self.k),
+            evictable: false,
+        });
+        if node.history.len() == self.k {
+            node.history.pop_front();
+        }
+        node.history.push_back(stamp);
+        if node.evictable {
+            node.evictable = false;
+            internal.num_evictable -= 1;
+        }
+    }
+
+    fn unpin(&self, frame_id: FrameId) {
+        self.check_frame(frame_id);
+        let mut internal = self.internal.lock();
+        let node = internal.nodes.entry(frame_id).or_insert_with(|| LruKNode {
+            history: VecDeque::with_capacity(self.k),
+            evictable: false,
+        });
+        if !node.evictable {
+            node.evictable = true;
+            internal.num_evictable += 1;
+        }
+    }
+
+    fn remove(&self, frame_id: FrameId) {
+        let mut internal = self.internal.lock();
+        if let Some(node) = internal.nodes.remove(&frame_id) {
+            if node.evictable {
+                internal.num_evictable -= 1;
+            }
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.internal.lock().num_evictable
+    }
+
+    fn is_evictable(&self, frame_id: FrameId) -> bool {
+        self.internal
+            .lock()
+            .nodes
+            .get(&frame_id)
+            .is_some_and(|node| node.evictable)
+    }
+
+    fn candidates(&self, n: usize) -> Vec<FrameId> {
+        let internal = self.internal.lock();
+        let mut evictable: Vec<((bool, u64), FrameId)> = internal
+            .nodes
+            .iter()
+            .filter(|(_, node)| node.evictable)
+            .map(|(frame_id, node)| (self.eviction_key(node), *frame_id))
+            .collect();
+        evictable.sort_unstable();
+        evictable
+            .into_iter()
+            .take(n)
+            .map(|(_, frame_id)| frame_id)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(replacer: &LruKReplacer, frame_id: FrameId) {
+        replacer.pin(frame_id);
+        replacer.unpin(frame_id);
+    }
+
+    #[test]
+    fn infinite_distance_first() {
+        let replacer = LruKReplacer::new(7, 2);
+        // frames 1..=5 are accessed once, frame 1 is accessed a second time
+        for frame_id in 1..=5 {
+            access(&replacer, frame_id);
+        }
+        access(&replacer, 1);
+        assert_eq!(replacer.size(), 5);
+        // the candidates are the victims to come, which stay where they are
+        assert_eq!(replacer.candidates(3), [2, 3, 4]);
+        assert_eq!(replacer.candidates(10), [2, 3, 4, 5, 1]);
+        assert!(replacer.is_evictable(2));
+
+        // frames 2..=5 have an infinite backward 2-distance and go in LRU order before frame 1
+        assert_eq!(replacer.victim(), Some(2));
+        assert_eq!(replacer.victim(), Some(3));
+
+        // a pinned frame is never evicted
+        replacer.pin(4);
+        assert!(!replacer.is_evictable(4));
+        assert_eq!(replacer.candidates(10), [5, 1]);
+        assert_eq!(replacer.victim(), Some(5));
+        assert_eq!(replacer.victim(), Some(1));
+        assert_eq!(replacer.victim(), None);
+
+        replacer.unpin(4);
+        assert_eq!(replacer.victim(), Some(4));
+        assert_eq!(replacer.size(), 0);
+    }
+
+    #[test]
+    fn largest_backward_k_distance() {
+        let replacer = LruKReplacer::new(4, 2);
+        // history: 0 -> [0, 3], 1 -> [1, 4], 2 -> [2, 5]
+        for frame_id in [0, 1, 2, 0, 1, 2] {
+            access(&replacer, frame_id);
+        }
+        // frame 0 is touched again: [3, 6]. Frame 1 now has the oldest second most recent access
+        access(&replacer, 0);
+        assert_eq!(replacer.victim(), Some(1));
+        assert_eq!(replacer.victim(), Some(2));
+        assert_eq!(replacer.victim(), Some(0));
+    }
+
+    #[test]
+    fn remove_forgets_history() {
+        let replacer = LruKReplacer::new(4, 2);
+        access(&replacer, 0);
+        access(&replacer, 0);
+        access(&replacer, 1);
+        replacer.remove(0);
+        assert_eq!(replacer.size(), 1);
+
+        // frame 0 starts over with a single access and is no longer protected by its old history
+        access(&replacer, 0);
+        assert_eq!(replacer.victim(), Some(1));
+        assert_eq!(replacer.victim(), Some(0));
+    }
+
+    #[test]
+    fn scan_resistance() {
+        let replacer = LruKReplacer::new(16, 2);
+        // a hot frame that is read twice, followed by a scan that touches every other frame once
+        access(&replacer, 0);
+        access(&replacer, 0);
+        for frame_id in 1..16 {
+            access(&replacer, frame_id);
+        }
+        for expected in 1..16 {
+            assert_eq!(replacer.victim(), Some(expected));
+        }
+        assert_eq!(replacer.victim(), Some(0));
+    }
+}
@@ -1,18 +1,149 @@
-#![allow(unused_variables)]
+use std::collections::{BTreeMap, HashMap};
+
 use super::Replacer;
+use crate::concurrency::Synchronized;
+use crate::shared::FrameId;
+
+struct LruReplacerInternal {
+    /// Monotonic counter used to order unpins. Smaller stamps were unpinned less recently
+    clock: u64,
+    /// Maps each evictable frame to the stamp of the unpin that made it evictable
+    stamps: HashMap<FrameId, u64>,
+    /// Evictable frames ordered from least to most recently unpinned
+    order: BTreeMap<u64, FrameId>,
+}
 
-pub struct LruReplacer {}
+/// Evicts the frame that was unpinned least recently
+pub struct LruReplacer {
+    capacity: usize,
+    internal: Synchronized<LruReplacerInternal>,
+}
+
+impl LruReplacer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            internal: std::sync::Arc::new(parking_lot::Mutex::new(LruReplacerInternal {
+                clock: 0,
+                stamps: HashMap::with_capacity(capacity),
+                order: BTreeMap::new(),
+            })),
+        }
+    }
+}
 
 impl Replacer for LruReplacer {
-    fn pin(frame_id: crate::shared::FrameId) {}
+    fn victim(&self) -> Option<FrameId> {
+        let mut internal = self.internal.lock();
+        let (_, frame_id) = internal.order.pop_first()?;
+        internal.stamps.remove(&frame_id);
+        Some(frame_id)
+    }
+
+    fn pin(&self, frame_id: FrameId) {
+        let mut internal = self.internal.lock();
+        if let Some(stamp) = internal.stamps.remove(&frame_id) {
+            internal.order.remove(&stamp);
+        }
+    }
+
+    fn unpin(&self, frame_id: FrameId) {
+        assert!(
+            frame_id >= 0 && (frame_id as usize) < self.capacity,
+            "frame {} is out of range for a replacer of capacity {}",
+            frame_id,
+            self.capacity
+        );
+        let mut internal = self.internal.lock();
+        // unpinning an already evictable frame does not refresh its position
+        if internal.stamps.contains_key(&frame_id) {
+            return;
+        }
+        let stamp = internal.clock;
+        internal.clock += 1;
+        internal.stamps.insert(frame_id, stamp);
+        internal.order.insert(stamp, frame_id);
+    }
+
+    fn remove(&self, frame_id: FrameId) {
+        self.pin(frame_id);
+    }
+
+    fn size(&self) -> usize {
+        self.internal.lock().stamps.len()
+    }
+
+    fn is_evictable(&self, frame_id: FrameId) -> bool {
+        self.internal.lock().stamps.contains_key(&frame_id)
+    }
+
+    fn candidates(&self, n: usize) -> Vec<FrameId> {
+        self.internal
+            .lock()
+            .order
+            .values()
+            .take(n)
+            .copied()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn victim_order() {
+        let replacer = LruReplacer::new(7);
+        for frame_id in [1, 2, 3, 4, 5, 6, 1] {
+            replacer.unpin(frame_id);
+        }
+        assert_eq!(replacer.size(), 6);
+        assert_eq!(replacer.candidates(2), [1, 2]);
+        assert!(replacer.is_evictable(6) && !replacer.is_evictable(0));
+
+        assert_eq!(replacer.victim(), Some(1));
+        assert_eq!(replacer.victim(), Some(2));
+        assert_eq!(replacer.victim(), Some(3));
+
+        replacer.pin(3);
+        replacer.pin(4);
+        assert_eq!(replacer.size(), 2);
 
-    fn size() -> usize {
-        0
+        replacer.unpin(4);
+        assert_eq!(replacer.victim(), Some(5));
+        assert_eq!(replacer.victim(), Some(6));
+        assert_eq!(replacer.victim(), Some(4));
+        assert_eq!(replacer.victim(), None);
+        assert_eq!(replacer.size(), 0);
     }
 
-    fn unpin(frame_id: crate::shared::FrameId) {}
+    #[test]
+    fn threaded_unpin_victim() {
+        let replacer = Arc::new(LruReplacer::new(64));
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(8)
+            .build()
+            .unwrap();
+        pool.scope(|scope| {
+            for t in 0..8 {
+                let replacer = replacer.clone();
+                scope.spawn(move |_| {
+                    for i in 0..8 {
+                        replacer.unpin(t * 8 + i);
+                    }
+                });
+            }
+        });
+        assert_eq!(replacer.size(), 64);
 
-    fn victim(frame_id: crate::shared::FrameId) -> bool {
-        false
+        let mut victims = Vec::new();
+        while let Some(frame_id) = replacer.victim() {
+            victims.push(frame_id);
+        }
+        victims.sort();
+        assert_eq!(victims, (0..64).collect::<Vec<FrameId>>());
     }
 }
@@ -1,10 +1,23 @@
+pub mod lrukreplacer;
 pub mod lrureplacer;
 
 use crate::shared::FrameId;
 
-pub(in crate::storage::replacer) trait Replacer {
-    fn victim(frame_id: FrameId) -> bool;
-    fn pin(frame_id: FrameId);
-    fn unpin(frame_id: FrameId);
-    fn size() -> usize;
+/// A Replacer tracks the frames of the buffer pool that are eligible for eviction. Frames become eligible when they are
+/// unpinned and stop being eligible when they are pinned again. Implementations must be safe to share between threads
+pub trait Replacer: Send + Sync {
+    /// Remove the frame chosen by the replacement policy and return it, or None if no frame is evictable
+    fn victim(&self) -> Option<FrameId>;
+    /// Mark a frame as in use. A pinned frame is never returned by `victim`
+    fn pin(&self, frame_id: FrameId);
+    /// Mark a frame as evictable
+    fn unpin(&self, frame_id: FrameId);
+    /// Forget everything known about a frame, e.g. because its page was deleted and the frame is free again
+    fn remove(&self, frame_id: FrameId);
+    /// The number of evictable frames
+    fn size(&self) -> usize;
+    /// Whether `victim` may choose the frame
+    fn is_evictable(&self, frame_id: FrameId) -> bool;
+    /// Up to `n` evictable frames in the order `victim` would choose them, without removing any
+    fn candidates(&self, n: usize) -> Vec<FrameId>;
 }
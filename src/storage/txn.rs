@@ -0,0 +1,440 @@
+// SOURCES + USEFUL LINKS
+// https://github.com/cmu-db/bustub/blob/master/src/include/concurrency/transaction.h
+// https://web.stanford.edu/class/cs345d-01/rl/aries.pdf (ARIES)
+use std::cell::RefCell;
+use std::ops::Range;
+
+use crate::concurrency::rw_acquire_shared;
+use crate::shared::{Lsn, PageId, TxnId, INVALID_LSN, INVALID_TXN_ID, PAGE_SIZE};
+use crate::storage::bufmgr::{BufferPoolFrame, BufferPoolInternal, BufferPoolResult};
+use crate::storage::page::{set_page_lsn, Page, PageWriteLatch};
+use crate::storage::page_guard::WritePageGuard;
+use crate::storage::wal::{LogBody, LogRecord};
+
+/// A change made by a transaction, kept so it can be rolled back without reading the log. `prev_lsn` is the
+/// transaction's previous record, which is where rolling back continues after the change
+enum UndoEntry {
+    Update {
+        page_id: PageId,
+        offset: usize,
+        before: Vec<u8>,
+        prev_lsn: Lsn,
+    },
+    Allocate {
+        page_id: PageId,
+        prev_lsn: Lsn,
+    },
+}
+
+struct TxnState {
+    /// LSN of the transaction's last log record
+    last_lsn: Lsn,
+    /// Pages the transaction changed. They stay latched and pinned until it ends, so no other operation sees or
+    /// overwrites a change that may still be rolled back
+    held: Vec<(BufferPoolFrame, PageWriteLatch)>,
+    undo: Vec<UndoEntry>,
+    committed: bool,
+}
+
+/// A group of page changes that become durable together or not at all. Pages are latched through the transaction, which
+/// logs what changed when a guard is dropped and keeps the page latched until `commit`. Dropping a transaction that was
+/// not committed rolls it back, logging a compensation record for every change it reverts.
+///
+/// A system transaction changes the structure of pages, as a split does, without changing their contents as a user sees
+/// them. It commits without waiting for the log: only transactions that log after it can depend on its changes, and
+/// their commit flushes its commit record along with their own. If it is lost in a crash, nothing that depends on it is
+/// durable either.
+///
+/// A transaction is used by one thread. Since it holds the latches of the pages it changed, it must take latches in the
+/// same order as everyone else
+pub struct Transaction<'a> {
+    bufmgr: &'a BufferPoolInternal,
+    txn_id: TxnId,
+    system: bool,
+    state: RefCell<TxnState>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(bufmgr: &'a BufferPoolInternal, system: bool) -> Self {
+        let txn_id = match bufmgr.get_wal() {
+            Some(wal) => rw_acquire_shared(wal).next_txn_id(),
+            None => INVALID_TXN_ID,
+        };
+        Self {
+            bufmgr,
+            txn_id,
+            system,
+            state: RefCell::new(TxnState {
+                last_lsn: INVALID_LSN,
+                held: Vec::new(),
+                undo: Vec::new(),
+                committed: false,
+            }),
+        }
+    }
+
+    #[inline]
+    pub fn get_txn_id(&self) -> TxnId {
+        self.txn_id
+    }
+
+    /// Fetch a page and latch it exclusively on behalf of the transaction. A page the transaction already changed is
+    /// handed back to it without latching it again
+    pub fn fetch_page_write(&'a self, page_id: PageId) -> BufferPoolResult<WritePageGuard<'a>> {
+        let held = {
+            let mut state = self.state.borrow_mut();
+            let idx = state
+                .held
+                .iter()
+                .position(|(frame, _)| frame.get_page().get_id() == page_id);
+            idx.map(|idx| state.held.swap_remove(idx))
+        };
+        if let Some((frame, latch)) = held {
+            return Ok(WritePageGuard::resume(self.bufmgr, frame, latch, self));
+        }
+        let frame_id = self.bufmgr.fetch_page(page_id)?;
+        Ok(WritePageGuard::new(
+            self.bufmgr,
+            self.bufmgr.frame(frame_id),
+            Some(self),
+            false,
+        ))
+    }
+
+    /// Allocate a new page and latch it exclusively on behalf of the transaction. The page is freed again if the
+    /// transaction rolls back
+    pub fn new_page_write(&'a self) -> BufferPoolResult<WritePageGuard<'a>> {
+        let (_, frame_id) = self.bufmgr.new_page_with(|| self.allocate_page())?;
+        Ok(WritePageGuard::new(
+            self.bufmgr,
+            self.bufmgr.frame(frame_id),
+            Some(self),
+            true,
+        ))
+    }
+
+    /// Take an unused page id from the disk manager on behalf of the transaction. The allocation is logged in the
+    /// transaction's chain, so that rolling it back, or recovering from a crash before it commits, frees the page
+    pub fn allocate_page(&self) -> std::io::Result<PageId> {
+        let mut state = self.state.borrow_mut();
+        let prev_lsn = state.last_lsn;
+        let diskmgr = rw_acquire_shared(self.bufmgr.get_diskmgr());
+        let page_id = match self.bufmgr.get_wal() {
+            Some(wal) => diskmgr.allocate_page_logged(|page_id| {
+                state.last_lsn = rw_acquire_shared(wal).append(&LogRecord {
+                    txn_id: self.txn_id,
+                    prev_lsn,
+                    body: LogBody::AllocatePage { page_id },
+                });
+            })?,
+            None => diskmgr.allocate_page()?,
+        };
+        state.undo.push(UndoEntry::Allocate { page_id, prev_lsn });
+        Ok(page_id)
+    }
+
+    /// Log a change a guard made to a page of the transaction and stamp the page with the LSN of the record
+    pub(crate) fn log_update(
+        &self,
+        page: &Page,
+        latch: &mut PageWriteLatch,
+        range: Range<usize>,
+        before: &[u8; PAGE_SIZE],
+    ) {
+        let mut state = self.state.borrow_mut();
+        let page_id = page.get_id();
+        let prev_lsn = state.last_lsn;
+        if let Some(wal) = self.bufmgr.get_wal() {
+            let record = LogRecord {
+                txn_id: self.txn_id,
+                prev_lsn,
+                body: LogBody::Update {
+                    page_id,
+                    offset: range.start as u16,
+                    before: before[range.clone()].to_vec(),
+                    after: latch[range.clone()].to_vec(),
+                },
+            };
+            let wal = rw_acquire_shared(wal);
+            // see WritePageGuard::drop for why the recLSN is set before appending
+            page.mark_dirty_at(wal.get_next_lsn());
+            state.last_lsn = wal.append(&record);
+            set_page_lsn(latch, state.last_lsn);
+        }
+        state.undo.push(UndoEntry::Update {
+            page_id,
+            offset: range.start,
+            before: before[range].to_vec(),
+            prev_lsn,
+        });
+    }
+
+    /// Keep a page latched and pinned until the transaction ends
+    pub(crate) fn retain(&self, frame: BufferPoolFrame, latch: PageWriteLatch) {
+        self.state.borrow_mut().held.push((frame, latch));
+    }
+
+    /// Make the transaction's changes durable. The pages it holds are released before waiting for the log, so other
+    /// operations can use them while the commit record is flushed together with those of concurrent transactions. A
+    /// system transaction does not wait
+    pub fn commit(self) -> std::io::Result<()> {
+        let (commit_lsn, held) = {
+            let mut state = self.state.borrow_mut();
+            let commit_lsn = match self.bufmgr.get_wal() {
+                Some(wal) if state.last_lsn != INVALID_LSN => {
+                    let record = LogRecord {
+                        txn_id: self.txn_id,
+                        prev_lsn: state.last_lsn,
+                        body: LogBody::Commit,
+                    };
+                    Some(rw_acquire_shared(wal).append(&record))
+                }
+                _ => None,
+            };
+            state.committed = true;
+            (commit_lsn, std::mem::take(&mut state.held))
+        };
+        self.release(held);
+        match (commit_lsn, self.bufmgr.get_wal()) {
+            (Some(lsn), Some(wal)) if !self.system => rw_acquire_shared(wal).flush(lsn),
+            _ => Ok(()),
+        }
+    }
+
+    /// Revert every change of the transaction, newest first. Returns the pages it allocated, which can only be freed once
+    /// the pages it holds are released
+    fn rollback(&self) -> Vec<PageId> {
+        let mut state = self.state.borrow_mut();
+        let state = &mut *state;
+        let wal = self.bufmgr.get_wal();
+        let mut allocated = Vec::new();
+        while let Some(entry) = state.undo.pop() {
+            let (page_id, offset, before, prev_lsn) = match entry {
+                UndoEntry::Update {
+                    page_id,
+                    offset,
+                    before,
+                    prev_lsn,
+                } => (page_id, offset, before, prev_lsn),
+                UndoEntry::Allocate { page_id, prev_lsn } => {
+                    allocated.push(page_id);
+                    if let Some(wal) = wal {
+                        let record = LogRecord {
+                            txn_id: self.txn_id,
+                            prev_lsn: state.last_lsn,
+                            body: LogBody::UndoAllocate {
+                                page_id,
+                                undo_next: prev_lsn,
+                            },
+                        };
+                        state.last_lsn = rw_acquire_shared(wal).append(&record);
+                    }
+                    continue;
+                }
+            };
+            let (frame, latch) = state
+                .held
+                .iter_mut()
+                .find(|(frame, _)| frame.get_page().get_id() == page_id)
+                .expect("changed page is held by the transaction");
+            let range = offset..offset + before.len();
+            latch[range].copy_from_slice(&before);
+            if let Some(wal) = wal {
+                let record = LogRecord {
+                    txn_id: self.txn_id,
+                    prev_lsn: state.last_lsn,
+                    body: LogBody::Compensation {
+                        page_id,
+                        offset: offset as u16,
+                        after: before,
+                        undo_next: prev_lsn,
+                    },
+                };
+                let wal = rw_acquire_shared(wal);
+                frame.get_page().mark_dirty_at(wal.get_next_lsn());
+                let lsn = wal.append(&record);
+                set_page_lsn(latch, lsn);
+                state.last_lsn = lsn;
+            }
+        }
+        if let Some(wal) = wal.filter(|_| state.last_lsn != INVALID_LSN) {
+            let record = LogRecord {
+                txn_id: self.txn_id,
+                prev_lsn: state.last_lsn,
+                body: LogBody::Abort,
+            };
+            state.last_lsn = rw_acquire_shared(wal).append(&record);
+        }
+        allocated
+    }
+
+    fn release(&self, held: Vec<(BufferPoolFrame, PageWriteLatch)>) {
+        for (frame, latch) in held {
+            drop(latch);
+            self.bufmgr.unpin_frame(frame.get_frame_id(), true);
+        }
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        let committed = self.state.borrow().committed;
+        let allocated = if committed {
+            Vec::new()
+        } else {
+            self.rollback()
+        };
+        let held = std::mem::take(&mut self.state.borrow_mut().held);
+        self.release(held);
+        // a page that cannot be freed now stays allocated, but nothing reaches it
+        let _ = self.bufmgr.discard_pages(&allocated);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::shared::PAGE_SIZE;
+    use crate::storage::bufmgr::BufferPoolInternal;
+    use crate::storage::diskmgr::{DiskMgrInternal, OpenMode};
+    use crate::storage::page::{get_page_lsn, PAGE_LSN_SIZE};
+    use crate::storage::wal::{LogBody, Wal, WalInternal};
+
+    fn make_pool(name: &str) -> (BufferPoolInternal, Wal) {
+        let dir = crate::shared::cwd() + "/data/test/__txn__/";
+        let diskmgr = Arc::new(parking_lot::RwLock::new(DiskMgrInternal::recreate(
+            &(dir.clone() + name + ".bin"),
+        )));
+        let wal_path = dir + name + "_wal.bin";
+        let _ = std::fs::remove_file(&wal_path);
+        let wal = Arc::new(parking_lot::RwLock::new(
+            WalInternal::open(&wal_path, OpenMode::CreateNew).unwrap(),
+        ));
+        (
+            BufferPoolInternal::with_wal(4, 1, diskmgr, wal.clone()),
+            wal,
+        )
+    }
+
+    fn bodies(wal: &Wal) -> Vec<LogBody> {
+        let wal = wal.read();
+        wal.flush_all().unwrap();
+        wal.iter_from(0).unwrap().map(|(_, r)| r.body).collect()
+    }
+
+    #[test]
+    fn commit_is_durable() {
+        let (pool, wal) = make_pool("commit");
+        let page_id = {
+            let mut guard = pool.new_page_write().unwrap();
+            guard[PAGE_LSN_SIZE] = 1;
+            guard.get_page_id()
+        };
+        let flushed = wal.read().get_flushed_lsn();
+
+        let txn = pool.begin();
+        {
+            let mut guard = txn.fetch_page_write(page_id).unwrap();
+            guard[100] = 7;
+            guard[200] = 8;
+        }
+        // the page stays latched by the transaction until it commits
+        assert!(pool.frame(0).get_page().get_pin_count() > 0);
+        let txn_id = txn.get_txn_id();
+        txn.commit().unwrap();
+        assert!(wal.read().get_flushed_lsn() > flushed);
+        assert_eq!(pool.frame(0).get_page().get_pin_count(), 0);
+
+        let guard = pool.fetch_page_read(page_id).unwrap();
+        assert_eq!((guard[100], guard[200]), (7, 8));
+        let lsn = get_page_lsn(&guard);
+        drop(guard);
+        let record = wal.read().read_record(lsn).unwrap();
+        assert_eq!(record.txn_id, txn_id);
+        match record.body {
+            LogBody::Update {
+                offset,
+                before,
+                after,
+                ..
+            } => {
+                // only the bytes that changed are logged
+                assert_eq!(offset, 100);
+                assert_eq!(after.len(), 101);
+                assert_eq!((before[0], after[0], after[100]), (0, 7, 8));
+            }
+            body => panic!("unexpected record {:?}", body),
+        }
+        assert_eq!(bodies(&wal).last(), Some(&LogBody::Commit));
+    }
+
+    #[test]
+    fn drop_rolls_back() {
+        let (pool, wal) = make_pool("rollback");
+        let page_ids: Vec<_> = (0..2)
+            .map(|_| {
+                let mut guard = pool.new_page_write().unwrap();
+                guard[PAGE_SIZE - 1] = 1;
+                guard.get_page_id()
+            })
+            .collect();
+
+        {
+            let txn = pool.begin();
+            for &page_id in &page_ids {
+                txn.fetch_page_write(page_id).unwrap()[PAGE_SIZE - 1] = 2;
+            }
+            // a page the transaction holds is handed back to it instead of being latched again
+            txn.fetch_page_write(page_ids[0]).unwrap()[PAGE_SIZE - 1] = 3;
+        }
+        for &page_id in &page_ids {
+            assert_eq!(pool.fetch_page_read(page_id).unwrap()[PAGE_SIZE - 1], 1);
+        }
+        let bodies = bodies(&wal);
+        let tail: Vec<&str> = bodies[bodies.len() - 7..]
+            .iter()
+            .map(|body| match body {
+                LogBody::Update { .. } => "update",
+                LogBody::Compensation { .. } => "clr",
+                LogBody::Commit => "commit",
+                LogBody::Abort => "abort",
+                LogBody::BeginCheckpoint | LogBody::EndCheckpoint { .. } => "checkpoint",
+                LogBody::AllocatePage { .. }
+                | LogBody::FreePage { .. }
+                | LogBody::UndoAllocate { .. } => "space",
+            })
+            .collect();
+        assert_eq!(
+            tail,
+            ["update", "update", "update", "clr", "clr", "clr", "abort"]
+        );
+    }
+
+    #[test]
+    fn rollback_frees_allocated_pages() {
+        let (pool, wal) = make_pool("rollback_alloc");
+        let page_id = {
+            let txn = pool.begin();
+            let mut guard = txn.new_page_write().unwrap();
+            guard[100] = 1;
+            guard.get_page_id()
+        };
+        let diskmgr = pool.get_diskmgr().read();
+        assert!(!diskmgr.is_allocated(page_id));
+        assert_eq!(diskmgr.allocate_page().unwrap(), page_id);
+        drop(diskmgr);
+        let bodies = bodies(&wal);
+        assert!(matches!(
+            bodies[..],
+            [
+                LogBody::AllocatePage { .. },
+                LogBody::Update { .. },
+                LogBody::Compensation { .. },
+                LogBody::UndoAllocate { .. },
+                LogBody::Abort,
+                LogBody::FreePage { .. },
+            ]
+        ));
+    }
+}
@@ -0,0 +1,569 @@
+// SOURCES + USEFUL LINKS
+// https://github.com/cmu-db/bustub/blob/master/src/include/recovery/log_manager.h
+// https://github.com/postgres/postgres/blob/master/src/backend/access/transam/README (Write-Ahead Log Coding)
+// https://web.stanford.edu/class/cs345d-01/rl/aries.pdf (ARIES)
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::concurrency::{acquire, Synchronized};
+use crate::shared::{Lsn, PageId, TxnId, INVALID_TXN_ID};
+use crate::storage::diskmgr::{DiskError, DiskResult, FaultInjector, OpenMode, WriteFate};
+
+/// Identifies a log file. The first record starts right after the log header, so no record has LSN 0
+const LOG_MAGIC: [u8; 8] = *b"SYMBWAL1";
+const LOG_HEADER_SIZE: u64 = 16;
+/// Every record is framed by its length and a checksum of its bytes
+const FRAME_HEADER_SIZE: usize = 8;
+/// Appends flush the buffer themselves once it grows past this size
+const LOG_BUFFER_SIZE: usize = 1 << 16;
+
+/// What a log record describes
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum LogBody {
+    /// Bytes `offset..offset + after.len()` of a page changed from `before` to `after`
+    Update {
+        page_id: PageId,
+        offset: u16,
+        before: Vec<u8>,
+        after: Vec<u8>,
+    },
+    /// Written while rolling back an update, which it reverts by writing `after`. It is never undone itself, and
+    /// `undo_next` is the next record of the transaction left to roll back
+    Compensation {
+        page_id: PageId,
+        offset: u16,
+        after: Vec<u8>,
+        undo_next: Lsn,
+    },
+    Commit,
+    /// Written once a rollback has completed
+    Abort,
+    /// Starts a fuzzy checkpoint. Recovery begins its analysis at the last complete one
+    BeginCheckpoint,
+    /// Completes the checkpoint begun by the last `BeginCheckpoint`, with the buffer pool's dirty pages and their
+    /// recLSNs and the running transactions and their last LSNs, as they were at some point after the begin record
+    EndCheckpoint {
+        dirty_pages: Vec<(PageId, Lsn)>,
+        active_txns: Vec<(TxnId, Lsn)>,
+    },
+    /// The disk manager handed out a page, to a transaction or outside of any. The bitmap only reaches the disk at
+    /// checkpoints, so recovery marks the page as in use again, and frees it if its transaction is rolled back
+    AllocatePage {
+        page_id: PageId,
+    },
+    /// A page was given back to the disk manager. Logged outside of any transaction
+    FreePage {
+        page_id: PageId,
+    },
+    /// Written while rolling back an `AllocatePage`, whose page is freed. Like a `Compensation`, it is never undone itself,
+    /// and `undo_next` is the next record of the transaction left to roll back
+    UndoAllocate {
+        page_id: PageId,
+        undo_next: Lsn,
+    },
+}
+
+/// A record of the write-ahead log. The records of a transaction are chained backwards through `prev_lsn`
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct LogRecord {
+    pub txn_id: TxnId,
+    pub prev_lsn: Lsn,
+    pub body: LogBody,
+}
+
+/// FNV-1a, enough to tell a torn write at the end of the log from a complete record
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5u32, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+/// Records appended but not yet written to the log file
+struct LogBuffer {
+    bytes: Vec<u8>,
+    /// LSN of the first buffered byte
+    start_lsn: Lsn,
+    /// LSN the next record will get
+    next_lsn: Lsn,
+    /// Set while a thread is writing the buffer out with the buffer unlocked
+    flushing: bool,
+    /// Transactions that logged a change but have not ended yet, with the LSN of their last record
+    active_txns: HashMap<TxnId, Lsn>,
+}
+
+/// An append-only write-ahead log. An LSN is the offset of a record in the log file, so LSNs grow monotonically.
+///
+/// Records are appended to an in-memory buffer and made durable by `flush`, which implements group commit: one thread
+/// at a time writes out and fsyncs everything buffered so far, while threads that want their records flushed in the
+/// meantime wait for it and are usually covered by the same or the next fsync
+pub struct WalInternal {
+    file_handle: Synchronized<File>,
+    file_path: String,
+    buffer: Synchronized<LogBuffer>,
+    /// Signalled whenever a flush completes
+    flushed: parking_lot::Condvar,
+    /// Every record below this LSN is durable
+    flushed_lsn: AtomicU64,
+    next_txn_id: AtomicU64,
+    num_fsyncs: AtomicUsize,
+    faults: Option<Arc<FaultInjector>>,
+}
+
+impl WalInternal {
+    /// Open or create the log file at `file_path`. A torn record at the end of an existing log, left by a crash in the
+    /// middle of a flush, is cut off
+    pub fn open(file_path: &str, mode: OpenMode) -> DiskResult<Self> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        match mode {
+            OpenMode::CreateNew => options.create_new(true),
+            OpenMode::OpenExisting => &mut options,
+            OpenMode::OpenOrCreate => options.create(true),
+        };
+        let mut file =
+            options
+                .open(std::path::Path::new(file_path))
+                .map_err(|e| match e.kind() {
+                    std::io::ErrorKind::AlreadyExists => DiskError::AlreadyExists(file_path.into()),
+                    std::io::ErrorKind::NotFound => DiskError::NotFound(file_path.into()),
+                    _ => DiskError::Io(e),
+                })?;
+
+        let file_len = file.metadata()?.len();
+        let (end, max_txn_id) = if file_len == 0 && mode != OpenMode::OpenExisting {
+            let mut header = [0u8; LOG_HEADER_SIZE as usize];
+            header[..LOG_MAGIC.len()].copy_from_slice(&LOG_MAGIC);
+            file.write_all(&header)?;
+            file.sync_all()?;
+            (LOG_HEADER_SIZE, INVALID_TXN_ID)
+        } else {
+            let mut header = [0u8; LOG_HEADER_SIZE as usize];
+            file.seek(SeekFrom::Start(0))?;
+            if file.read_exact(&mut header).is_err() || header[..LOG_MAGIC.len()] != LOG_MAGIC {
+                return Err(DiskError::NotADatabase(file_path.into()));
+            }
+            let mut end = LOG_HEADER_SIZE;
+            let mut max_txn_id = INVALID_TXN_ID;
+            for (lsn, record) in LogIterator::open(file_path, LOG_HEADER_SIZE)? {
+                max_txn_id = max_txn_id.max(record.txn_id);
+                end = lsn
+                    + (FRAME_HEADER_SIZE + bincode::serialized_size(&record).unwrap() as usize)
+                        as u64;
+            }
+            if end < file_len {
+                file.set_len(end)?;
+                file.sync_all()?;
+            }
+            (end, max_txn_id)
+        };
+
+        Ok(Self {
+            file_handle: Arc::new(parking_lot::Mutex::new(file)),
+            file_path: String::from(file_path),
+            buffer: Arc::new(parking_lot::Mutex::new(LogBuffer {
+                bytes: Vec::new(),
+                start_lsn: end,
+                next_lsn: end,
+                flushing: false,
+                active_txns: HashMap::new(),
+            })),
+            flushed: parking_lot::Condvar::new(),
+            flushed_lsn: AtomicU64::new(end),
+            next_txn_id: AtomicU64::new(max_txn_id + 1),
+            num_fsyncs: AtomicUsize::new(0),
+            faults: None,
+        })
+    }
+
+    /// Route every write through `faults`, which decides when the process dies. The write during which it dies is torn
+    pub fn with_faults(mut self, faults: Arc<FaultInjector>) -> Self {
+        self.faults = Some(faults);
+        self
+    }
+
+    #[inline]
+    pub fn get_file_path(&self) -> &str {
+        &self.file_path
+    }
+
+    /// Every record with a smaller LSN is durable
+    #[inline]
+    pub fn get_flushed_lsn(&self) -> Lsn {
+        self.flushed_lsn.load(Ordering::Acquire)
+    }
+
+    /// The LSN the next appended record will get
+    pub fn get_next_lsn(&self) -> Lsn {
+        acquire(&self.buffer).next_lsn
+    }
+
+    /// The transactions that have logged changes but not ended, with the LSN of their last record
+    pub fn active_txns(&self) -> Vec<(TxnId, Lsn)> {
+        acquire(&self.buffer)
+            .active_txns
+            .iter()
+            .map(|(&txn_id, &lsn)| (txn_id, lsn))
+            .collect()
+    }
+
+    #[inline]
+    pub fn get_num_fsyncs(&self) -> usize {
+        self.num_fsyncs.load(Ordering::Relaxed)
+    }
+
+    /// Hand out an id for a new transaction. Ids are never reused, not even across restarts
+    pub fn next_txn_id(&self) -> TxnId {
+        self.next_txn_id.fetch_add(1, Ordering::AcqRel)
+    }
+
+    /// Add a record to the log buffer and return its LSN. The record is not durable until `flush` is called with an LSN
+    /// at least as large
+    pub fn append(&self, record: &LogRecord) -> Lsn {
+        let payload = bincode::serialize(record).unwrap();
+        let (lsn, full) = {
+            let mut buffer = acquire(&self.buffer);
+            let lsn = buffer.next_lsn;
+            buffer
+                .bytes
+                .extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            buffer
+                .bytes
+                .extend_from_slice(&checksum(&payload).to_le_bytes());
+            buffer.bytes.extend_from_slice(&payload);
+            buffer.next_lsn += (FRAME_HEADER_SIZE + payload.len()) as u64;
+            if record.txn_id != INVALID_TXN_ID {
+                match record.body {
+                    LogBody::Commit | LogBody::Abort => {
+                        buffer.active_txns.remove(&record.txn_id);
+                    }
+                    _ => {
+                        buffer.active_txns.insert(record.txn_id, lsn);
+                    }
+                }
+            }
+            (lsn, buffer.bytes.len() >= LOG_BUFFER_SIZE)
+        };
+        if full {
+            // a failed flush leaves the records buffered, and whoever needs them durable gets the error from its own flush
+            let _ = self.flush(lsn);
+        }
+        lsn
+    }
+
+    /// Make every record up to and including the one at `lsn` durable. An LSN past the end of the log flushes everything
+    /// appended so far
+    pub fn flush(&self, lsn: Lsn) -> std::io::Result<()> {
+        if self.get_flushed_lsn() > lsn {
+            return Ok(());
+        }
+        let mut buffer = acquire(&self.buffer);
+        loop {
+            let target = (lsn + 1).min(buffer.next_lsn);
+            if self.get_flushed_lsn() >= target {
+                return Ok(());
+            }
+            if buffer.flushing {
+                // someone else is writing. Whatever they do not cover, the next flusher will
+                self.flushed.wait(&mut buffer);
+                continue;
+            }
+
+            let bytes = std::mem::take(&mut buffer.bytes);
+            let start_lsn = buffer.start_lsn;
+            buffer.start_lsn = buffer.next_lsn;
+            buffer.flushing = true;
+            let result = parking_lot::MutexGuard::unlocked(&mut buffer, || {
+                let mut file = acquire(&self.file_handle);
+                file.seek(SeekFrom::Start(start_lsn))?;
+                match self.faults.as_ref().map(|faults| faults.next_write()) {
+                    None | Some(WriteFate::Complete) => {}
+                    Some(WriteFate::Torn) => {
+                        file.write_all(&bytes[..bytes.len() / 2])?;
+                        return Err(FaultInjector::crash_error());
+                    }
+                    Some(WriteFate::Lost) => return Err(FaultInjector::crash_error()),
+                }
+                file.write_all(&bytes)?;
+                file.sync_data()
+            });
+            buffer.flushing = false;
+            match result {
+                Ok(()) => {
+                    self.num_fsyncs.fetch_add(1, Ordering::Relaxed);
+                    self.flushed_lsn
+                        .store(start_lsn + bytes.len() as u64, Ordering::Release);
+                    self.flushed.notify_all();
+                }
+                Err(e) => {
+                    // put the bytes back so that a later flush writes them
+                    let mut restored = bytes;
+                    restored.append(&mut buffer.bytes);
+                    buffer.bytes = restored;
+                    buffer.start_lsn = start_lsn;
+                    self.flushed.notify_all();
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Flush every record appended so far
+    pub fn flush_all(&self) -> std::io::Result<()> {
+        self.flush(Lsn::MAX - 1)
+    }
+
+    /// Read the record at `lsn`, which must be durable
+    pub fn read_record(&self, lsn: Lsn) -> std::io::Result<LogRecord> {
+        LogIterator::open(&self.file_path, lsn)?
+            .next()
+            .map(|(_, record)| record)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("no log record at lsn {}", lsn),
+                )
+            })
+    }
+
+    /// Iterate over the durable records starting at `lsn`, which must be the LSN of a record
+    pub fn iter_from(&self, lsn: Lsn) -> std::io::Result<LogIterator> {
+        LogIterator::open(&self.file_path, lsn.max(LOG_HEADER_SIZE))
+    }
+
+    /// Flush the log before closing it
+    pub fn close(&self) -> std::io::Result<()> {
+        self.flush_all()
+    }
+}
+
+pub type Wal = Arc<parking_lot::RwLock<WalInternal>>;
+
+/// Reads log records in order until the end of the log or the first torn record
+pub struct LogIterator {
+    reader: BufReader<File>,
+    lsn: Lsn,
+    /// Length of the file when the iterator was opened. Records past it were not durable yet
+    end: u64,
+}
+
+impl LogIterator {
+    /// Read the log at `file_path` through a handle of its own. A handle cloned from the log's would share its file offset,
+    /// which a flush relies on between seeking and writing
+    fn open(file_path: &str, lsn: Lsn) -> std::io::Result<Self> {
+        let mut file = File::open(file_path)?;
+        let end = file.metadata()?.len();
+        file.seek(SeekFrom::Start(lsn))?;
+        Ok(Self {
+            reader: BufReader::new(file),
+            lsn,
+            end,
+        })
+    }
+}
+
+impl Iterator for LogIterator {
+    type Item = (Lsn, LogRecord);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut frame = [0u8; FRAME_HEADER_SIZE];
+        self.reader.read_exact(&mut frame).ok()?;
+        let len = u32::from_le_bytes(frame[..4].try_into().unwrap()) as usize;
+        let sum = u32::from_le_bytes(frame[4..].try_into().unwrap());
+        // a torn frame header can claim any length, which must not be allocated before the checksum rejects it
+        if len as u64 > self.end.saturating_sub(self.lsn + FRAME_HEADER_SIZE as u64) {
+            return None;
+        }
+        let mut payload = vec![0u8; len];
+        self.reader.read_exact(&mut payload).ok()?;
+        if checksum(&payload) != sum {
+            return None;
+        }
+        let record = bincode::deserialize(&payload).ok()?;
+        let lsn = self.lsn;
+        self.lsn += (FRAME_HEADER_SIZE + len) as u64;
+        Some((lsn, record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(crate) fn wal_path(name: &str) -> String {
+        let path = crate::shared::cwd() + "/data/test/__wal__/" + name;
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn update(txn_id: TxnId, prev_lsn: Lsn, page_id: PageId) -> LogRecord {
+        LogRecord {
+            txn_id,
+            prev_lsn,
+            body: LogBody::Update {
+                page_id,
+                offset: 8,
+                before: vec![0; 4],
+                after: vec![page_id as u8; 4],
+            },
+        }
+    }
+
+    #[test]
+    fn append_flush_read() {
+        let path = wal_path("append_flush_read.bin");
+        let (lsns, records) = {
+            let wal = WalInternal::open(&path, OpenMode::CreateNew).unwrap();
+            let txn_id = wal.next_txn_id();
+            let mut prev_lsn = 0;
+            let mut lsns = Vec::new();
+            let mut records = Vec::new();
+            for page_id in 1..=10 {
+                let record = update(txn_id, prev_lsn, page_id);
+                prev_lsn = wal.append(&record);
+                assert!(lsns.last().is_none_or(|&last| prev_lsn > last));
+                lsns.push(prev_lsn);
+                records.push(record);
+            }
+            // nothing reaches the file before a flush
+            assert_eq!(wal.iter_from(0).unwrap().count(), 0);
+            wal.flush(lsns[4]).unwrap();
+            assert!(wal.get_flushed_lsn() > lsns[4]);
+            assert_eq!(wal.read_record(lsns[4]).unwrap(), records[4]);
+            wal.close().unwrap();
+            (lsns, records)
+        };
+
+        // the records survive a reopen, after which appends continue at the end
+        let wal = WalInternal::open(&path, OpenMode::OpenExisting).unwrap();
+        let read: Vec<(Lsn, LogRecord)> = wal.iter_from(0).unwrap().collect();
+        assert_eq!(read.iter().map(|(lsn, _)| *lsn).collect::<Vec<_>>(), lsns);
+        assert_eq!(
+            read.into_iter().map(|(_, r)| r).collect::<Vec<_>>(),
+            records
+        );
+        assert!(wal.next_txn_id() > records[0].txn_id);
+        let lsn = wal.append(&update(9, 0, 1));
+        assert!(lsn > *lsns.last().unwrap());
+        assert_eq!(wal.iter_from(lsns[9]).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn torn_tail_is_cut_off() {
+        let path = wal_path("torn_tail.bin");
+        let last = {
+            let wal = WalInternal::open(&path, OpenMode::CreateNew).unwrap();
+            for page_id in 0..3 {
+                wal.append(&update(1, 0, page_id));
+            }
+            let last = wal.append(&update(1, 0, 3));
+            wal.close().unwrap();
+            last
+        };
+        // a crash in the middle of writing the last record
+        let len = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+        let wal = WalInternal::open(&path, OpenMode::OpenExisting).unwrap();
+        assert_eq!(wal.iter_from(0).unwrap().count(), 3);
+        assert_eq!(wal.get_next_lsn(), last);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), last);
+        drop(wal);
+
+        // garbage after the last record whose frame header claims a huge length
+        let mut garbage = vec![0xffu8; FRAME_HEADER_SIZE];
+        garbage.extend_from_slice(b"not a record");
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&garbage)
+            .unwrap();
+        let wal = WalInternal::open(&path, OpenMode::OpenExisting).unwrap();
+        assert_eq!(wal.iter_from(0).unwrap().count(), 3);
+        assert_eq!(wal.get_next_lsn(), last);
+
+        let foreign = wal_path("foreign.bin");
+        std::fs::write(&foreign, "definitely not a log").unwrap();
+        assert!(matches!(
+            WalInternal::open(&foreign, OpenMode::OpenExisting),
+            Err(DiskError::NotADatabase(_))
+        ));
+    }
+
+    /// Reading the log while it is flushed must not move the offset a flush writes at
+    #[test]
+    fn reads_during_flush() {
+        let path = wal_path("reads_during_flush.bin");
+        let wal = WalInternal::open(&path, OpenMode::CreateNew).unwrap();
+        let records: Vec<LogRecord> = (0..500).map(|page_id| update(1, 0, page_id)).collect();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for record in &records {
+                    let lsn = wal.append(record);
+                    wal.flush(lsn).unwrap();
+                }
+            });
+            scope.spawn(|| {
+                for _ in 0..200 {
+                    let read: Vec<LogRecord> = wal
+                        .iter_from(0)
+                        .unwrap()
+                        .map(|(_, record)| record)
+                        .collect();
+                    assert_eq!(read[..], records[..read.len()]);
+                }
+            });
+        });
+        wal.close().unwrap();
+        drop(wal);
+        let wal = WalInternal::open(&path, OpenMode::OpenExisting).unwrap();
+        let read: Vec<LogRecord> = wal
+            .iter_from(0)
+            .unwrap()
+            .map(|(_, record)| record)
+            .collect();
+        assert_eq!(read, records);
+    }
+
+    #[test]
+    fn group_commit() {
+        let path = wal_path("group_commit.bin");
+        let wal = WalInternal::open(&path, OpenMode::CreateNew).unwrap();
+        let appended = std::sync::Barrier::new(8);
+        std::thread::scope(|scope| {
+            for t in 0..8 {
+                let (wal, appended) = (&wal, &appended);
+                scope.spawn(move || {
+                    for i in 0..50 {
+                        let txn_id = wal.next_txn_id();
+                        let lsn = wal.append(&update(txn_id, 0, t * 100 + i));
+                        let commit = LogRecord {
+                            txn_id,
+                            prev_lsn: lsn,
+                            body: LogBody::Commit,
+                        };
+                        let lsn = wal.append(&commit);
+                        // every thread commits in each round before any of them flushes
+                        appended.wait();
+                        wal.flush(lsn).unwrap();
+                        assert!(wal.get_flushed_lsn() > lsn);
+                        appended.wait();
+                    }
+                });
+            }
+        });
+        assert_eq!(wal.iter_from(0).unwrap().count(), 800);
+        // the first flush of a round writes the commits of all 8 threads, and the others wait for it or find their
+        // commit already durable
+        assert_eq!(wal.get_num_fsyncs(), 50);
+    }
+}